@@ -0,0 +1,233 @@
+use crate::config;
+use crate::deployments;
+use crate::repos::{Scope, tag_safe};
+use crate::state::{load_state, parse_qm_list, qm_list};
+use crate::types::{AppError, DesiredState, Result};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+const STATE_FILE: &str = "vm_ids.json";
+pub const ALLOC_TAG_PREFIX: &str = "alloc-";
+
+// Written on VMs whose ID was allocated, so the allocation survives losing vm_ids.json
+pub fn alloc_tag(name: &str) -> String {
+    format!("{}{}", ALLOC_TAG_PREFIX, tag_safe(name))
+}
+
+// IDs handed out to VMs without a vm_id, per environment, keyed by VM name. Kept next to the
+// environment's deployment history so every run gives a VM the same ID.
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct Allocations {
+    ids: BTreeMap<String, u32>,
+}
+
+impl Allocations {
+    fn path(environment: &str) -> PathBuf {
+        Path::new(&deployments::environment_dir(
+            &config::get().deployments_dir,
+            environment,
+        ))
+        .join(STATE_FILE)
+    }
+
+    pub fn load(environment: &str) -> Self {
+        std::fs::read_to_string(Self::path(environment))
+            .ok()
+            .and_then(|raw| serde_json::from_str(&raw).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn record(&mut self, name: &str, vm_id: u32) {
+        self.ids.insert(name.to_string(), vm_id);
+    }
+
+    pub fn save(&self, environment: &str) -> Result<()> {
+        let path = Self::path(environment);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+// Gives every VM without a vm_id one from the scope's range. A VM keeps the ID it got before:
+// from `known` (recorded allocations, then alloc tags, then a managed VM of that name) as long
+// as that ID is still the VM's to have. New IDs skip anything in use on the cluster.
+// Returns the VMs that got a new ID.
+pub fn allocate(
+    desired: &mut DesiredState,
+    scope: &Scope,
+    known: &[HashMap<String, u32>],
+    cluster: &HashSet<u32>,
+    managed: &HashSet<u32>,
+) -> Result<Vec<(String, u32)>> {
+    let mut taken: HashSet<u32> = desired
+        .vms
+        .values()
+        .map(|vm| vm.vm_id)
+        .filter(|id| *id != 0)
+        .collect();
+    let mut names: Vec<String> = desired
+        .vms
+        .iter()
+        .filter(|(_, vm)| vm.vm_id == 0)
+        .map(|(key, _)| key.clone())
+        .collect();
+    names.sort();
+
+    // IDs a VM had before go first, so a new allocation can't take one of them
+    let mut fresh = Vec::new();
+    for key in names {
+        let vm = desired.vms.get_mut(&key).expect("name comes from desired");
+        let kept = known.iter().filter_map(|ids| ids.get(&vm.name)).find(|id| {
+            scope.owns(**id)
+                && !taken.contains(id)
+                && (!cluster.contains(id) || managed.contains(id))
+        });
+        match kept {
+            Some(id) => {
+                vm.vm_id = *id;
+                vm.vm_id_allocated = true;
+                taken.insert(*id);
+            }
+            None => fresh.push(key),
+        }
+    }
+
+    let mut allocated = Vec::new();
+    for key in fresh {
+        let vm = desired.vms.get_mut(&key).expect("name comes from desired");
+        let Some((start, end)) = scope.range else {
+            return Err(AppError::ConfigError(format!(
+                "{} has no vm_id and {} has no vm_id_range to allocate one from",
+                vm.name, scope.environment
+            )));
+        };
+        let id = (start..=end)
+            .find(|id| scope.owns(*id) && !taken.contains(id) && !cluster.contains(id))
+            .ok_or_else(|| {
+                AppError::ConfigError(format!(
+                    "No free VM ID left in vm_id_range {}-{} of {} for {}",
+                    start, end, scope.environment, vm.name
+                ))
+            })?;
+        vm.vm_id = id;
+        vm.vm_id_allocated = true;
+        taken.insert(id);
+        allocated.push((vm.name.clone(), id));
+    }
+    Ok(allocated)
+}
+
+// allocate() against the live cluster and the environment's records, which are updated with
+// the result before anything is created
+pub fn allocate_ids(desired: &mut DesiredState, scope: &Scope) -> Result<Vec<(String, u32)>> {
+    if desired.vms.values().all(|vm| vm.vm_id != 0) {
+        return Ok(Vec::new());
+    }
+    let cluster: HashSet<u32> = parse_qm_list(&qm_list()?)?
+        .into_iter()
+        .map(|vm| vm.vm_id)
+        .collect();
+    let deployed = load_state()?;
+    let managed_vms: Vec<_> = deployed
+        .vms
+        .values()
+        .filter(|vm| scope.manages(vm))
+        .collect();
+    let managed: HashSet<u32> = managed_vms.iter().map(|vm| vm.vm_id).collect();
+    let tagged: HashMap<String, u32> = desired
+        .vms
+        .values()
+        .filter_map(|config| {
+            let tag = alloc_tag(&config.name);
+            let vm = managed_vms.iter().find(|vm| vm.tags.contains(&tag))?;
+            Some((config.name.clone(), vm.vm_id))
+        })
+        .collect();
+    let by_name: HashMap<String, u32> = managed_vms
+        .iter()
+        .map(|vm| (vm.vm_name.clone(), vm.vm_id))
+        .collect();
+
+    let mut records = Allocations::load(&scope.environment);
+    let allocated = allocate(
+        desired,
+        scope,
+        &[records.ids.clone().into_iter().collect(), tagged, by_name],
+        &cluster,
+        &managed,
+    )?;
+    records.ids = desired
+        .vms
+        .values()
+        .filter(|vm| vm.vm_id_allocated)
+        .map(|vm| (vm.name.clone(), vm.vm_id))
+        .collect();
+    if let Err(e) = records.save(&scope.environment) {
+        warn!("Failed to save VM ID allocations: {:?}", e);
+    }
+    Ok(allocated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::VMConfig;
+    use crate::types::fixtures::vm_config;
+
+    fn vm(name: &str, vm_id: u32) -> (String, VMConfig) {
+        (name.to_string(), vm_config(name, vm_id))
+    }
+
+    #[test]
+    fn test_allocate_from_range() {
+        let scope = Scope {
+            environment: "prod".to_string(),
+            range: Some((100, 110)),
+            foreign_ids: HashSet::from([102]),
+            ..Default::default()
+        };
+        let mut desired = DesiredState {
+            vms: HashMap::from([vm("db", 0), vm("web", 0), vm("cache", 0), vm("fixed", 100)]),
+        };
+        // 101 is a VM on the cluster, 103 was web's last time but is gone now
+        let known = [HashMap::from([("web".to_string(), 103)])];
+        let cluster = HashSet::from([100, 101]);
+        let managed = HashSet::from([100]);
+        let allocated = allocate(&mut desired, &scope, &known, &cluster, &managed).unwrap();
+
+        assert_eq!(desired.vms["web"].vm_id, 103);
+        assert_eq!(desired.vms["cache"].vm_id, 104);
+        assert_eq!(desired.vms["db"].vm_id, 105);
+        assert_eq!(desired.vms["fixed"].vm_id, 100);
+        assert!(!desired.vms["fixed"].vm_id_allocated);
+        assert!(desired.vms["web"].vm_id_allocated);
+        assert_eq!(
+            allocated,
+            vec![("cache".to_string(), 104), ("db".to_string(), 105)]
+        );
+
+        // A recorded ID that is now configured explicitly or taken by a VM the environment
+        // doesn't manage is given up
+        let mut desired = DesiredState {
+            vms: HashMap::from([vm("web", 0), vm("fixed", 103)]),
+        };
+        allocate(&mut desired, &scope, &known, &cluster, &managed).unwrap();
+        assert_eq!(desired.vms["web"].vm_id, 104);
+        let mut desired = DesiredState {
+            vms: HashMap::from([vm("web", 0)]),
+        };
+        let cluster = HashSet::from([103]);
+        allocate(&mut desired, &scope, &known, &cluster, &managed).unwrap();
+        assert_eq!(desired.vms["web"].vm_id, 100);
+
+        let mut desired = DesiredState {
+            vms: HashMap::from([vm("web", 0)]),
+        };
+        let open = Scope::default();
+        assert!(allocate(&mut desired, &open, &[], &cluster, &managed).is_err());
+    }
+}
@@ -0,0 +1,57 @@
+use crate::config;
+use crate::types::{AppError, Result};
+use std::net::IpAddr;
+
+// The token routes that change state (cancel, gc, rollback, import) require, from
+// api.token_file. Without one configured those routes only answer requests from the host itself.
+pub fn load_token() -> Result<Option<String>> {
+    let Some(path) = &config::get().api.token_file else {
+        return Ok(None);
+    };
+    let token = std::fs::read_to_string(path)
+        .map_err(|e| AppError::ConfigError(format!("Failed to read {}: {}", path, e)))?
+        .trim()
+        .to_string();
+    if token.is_empty() {
+        return Err(AppError::ConfigError(format!("{} is empty", path)));
+    }
+    Ok(Some(token))
+}
+
+// Compares every byte so the time taken doesn't give away how much of the token matched
+fn token_matches(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// authorization is the request's Authorization header, expected as "Bearer <token>"
+pub fn authorized(token: Option<&str>, authorization: Option<&str>, peer: IpAddr) -> bool {
+    match token {
+        Some(token) => authorization
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .is_some_and(|given| token_matches(token, given.trim())),
+        None => peer.is_loopback(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authorized() {
+        let local: IpAddr = "127.0.0.1".parse().unwrap();
+        let remote: IpAddr = "192.168.1.20".parse().unwrap();
+        assert!(authorized(None, None, local));
+        assert!(!authorized(None, Some("Bearer anything"), remote));
+        assert!(authorized(Some("s3cret"), Some("Bearer s3cret"), remote));
+        assert!(!authorized(Some("s3cret"), Some("Bearer s3cre"), remote));
+        assert!(!authorized(Some("s3cret"), Some("s3cret"), remote));
+        // With a token even local requests need it
+        assert!(!authorized(Some("s3cret"), None, local));
+    }
+}
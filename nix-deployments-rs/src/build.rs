@@ -1,15 +1,37 @@
-use crate::git::git_ensure_commit;
-use crate::nix::{BASE_REPO_PATH, configure_dirs, eval_vm_config, list_nix_configs, nix_build};
+use crate::alloc::allocate_ids;
+use crate::capacity::check_plan;
+use crate::config::{self, RepoConfig};
+use crate::deployments;
+use crate::exec;
+use crate::git::{checkout_path, git_ensure_commit};
+use crate::health::{RestartTracker, StartDecision, capture_serial_console};
+use crate::nix::{
+    Flake, configure_dirs, dry_run_builds, eval_image_paths, eval_vm_config, link_store_path,
+    list_nix_configs, nix_build, resolve_flake, store_path_exists,
+};
+use crate::notify::{self, Notification};
+use crate::policy;
 use crate::qm::{
-    qm_create, qm_destroy, qm_importdisk, qm_resize, qm_set_agent, qm_set_disk, qm_set_resources,
-    qm_start, qm_stop,
+    qm_create, qm_destroy, qm_guest_exec, qm_importdisk, qm_resize, qm_set_agent, qm_set_disk,
+    qm_set_resources, qm_shutdown, qm_start, qm_stop,
+};
+use crate::repos;
+use crate::signing::trusted;
+use crate::state::{adopt_legacy, full_diff, get_vm_statuses};
+use crate::types::{
+    AppError, DeploymentSnapshot, DesiredState, FieldChange, ImagePaths, ImageReport, ImageStatus,
+    PipelineOutcome, PipelineTrigger, PowerState, ReportAction, Result, RunReport, ShutdownPath,
+    ShutdownPolicy, ShutdownRecord, StateDiff, UpdateAction, VMConfig, VMReport, VMUpdate,
 };
-use crate::state::{full_diff, get_vm_statuses, parse_vm_config};
-use crate::types::{AppError, FieldChange, Result, StateDiff, UpdateAction, VMConfig};
+use crate::validate::{Host, validated};
 use rayon::prelude::*;
-use std::collections::HashMap;
-use std::fs;
-use tracing::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::RwLock;
+use tracing::{debug, info, warn};
+
+// image type -> (qcow2 path, nix hash) for every image realised this run
+pub type BuiltImages = HashMap<String, (String, String)>;
 
 fn nix_store_hash(store_path: &str) -> Option<&str> {
     store_path
@@ -17,13 +39,12 @@ fn nix_store_hash(store_path: &str) -> Option<&str> {
         .and_then(|s| s.split('-').next())
 }
 
-pub fn get_nix_hash(symlink: &str) -> Option<String> {
-    let path = fs::canonicalize(symlink).ok()?;
-    let rstring = path.into_os_string().into_string().ok()?;
-    Some(rstring.strip_prefix("/nix/store/")?.to_string())
-}
-
-pub fn provision_vm(config: &VMConfig, qcow2_path: &str, commit_hash: &str) -> Result<()> {
+pub fn provision_vm(
+    config: &VMConfig,
+    qcow2_path: &str,
+    commit_hash: &str,
+    owner_tag: &str,
+) -> Result<()> {
     let nix_hash = nix_store_hash(qcow2_path).ok_or_else(|| {
         AppError::CmdError(format!(
             "could not extract nix hash from path: {}",
@@ -31,69 +52,229 @@ pub fn provision_vm(config: &VMConfig, qcow2_path: &str, commit_hash: &str) -> R
         ))
     })?;
     info!("Provisioning VM {} (id: {})", config.name, config.vm_id);
-    qm_create(config, nix_hash, commit_hash)?;
+    qm_create(config, nix_hash, commit_hash, owner_tag)?;
     let disk_ref = qm_importdisk(config.vm_id, qcow2_path, &config.storage_location)?;
     qm_set_disk(config.vm_id, &disk_ref, &config.disk_slot)?;
     qm_set_agent(config.vm_id)?;
     qm_resize(config.vm_id, &config.disk_slot, config.disk_gb)?;
-    info!("VM {} provisioned successfully, starting", config.name);
-    qm_start(config.vm_id)?;
-    info!("VM {} started", config.name);
+    match config.power_state {
+        PowerState::Running => {
+            info!("VM {} provisioned successfully, starting", config.name);
+            qm_start(config.vm_id)?;
+            info!("VM {} started", config.name);
+        }
+        PowerState::Stopped => {
+            info!(
+                "VM {} provisioned successfully, leaving it stopped",
+                config.name
+            );
+        }
+    }
 
     Ok(())
 }
 
+// Brings a VM to its declared power state, returns the shutdown record if it had to be stopped
+pub fn apply_power_state(config: &VMConfig) -> Result<Option<ShutdownRecord>> {
+    match config.power_state {
+        PowerState::Running => {
+            if qm_start(config.vm_id)? {
+                info!("Started VM {}", config.name);
+            }
+            Ok(None)
+        }
+        PowerState::Stopped => shutdown_vm(config.vm_id, &config.name, &config.shutdown).map(Some),
+    }
+}
+
+// Graceful first: optional pre-shutdown command in the guest, then ACPI/agent shutdown,
+// and only a hard stop if the guest hasn't gone down within the policy timeout
+pub fn shutdown_vm(vm_id: u32, name: &str, policy: &ShutdownPolicy) -> Result<ShutdownRecord> {
+    let pre_shutdown_succeeded = match &policy.pre_shutdown_command {
+        Some(command) => {
+            info!("Running pre-shutdown command on {}: {:?}", name, command);
+            match qm_guest_exec(vm_id, command, policy.pre_shutdown_timeout_secs) {
+                Ok(0) => Some(true),
+                Ok(code) => {
+                    warn!("Pre-shutdown command on {} exited with {}", name, code);
+                    Some(false)
+                }
+                Err(AppError::Cancelled(e)) => return Err(AppError::Cancelled(e)),
+                Err(e) => {
+                    warn!("Pre-shutdown command on {} failed: {}", name, e);
+                    Some(false)
+                }
+            }
+        }
+        None => None,
+    };
 
-pub fn build_all_configs(
-    repo_url: &str,
-    commit_hash: &str,
-) -> Result<HashMap<String, (String, String)>> {
-    let dest_path = format!("{}/{}", BASE_REPO_PATH, commit_hash);
-    info!(
-        "Cloning {} at commit {} to {}",
-        repo_url, commit_hash, dest_path
-    );
-    git_ensure_commit(&repo_url, &dest_path, &commit_hash)?;
-    let config_names = list_nix_configs(&dest_path)?;
     info!(
-        "Found {} nix configs: {:?}",
-        config_names.len(),
-        config_names
+        "Shutting down VM {} (id: {}), timeout {}s",
+        name, vm_id, policy.timeout_secs
     );
-    configure_dirs(config_names.clone(), &dest_path)?;
-    let builds = config_names
+    let path = match qm_shutdown(vm_id, policy.timeout_secs) {
+        Ok(true) => ShutdownPath::Graceful,
+        Ok(false) => ShutdownPath::NotRunning,
+        Err(AppError::Cancelled(e)) => return Err(AppError::Cancelled(e)),
+        Err(e) => {
+            warn!("Graceful shutdown of {} failed ({}), forcing stop", name, e);
+            qm_stop(&vm_id)?;
+            ShutdownPath::Forced
+        }
+    };
+    info!("VM {} is down ({:?})", name, path);
+
+    Ok(ShutdownRecord {
+        path,
+        pre_shutdown_succeeded,
+    })
+}
+
+// Evaluates the image derivations in parallel, nothing is built yet
+pub fn eval_images(flake: &Flake, image_types: &[String]) -> Result<HashMap<String, ImagePaths>> {
+    let ctx = exec::current();
+    image_types
         .par_iter()
-        .map(|config_name| -> Result<(String, (String, String))> {
-            info!("Building nix config: {}", config_name);
-            let result_path = nix_build(config_name, &dest_path)?;
-            let canonical = fs::canonicalize(&result_path)?;
-            let qcow2_path = format!("{}/nixos.qcow2", canonical.display());
-            let nix_hash = nix_store_hash(&qcow2_path)
-                .ok_or_else(|| {
-                    AppError::CmdError(format!(
-                        "could not extract nix hash from path: {}",
-                        qcow2_path
-                    ))
-                })?
-                .to_string();
-            info!("Built {} -> {} (nix hash: {})", config_name, result_path, nix_hash);
-            Ok((config_name.clone(), (qcow2_path, nix_hash)))
+        .map(|image_type| -> Result<(String, ImagePaths)> {
+            let paths =
+                exec::with_context(ctx.with_stream(format!("nix-eval/{}", image_type)), || {
+                    eval_image_paths(image_type, flake)
+                })?;
+            Ok((image_type.clone(), paths))
         })
-        .collect::<Result<HashMap<_, _>>>()?;
-    Ok(builds)
+        .collect()
 }
 
-pub fn run_pipeline(repo_url: &str, commit_hash: &str) -> Result<()> {
-    let dest_path = format!("{}/{}", BASE_REPO_PATH, commit_hash);
-    info!("Building all configs for commit {}", commit_hash);
-    let built_configs = build_all_configs(repo_url, commit_hash)?;
-    let eval = eval_vm_config(&dest_path)?;
-    let parsed = parse_vm_config(&eval)?;
-    let image_hashes: HashMap<String, String> = built_configs
+// Images a VM is about to be created or rebuilt from. Everything else is already deployed
+// with the evaluated hash and does not need to exist locally.
+pub fn needed_images(diff: &StateDiff) -> HashSet<String> {
+    diff.to_create
         .iter()
-        .map(|(name, (_, nix_hash))| (name.clone(), nix_hash.clone()))
+        .map(|config| config.image_type.clone())
+        .chain(
+            diff.to_update
+                .iter()
+                .filter(|u| matches!(u.required_action, UpdateAction::Rebuild))
+                .map(|u| u.config.image_type.clone()),
+        )
+        .collect()
+}
+
+pub fn image_hash(paths: &ImagePaths) -> Result<String> {
+    nix_store_hash(&paths.out_path)
+        .map(|h| h.to_string())
+        .ok_or_else(|| {
+            AppError::CmdError(format!(
+                "could not extract nix hash from path: {}",
+                paths.out_path
+            ))
+        })
+}
+
+// Realises only the needed images, linking ones already in the store instead of rebuilding them
+pub fn build_images(
+    dest_path: &str,
+    flake: &Flake,
+    needed: &HashSet<String>,
+    image_paths: &HashMap<String, ImagePaths>,
+) -> Result<(BuiltImages, Vec<ImageReport>)> {
+    // rayon workers don't inherit the caller's exec context, hand it over explicitly
+    let ctx = exec::current();
+    let results = image_paths
+        .par_iter()
+        .map(
+            |(image_type, paths)| -> Result<(ImageReport, Option<String>)> {
+                let nix_hash = image_hash(paths)?;
+                if !needed.contains(image_type) {
+                    info!("Skipping {}: no VM needs a new image", image_type);
+                    let report = ImageReport {
+                        image_type: image_type.clone(),
+                        nix_hash,
+                        status: ImageStatus::Skipped,
+                    };
+                    return Ok((report, None));
+                }
+                exec::with_context(ctx.with_stream(format!("nix-build/{}", image_type)), || {
+                    let status = if store_path_exists(&paths.out_path)? {
+                        info!("{} already in the store: {}", image_type, paths.out_path);
+                        link_store_path(image_type, &paths.out_path, dest_path)?;
+                        ImageStatus::Skipped
+                    } else {
+                        let to_build = dry_run_builds(image_type, flake)?;
+                        info!("Building nix config: {}", image_type);
+                        nix_build(image_type, flake, dest_path)?;
+                        if to_build.contains(&paths.drv_path) {
+                            ImageStatus::Built
+                        } else {
+                            ImageStatus::Substituted
+                        }
+                    };
+                    info!(
+                        "{} -> {} (nix hash: {}, {:?})",
+                        image_type, paths.out_path, nix_hash, status
+                    );
+                    let report = ImageReport {
+                        image_type: image_type.clone(),
+                        nix_hash,
+                        status,
+                    };
+                    Ok((report, Some(format!("{}/nixos.qcow2", paths.out_path))))
+                })
+            },
+        )
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut built = HashMap::new();
+    let mut reports = Vec::new();
+    for (report, qcow2_path) in results {
+        if let Some(qcow2_path) = qcow2_path {
+            built.insert(
+                report.image_type.clone(),
+                (qcow2_path, report.nix_hash.clone()),
+            );
+        }
+        reports.push(report);
+    }
+    reports.sort_by(|a, b| a.image_type.cmp(&b.image_type));
+    Ok((built, reports))
+}
+
+pub fn run_pipeline(repo: &RepoConfig, commit_hash: &str) -> Result<PipelineOutcome> {
+    let repo_url = repo.url.as_str();
+    let environment = repos::environment(repo);
+    let dest_path = checkout_path(&environment, commit_hash);
+    info!(
+        "Checking out {} at commit {} to {}",
+        repo_url, commit_hash, dest_path
+    );
+    git_ensure_commit(repo_url, &environment, commit_hash, &trusted(commit_hash))?;
+    let flake = resolve_flake(&dest_path, Some(repo))?;
+    let eval = eval_vm_config(&flake)?;
+    // Only nixosConfigurations some VM refers to are considered at all
+    let config_names = list_nix_configs(&flake)?;
+    let mut parsed = validated(&eval, &Host::discover(Some(config_names)))?;
+    let mut scope = repos::scope(repo)?;
+    for (name, vm_id) in allocate_ids(&mut parsed, &scope)? {
+        exec::log_line(&format!("Allocated vm_id {} to {}", vm_id, name));
+    }
+    scope.check_desired(&parsed)?;
+    scope.claim(&parsed);
+
+    let mut image_types: Vec<String> = parsed
+        .vms
+        .values()
+        .map(|vm| vm.image_type.clone())
         .collect();
-    let diff = full_diff(&parsed, &image_hashes)?;
+    image_types.sort();
+    image_types.dedup();
+    configure_dirs(image_types.clone(), &dest_path)?;
+    let image_paths = eval_images(&flake, &image_types)?;
+    let image_hashes = image_paths
+        .iter()
+        .map(|(image_type, paths)| Ok((image_type.clone(), image_hash(paths)?)))
+        .collect::<Result<HashMap<String, String>>>()?;
+    let diff = full_diff(&parsed, &image_hashes, &scope)?;
     info!(
         "Diff: {} to create, {} to update, {} to delete",
         diff.to_create.len(),
@@ -111,11 +292,18 @@ pub fn run_pipeline(repo_url: &str, commit_hash: &str) -> Result<()> {
             .changed_fields
             .iter()
             .map(|f| match f {
-                FieldChange::Memory => format!("memory"),
-                FieldChange::Cores => format!("cores"),
-                FieldChange::Sockets => format!("sockets"),
-                FieldChange::Disk => format!("disk"),
-                FieldChange::Image => format!("image"),
+                FieldChange::Name => format!("name (was {})", update.deployed.vm_name),
+                FieldChange::VmId => format!("vm_id (was {})", update.deployed.vm_id),
+                FieldChange::Memory => "memory".to_string(),
+                FieldChange::Cores => "cores".to_string(),
+                FieldChange::Sockets => "sockets".to_string(),
+                FieldChange::Disk => "disk".to_string(),
+                FieldChange::Image => "image".to_string(),
+                FieldChange::PowerState => "power state".to_string(),
+                FieldChange::OnBoot => "onboot".to_string(),
+                FieldChange::Startup => "startup".to_string(),
+                FieldChange::Network => "network".to_string(),
+                FieldChange::Tags => "tags".to_string(),
             })
             .collect();
         match &update.required_action {
@@ -143,52 +331,136 @@ pub fn run_pipeline(repo_url: &str, commit_hash: &str) -> Result<()> {
         }
     }
 
-    reconcile(diff, built_configs, commit_hash)?;
+    policy::enforce(repo, &parsed, &diff)?;
+    check_plan(&diff)?;
+
+    let needed = needed_images(&diff);
+    let (built_configs, images) = build_images(&dest_path, &flake, &needed, &image_paths)?;
+    // Claimed legacy VMs are already in the diff, their owner tag is only written once the
+    // plan has passed every check and is about to be applied
+    for name in adopt_legacy(&scope)? {
+        exec::log_line(&format!("Adopted legacy VM {} into {}", name, environment));
+    }
+    // VMs removed from the config are shut down the way the last deployment configured them
+    let previous = deployments::load_current(&deployments::environment_dir(
+        &config::get().deployments_dir,
+        &environment,
+    ))?;
+    let mut report = reconcile(
+        diff,
+        built_configs,
+        commit_hash,
+        &scope.owner_tag,
+        previous.as_ref().map(|snapshot| &snapshot.desired),
+    )?;
+    report.images = images;
     info!("Pipeline complete for commit {}", commit_hash);
 
-    Ok(())
+    Ok(PipelineOutcome {
+        snapshot: DeploymentSnapshot {
+            repo_url: repo_url.to_string(),
+            environment,
+            commit_hash: commit_hash.to_string(),
+            deployed_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            desired: parsed,
+            image_hashes,
+        },
+        report,
+    })
 }
 
-pub fn ensure_vms_running(repo_path: &str) {
-    let raw = match eval_vm_config(repo_path) {
-        Ok(r) => r,
-        Err(e) => {
-            warn!("Periodic reconcile: failed to eval vm config: {:?}", e);
-            return;
-        }
-    };
-    let desired = match parse_vm_config(&raw) {
-        Ok(d) => d,
-        Err(e) => {
-            warn!("Periodic reconcile: failed to parse vm config: {:?}", e);
-            return;
-        }
-    };
+// Works from the desired state cached by the last successful pipeline, the repo checkout
+// is not needed after the pipeline has finished
+// The tracker is shared with /status and every environment, so it is only locked to decide
+// and record, never across qm. Returns the VMs that just tipped into a crash loop for
+// report_crash_loop, which is left to the caller as console capture is slow.
+pub fn ensure_vms_running(
+    desired: &DesiredState,
+    restarts: &RwLock<RestartTracker>,
+) -> Vec<(u32, String)> {
+    let mut crash_loops = Vec::new();
     if desired.vms.is_empty() {
         info!("Periodic reconcile: no VMs in config");
-        return;
+        return crash_loops;
     }
     let actual = match get_vm_statuses() {
         Ok(s) => s,
         Err(e) => {
             warn!("Periodic reconcile: failed to get VM statuses: {:?}", e);
-            return;
+            return crash_loops;
         }
     };
     info!(
         "Periodic reconcile: checking {} managed VMs",
         desired.vms.len()
     );
+    let policy = &config::get().crash_loop;
     for (name, vm) in &desired.vms {
-        match actual.get(&vm.vm_id).map(|s| s.as_str()) {
-            Some("running") => {
+        match (actual.get(&vm.vm_id).map(|s| s.as_str()), vm.power_state) {
+            (Some("running"), PowerState::Running) => {
                 info!("Periodic reconcile: {} (id: {}) is running", name, vm.vm_id);
+                let recovered = {
+                    let mut restarts = restarts.blocking_write();
+                    let was_looping = restarts.is_crash_looping(vm.vm_id);
+                    restarts.record_running(vm.vm_id, Instant::now(), policy) && was_looping
+                };
+                if recovered {
+                    notify::send(&Notification {
+                        event: "crash_loop_recovered".to_string(),
+                        vm: Some(name.clone()),
+                        message: format!("{} (id: {}) has been running stably", name, vm.vm_id),
+                    });
+                }
             }
-            Some(status) => {
+            (Some("running"), PowerState::Stopped) => {
                 info!(
-                    "Periodic reconcile: {} (id: {}) is {} -> starting",
+                    "Periodic reconcile: {} (id: {}) is running but should be stopped -> shutting down",
+                    name, vm.vm_id
+                );
+                if let Err(e) = shutdown_vm(vm.vm_id, name, &vm.shutdown) {
+                    warn!(
+                        "Periodic reconcile: failed to shut down VM {}: {:?}",
+                        name, e
+                    );
+                }
+            }
+            (Some(status), PowerState::Stopped) => {
+                info!(
+                    "Periodic reconcile: {} (id: {}) is {}, kept stopped",
                     name, vm.vm_id, status
                 );
+            }
+            (Some(status), PowerState::Running) => {
+                let now = Instant::now();
+                let (tipped, looping) = {
+                    let mut restarts = restarts.blocking_write();
+                    if let StartDecision::Backoff(wait) = restarts.decide(vm.vm_id, now) {
+                        debug!(
+                            "Periodic reconcile: {} (id: {}) is {}, backing off for {}s",
+                            name,
+                            vm.vm_id,
+                            status,
+                            wait.as_secs()
+                        );
+                        continue;
+                    }
+                    let tipped = restarts.record_start(vm.vm_id, name, now, policy);
+                    (tipped, restarts.is_crash_looping(vm.vm_id))
+                };
+                if looping {
+                    warn!(
+                        "Periodic reconcile: {} (id: {}) is {} and crash looping -> starting",
+                        name, vm.vm_id, status
+                    );
+                } else {
+                    info!(
+                        "Periodic reconcile: {} (id: {}) is {} -> starting",
+                        name, vm.vm_id, status
+                    );
+                }
                 match qm_start(vm.vm_id) {
                     Ok(true) => {
                         info!("Periodic reconcile: started VM {}", name);
@@ -200,8 +472,11 @@ pub fn ensure_vms_running(repo_path: &str) {
                         warn!("Periodic reconcile: failed to start VM {}: {:?}", name, e);
                     }
                 }
+                if tipped {
+                    crash_loops.push((vm.vm_id, name.clone()));
+                }
             }
-            None => {
+            (None, _) => {
                 warn!(
                     "Periodic reconcile: {} (id: {}) does not exist in Proxmox, will be recreated on next push",
                     name, vm.vm_id
@@ -209,52 +484,153 @@ pub fn ensure_vms_running(repo_path: &str) {
             }
         }
     }
+    crash_loops
+}
+
+// Grabs the serial console of the start that was just issued so the status and
+// notification show why the VM keeps dying. Capturing takes up to console_capture_secs,
+// so it runs on its own task without the environment's permit.
+pub fn report_crash_loop(vm_id: u32, name: &str, restarts: &RwLock<RestartTracker>) {
+    let policy = &config::get().crash_loop;
+    warn!(
+        "Periodic reconcile: {} (id: {}) is crash looping, {} starts within {}s",
+        name, vm_id, policy.max_starts, policy.window_secs
+    );
+    let console =
+        match capture_serial_console(vm_id, Duration::from_secs(policy.console_capture_secs)) {
+            Ok(console) => console,
+            Err(e) => {
+                warn!("Failed to capture serial console of {}: {}", name, e);
+                String::new()
+            }
+        };
+    restarts
+        .blocking_write()
+        .set_console(vm_id, console.clone());
+    notify::send(&Notification {
+        event: "crash_loop".to_string(),
+        vm: Some(name.to_string()),
+        message: format!(
+            "{} (id: {}) started {} times within {}s, last console output:\n{}",
+            name, vm_id, policy.max_starts, policy.window_secs, console
+        ),
+    });
+}
+
+// Shutdown policy of a VM being destroyed, from the desired state it was last deployed with.
+// VMs that were never in a deployment (legacy, adopted) get the default.
+fn removed_policy(previous: Option<&DesiredState>, vm_id: u32) -> ShutdownPolicy {
+    previous
+        .and_then(|desired| desired.vms.values().find(|vm| vm.vm_id == vm_id))
+        .map(|vm| vm.shutdown.clone())
+        .unwrap_or_default()
 }
 
 pub fn reconcile(
     diff: StateDiff,
-    built_configs: HashMap<String, (String, String)>,
+    built_configs: BuiltImages,
     commit_hash: &str,
-) -> Result<()> {
+    owner_tag: &str,
+    previous: Option<&DesiredState>,
+) -> Result<RunReport> {
+    let mut report = RunReport {
+        commit_hash: commit_hash.to_string(),
+        trigger: PipelineTrigger::Push,
+        images: Vec::new(),
+        vms: Vec::new(),
+        gc: None,
+    };
+    // Deletes and rebuilds go first so what they free is there for new VMs
+    for vm in diff.to_delete {
+        exec::check_cancelled()?;
+        info!("Deleting VM {} (id: {})", vm.vm_name, vm.vm_id);
+        let policy = removed_policy(previous, vm.vm_id);
+        let shutdown = exec::with_stream(format!("qm/{}", vm.vm_name), || {
+            let shutdown = shutdown_vm(vm.vm_id, &vm.vm_name, &policy)?;
+            qm_destroy(vm.vm_id)?;
+            Ok::<_, AppError>(shutdown)
+        })?;
+        info!("Deleted VM {}", vm.vm_name);
+        report.vms.push(VMReport {
+            name: vm.vm_name.clone(),
+            vm_id: vm.vm_id,
+            action: ReportAction::Destroyed,
+            shutdown: Some(shutdown),
+        });
+    }
+    for actions in diff.to_update {
+        exec::check_cancelled()?;
+        let vm_report = exec::with_stream(format!("qm/{}", actions.name), || {
+            update_vm(&actions, &built_configs, commit_hash, owner_tag)
+        })?;
+        report.vms.push(vm_report);
+    }
     for config in diff.to_create {
+        exec::check_cancelled()?;
         let (qcow_path, _) = built_configs
             .get(&config.image_type)
             .ok_or(AppError::CmdError(format!(
                 "No built image for type '{}' (vm: {})",
                 config.image_type, config.name
             )))?;
-        provision_vm(&config, qcow_path, commit_hash)?;
-    }
-    for vm in diff.to_delete {
-        info!("Deleting VM {} (id: {})", vm.vm_name, vm.vm_id);
-        qm_stop(&vm.vm_id)?;
-        qm_destroy(vm.vm_id)?;
-        info!("Deleted VM {}", vm.vm_name);
+        exec::with_stream(format!("qm/{}", config.name), || {
+            provision_vm(&config, qcow_path, commit_hash, owner_tag)
+        })?;
+        report.vms.push(VMReport {
+            name: config.name.clone(),
+            vm_id: config.vm_id,
+            action: ReportAction::Created,
+            shutdown: None,
+        });
     }
-    for actions in diff.to_update {
-        match &actions.required_action {
-            UpdateAction::InPlace => {
-                info!("Updating VM {} in place", actions.name);
-                qm_set_resources(actions.config.vm_id, &actions)?;
-                info!("Updated VM {}", actions.name);
-            }
-            UpdateAction::Rebuild => {
-                info!("Rebuilding VM {} (destroy + provision)", actions.name);
-                let (qcow_path, _) =
-                    built_configs
-                        .get(&actions.config.image_type)
-                        .ok_or(AppError::CmdError(format!(
-                            "No built image for type '{}' (vm: {})",
-                            actions.config.image_type, actions.name
-                        )))?;
-                qm_stop(&actions.config.vm_id)?;
-                qm_destroy(actions.config.vm_id)?;
-                provision_vm(&actions.config, qcow_path, commit_hash)?;
-            }
-            UpdateAction::Protected => {
-                warn!("{} is protected, no action taken", actions.name);
+    Ok(report)
+}
+
+fn update_vm(
+    actions: &VMUpdate,
+    built_configs: &BuiltImages,
+    commit_hash: &str,
+    owner_tag: &str,
+) -> Result<VMReport> {
+    let mut vm_report = VMReport {
+        name: actions.name.clone(),
+        vm_id: actions.config.vm_id,
+        action: ReportAction::Protected,
+        shutdown: None,
+    };
+    match &actions.required_action {
+        UpdateAction::InPlace => {
+            info!("Updating VM {} in place", actions.name);
+            qm_set_resources(actions.deployed.vm_id, actions)?;
+            if actions.changed_fields.contains(&FieldChange::PowerState) {
+                vm_report.shutdown = apply_power_state(&actions.config)?;
             }
+            info!("Updated VM {}", actions.name);
+            vm_report.action = ReportAction::UpdatedInPlace;
+        }
+        UpdateAction::Rebuild => {
+            info!("Rebuilding VM {} (destroy + provision)", actions.name);
+            let (qcow_path, _) =
+                built_configs
+                    .get(&actions.config.image_type)
+                    .ok_or(AppError::CmdError(format!(
+                        "No built image for type '{}' (vm: {})",
+                        actions.config.image_type, actions.name
+                    )))?;
+            // The deployed ID, which differs from the config's if the vm_id changed
+            let shutdown = shutdown_vm(
+                actions.deployed.vm_id,
+                &actions.name,
+                &actions.config.shutdown,
+            )?;
+            qm_destroy(actions.deployed.vm_id)?;
+            provision_vm(&actions.config, qcow_path, commit_hash, owner_tag)?;
+            vm_report.action = ReportAction::Rebuilt;
+            vm_report.shutdown = Some(shutdown);
+        }
+        UpdateAction::Protected => {
+            warn!("{} is protected, no action taken", actions.name);
         }
     }
-    Ok(())
+    Ok(vm_report)
 }
@@ -0,0 +1,338 @@
+use crate::config::{self, CapacityConfig, CapacityMode};
+use crate::exec;
+use crate::state::{from_qm_list, parse_qm_config, parse_qm_list, qm_config, qm_list, with_config};
+use crate::types::{AppError, DeployedVM, PowerState, Result, StateDiff, UpdateAction, VMConfig};
+use crate::validate::pvesm_status;
+use std::collections::{BTreeMap, HashMap};
+use tracing::warn;
+
+const KIB_PER_GB: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Default)]
+pub struct HostCapacity {
+    pub memory_mb: u64,
+    pub cpus: u64,
+    // Free space per storage
+    pub available_gb: HashMap<String, u64>,
+}
+
+// What the VMs on the host hold now, counting only those that are running or start on boot
+#[derive(Debug, Clone, Default)]
+pub struct Usage {
+    pub memory_mb: u64,
+    pub cpus: u64,
+}
+
+// Net change a plan makes. Deletions and rebuilds free their resources before anything new
+// is created, see build::reconcile.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Delta {
+    pub memory_mb: i64,
+    pub cpus: i64,
+    pub storage_gb: BTreeMap<String, i64>,
+}
+
+impl Delta {
+    fn grows(&self) -> bool {
+        self.memory_mb > 0 || self.cpus > 0 || self.storage_gb.values().any(|gb| *gb > 0)
+    }
+
+    fn add(&mut self, config: &VMConfig) {
+        if config.power_state == PowerState::Running || config.onboot {
+            self.memory_mb += i64::from(config.memory_mb);
+            self.cpus += i64::from(config.cores) * i64::from(config.sockets);
+        }
+    }
+
+    fn add_disk(&mut self, config: &VMConfig) {
+        *self
+            .storage_gb
+            .entry(config.storage_location.clone())
+            .or_default() += i64::from(config.disk_gb);
+    }
+
+    fn remove(&mut self, vm: &DeployedVM) {
+        if vm.status == "running" || vm.onboot {
+            self.memory_mb -= i64::from(vm.mem_mb);
+            self.cpus -= i64::from(vm.cores) * i64::from(vm.sockets);
+        }
+    }
+
+    fn remove_disk(&mut self, vm: &DeployedVM) {
+        if let Some(storage) = &vm.storage {
+            *self.storage_gb.entry(storage.clone()).or_default() -= vm.bootdisk_gb.round() as i64;
+        }
+    }
+}
+
+pub fn delta(diff: &StateDiff) -> Delta {
+    let mut delta = Delta::default();
+    for vm in &diff.to_delete {
+        delta.remove(vm);
+        delta.remove_disk(vm);
+    }
+    for update in &diff.to_update {
+        if matches!(update.required_action, UpdateAction::Protected) {
+            continue;
+        }
+        delta.remove(&update.deployed);
+        delta.add(&update.config);
+        if matches!(update.required_action, UpdateAction::Rebuild) {
+            delta.remove_disk(&update.deployed);
+            delta.add_disk(&update.config);
+        }
+    }
+    for config in &diff.to_create {
+        delta.add(config);
+        delta.add_disk(config);
+    }
+    delta
+}
+
+// Only resources the plan adds to are checked, a plan that shrinks never fails here even on
+// a host that is already overcommitted
+pub fn check(
+    host: &HostCapacity,
+    usage: &Usage,
+    delta: &Delta,
+    policy: &CapacityConfig,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut compute =
+        |resource: &str, unit: &str, used: u64, change: i64, total: u64, ratio: f64| {
+            let limit = (total as f64 * ratio) as i64;
+            let after = used as i64 + change;
+            if change > 0 && after > limit {
+                problems.push(format!(
+                    "{}: the plan adds {} {}, VMs would have {} {} of {} allowed ({} x {})",
+                    resource, change, unit, after, unit, limit, total, ratio
+                ));
+            }
+        };
+    compute(
+        "memory",
+        "MB",
+        usage.memory_mb,
+        delta.memory_mb,
+        host.memory_mb,
+        policy.memory_ratio,
+    );
+    compute(
+        "cpu",
+        "vCPUs",
+        usage.cpus,
+        delta.cpus,
+        host.cpus,
+        policy.cpu_ratio,
+    );
+    for (storage, change) in &delta.storage_gb {
+        // Storages that don't exist are the validator's to report
+        let Some(available) = host.available_gb.get(storage) else {
+            continue;
+        };
+        let limit = (*available as f64 * policy.storage_ratio) as i64;
+        if *change > limit {
+            problems.push(format!(
+                "storage {}: the plan needs {} GB more, {} GB allowed ({} GB free x {})",
+                storage, change, limit, available, policy.storage_ratio
+            ));
+        }
+    }
+    problems
+}
+
+// Available space per storage from pvesm status, which reports KiB. Inactive storages are left out.
+pub fn parse_pvesm_available(output: &str) -> HashMap<String, u64> {
+    output
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.get(2) != Some(&"active") {
+                return None;
+            }
+            let available: u64 = parts.get(5)?.parse().ok()?;
+            Some((parts[0].to_string(), available / KIB_PER_GB))
+        })
+        .collect()
+}
+
+pub fn parse_meminfo(meminfo: &str) -> Option<u64> {
+    let kib: u64 = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemTotal:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kib / 1024)
+}
+
+// Every CPU thread of the host. available_parallelism would only count the ones this process
+// may use, which a cgroup limit or CPU affinity on the controller shrinks.
+pub fn parse_cpuinfo(cpuinfo: &str) -> Option<u64> {
+    let cpus = cpuinfo
+        .lines()
+        .filter(|line| line.split(':').next().map(str::trim) == Some("processor"))
+        .count() as u64;
+    (cpus > 0).then_some(cpus)
+}
+
+fn host_capacity() -> Result<HostCapacity> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo")?;
+    let memory_mb = parse_meminfo(&meminfo)
+        .ok_or_else(|| AppError::ParsingModuleError("no MemTotal in /proc/meminfo".to_string()))?;
+    let cpuinfo = std::fs::read_to_string("/proc/cpuinfo")?;
+    let cpus = parse_cpuinfo(&cpuinfo)
+        .ok_or_else(|| AppError::ParsingModuleError("no processor in /proc/cpuinfo".to_string()))?;
+    Ok(HostCapacity {
+        memory_mb,
+        cpus,
+        available_gb: parse_pvesm_available(&pvesm_status()?),
+    })
+}
+
+// Every VM on the host counts, whoever manages it
+fn usage() -> Result<Usage> {
+    let mut usage = Usage::default();
+    for row in parse_qm_list(&qm_list()?)? {
+        let parsed = parse_qm_config(&qm_config(row.vm_id)?)?;
+        let vm = with_config(from_qm_list(row), &parsed);
+        if vm.status == "running" || vm.onboot {
+            usage.memory_mb += u64::from(vm.mem_mb);
+            usage.cpus += u64::from(vm.cores) * u64::from(vm.sockets);
+        }
+    }
+    Ok(usage)
+}
+
+// Runs before images are built so an oversized plan fails early instead of halfway through
+// reconcile. If the host can't be inspected the plan goes ahead with a warning.
+pub fn check_plan(diff: &StateDiff) -> Result<()> {
+    let policy = &config::get().capacity;
+    let delta = delta(diff);
+    if policy.mode == CapacityMode::Off || !delta.grows() {
+        return Ok(());
+    }
+    let (host, usage) = match host_capacity().and_then(|host| Ok((host, usage()?))) {
+        Ok(found) => found,
+        Err(e) => {
+            warn!("Skipping capacity check, could not inspect the host: {}", e);
+            return Ok(());
+        }
+    };
+    let problems = check(&host, &usage, &delta, policy);
+    if problems.is_empty() {
+        return Ok(());
+    }
+    match policy.mode {
+        CapacityMode::Refuse => Err(AppError::CapacityError(problems.join("\n"))),
+        _ => {
+            for problem in &problems {
+                warn!("Overcommitting: {}", problem);
+                exec::log_line(&format!("Overcommitting: {}", problem));
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::VMUpdate;
+    use crate::types::fixtures::{deployed_vm, vm_config};
+
+    fn config(name: &str, memory_mb: u32, disk_gb: u32) -> VMConfig {
+        VMConfig {
+            memory_mb,
+            disk_gb,
+            ..vm_config(name, 100)
+        }
+    }
+
+    fn deployed(name: &str, mem_mb: u32, bootdisk_gb: f64) -> DeployedVM {
+        DeployedVM {
+            mem_mb,
+            bootdisk_gb,
+            cores: 4,
+            ..deployed_vm(name, 101)
+        }
+    }
+
+    #[test]
+    fn test_delta_frees_before_adding() {
+        let diff = StateDiff {
+            to_create: vec![config("new", 4096, 30)],
+            to_update: vec![VMUpdate {
+                name: "web".to_string(),
+                config: config("web", 2048, 20),
+                deployed: deployed("web", 1024, 10.0),
+                changed_fields: Vec::new(),
+                required_action: UpdateAction::Rebuild,
+            }],
+            to_delete: vec![deployed("old", 8192, 50.0)],
+        };
+        let delta = delta(&diff);
+        assert_eq!(delta.memory_mb, 4096 + 2048 - 1024 - 8192);
+        assert_eq!(delta.cpus, 2 + 2 - 4 - 4);
+        assert_eq!(delta.storage_gb["local-lvm"], 30 + 20 - 10 - 50);
+        assert!(!delta.grows());
+    }
+
+    #[test]
+    fn test_check_overcommit() {
+        let host = HostCapacity {
+            memory_mb: 16384,
+            cpus: 4,
+            available_gb: HashMap::from([("local-lvm".to_string(), 100)]),
+        };
+        let usage = Usage {
+            memory_mb: 12288,
+            cpus: 14,
+        };
+        let delta = Delta {
+            memory_mb: 8192,
+            cpus: 4,
+            storage_gb: BTreeMap::from([
+                ("local-lvm".to_string(), 150),
+                ("elsewhere".to_string(), 10),
+            ]),
+        };
+        let policy = CapacityConfig::default();
+        let problems = check(&host, &usage, &delta, &policy);
+        assert_eq!(problems.len(), 3);
+        assert!(problems[0].starts_with("memory: the plan adds 8192 MB"));
+        assert!(problems[1].starts_with("cpu:"));
+        assert!(problems[2].starts_with("storage local-lvm:"));
+
+        let generous = CapacityConfig {
+            memory_ratio: 1.5,
+            cpu_ratio: 8.0,
+            storage_ratio: 2.0,
+            ..Default::default()
+        };
+        assert!(check(&host, &usage, &delta, &generous).is_empty());
+    }
+
+    #[test]
+    fn test_parse_host_info() {
+        let output = "Name             Type     Status           Total            Used       Available        %
+local             dir     active        98497780        12345678        81062628   12.53%
+local-lvm     lvmthin     active       832888832       123456789       709432043   14.82%
+nas               nfs   inactive               0               0               0    0.00%";
+        let available = parse_pvesm_available(output);
+        assert_eq!(available["local"], 77);
+        assert_eq!(available["local-lvm"], 676);
+        assert!(!available.contains_key("nas"));
+        assert_eq!(
+            parse_meminfo("MemTotal:       65794136 kB\nMemFree:  1 kB"),
+            Some(64252)
+        );
+        let cpuinfo = "processor\t: 0\nmodel name\t: AMD EPYC\nflags\t\t: fpu processor_trace\n\n\
+                       processor\t: 1\nmodel name\t: AMD EPYC\n";
+        assert_eq!(parse_cpuinfo(cpuinfo), Some(2));
+        assert_eq!(parse_cpuinfo(""), None);
+    }
+}
@@ -0,0 +1,372 @@
+use crate::types::{AppError, Result};
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::info;
+
+pub const DEFAULT_CONFIG_PATH: &str = "/var/lib/proxnix/config.json";
+
+static CONFIG: OnceLock<ControllerConfig> = OnceLock::new();
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControllerConfig {
+    // Goes into the owner tag of every VM, controllers sharing a cluster need distinct ids
+    pub controller_id: String,
+    pub timeouts: Timeouts,
+    pub log_dir: String,
+    pub deployments_dir: String,
+    pub git: GitConfig,
+    pub gc: GcConfig,
+    pub signing: SigningConfig,
+    pub poll: PollConfig,
+    pub crash_loop: CrashLoopPolicy,
+    pub notifications: NotificationConfig,
+    pub drift: DriftConfig,
+    pub capacity: CapacityConfig,
+    pub policy: PolicyConfig,
+    pub api: ApiConfig,
+}
+
+impl Default for ControllerConfig {
+    fn default() -> Self {
+        Self {
+            controller_id: "proxnix".to_string(),
+            timeouts: Timeouts::default(),
+            log_dir: "/var/lib/proxnix/logs".to_string(),
+            deployments_dir: "/var/lib/proxnix/deployments".to_string(),
+            git: GitConfig::default(),
+            gc: GcConfig::default(),
+            signing: SigningConfig::default(),
+            poll: PollConfig::default(),
+            crash_loop: CrashLoopPolicy::default(),
+            notifications: NotificationConfig::default(),
+            drift: DriftConfig::default(),
+            capacity: CapacityConfig::default(),
+            policy: PolicyConfig::default(),
+            api: ApiConfig::default(),
+        }
+    }
+}
+
+// A VM the periodic reconciler has started max_starts times within window_secs is crash looping.
+// Starts back off exponentially from base_backoff_secs up to max_backoff_secs.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CrashLoopPolicy {
+    pub max_starts: u32,
+    pub window_secs: u64,
+    pub base_backoff_secs: u64,
+    pub max_backoff_secs: u64,
+    pub stable_secs: u64,
+    pub console_capture_secs: u64,
+}
+
+impl Default for CrashLoopPolicy {
+    fn default() -> Self {
+        Self {
+            max_starts: 5,
+            window_secs: 30 * 60,
+            base_backoff_secs: 10,
+            max_backoff_secs: 30 * 60,
+            stable_secs: 5 * 60,
+            console_capture_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DriftMode {
+    #[default]
+    Off,
+    Report,
+    Correct,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DriftConfig {
+    pub mode: DriftMode,
+    pub interval_secs: u64,
+}
+
+impl Default for DriftConfig {
+    fn default() -> Self {
+        Self {
+            mode: DriftMode::Off,
+            interval_secs: 5 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CapacityMode {
+    Off,
+    #[default]
+    Warn,
+    Refuse,
+}
+
+// How far a plan may commit the host, see capacity.rs. Memory and vCPUs of running and onboot
+// VMs are compared to host RAM and threads times the ratio, new disks to a storage's free
+// space times the ratio. Raise storage_ratio for thin pools. Only warns unless mode is
+// refuse, so hosts that ran overcommitted before keep deploying.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CapacityConfig {
+    pub mode: CapacityMode,
+    pub memory_ratio: f64,
+    pub cpu_ratio: f64,
+    pub storage_ratio: f64,
+}
+
+impl Default for CapacityConfig {
+    fn default() -> Self {
+        Self {
+            mode: CapacityMode::Warn,
+            memory_ratio: 1.0,
+            cpu_ratio: 4.0,
+            storage_ratio: 1.0,
+        }
+    }
+}
+
+// Rules every repo's config has to follow, see policy.rs. Empty lists and unset limits allow
+// anything. Name patterns match exactly or, ending in '*', by prefix.
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyConfig {
+    pub allowed_storages: Vec<String>,
+    pub allowed_bridges: Vec<String>,
+    // Inclusive ranges, on top of each repo's vm_id_range
+    pub allowed_vm_ids: Vec<(u32, u32)>,
+    pub max_per_vm: ResourceLimits,
+    // Totals over all VMs of an environment, whether running or not
+    pub max_per_repo: ResourceLimits,
+    // VMs that have to stay protected, and so can't be destroyed or renamed away either
+    pub protected_names: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResourceLimits {
+    pub memory_mb: Option<u64>,
+    // cores * sockets
+    pub vcpus: Option<u64>,
+    pub disk_gb: Option<u64>,
+}
+
+// Bearer token for the routes that change state, see auth.rs
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiConfig {
+    pub token_file: Option<String>,
+}
+
+// mirror_dir holds one bare mirror per repo, checkout_dir one worktree per commit.
+// SSH identities are tried in order: ssh-agent, then each of ssh_keys that exists.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GitConfig {
+    pub mirror_dir: String,
+    pub checkout_dir: String,
+    pub ssh_keys: Vec<String>,
+    pub ssh_agent: bool,
+    pub known_hosts: String,
+    pub repos: Vec<RepoConfig>,
+}
+
+impl Default for GitConfig {
+    fn default() -> Self {
+        Self {
+            mirror_dir: "/var/lib/proxnix/git".to_string(),
+            checkout_dir: "/tmp/proxnix/repos".to_string(),
+            ssh_keys: vec![
+                "/root/.ssh/id_ed25519".to_string(),
+                "/root/.ssh/id_rsa".to_string(),
+                "/root/.ssh/id_ecdsa".to_string(),
+            ],
+            ssh_agent: true,
+            known_hosts: "/root/.ssh/known_hosts".to_string(),
+            repos: Vec::new(),
+        }
+    }
+}
+
+// Per-repo overrides. A webhook for the same repo over any transport is fetched from url,
+// so an HTTPS url here makes an SSH push webhook fetch over HTTPS.
+// Without token_file, HTTPS falls back to the git credential helper.
+// host_key_fingerprints ("SHA256:...") replace the known_hosts check for this repo.
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RepoConfig {
+    pub url: String,
+    // Environment name, defaults to one derived from the URL
+    pub name: Option<String>,
+    // Refs a webhook push has to be on to deploy, empty for all
+    pub refs: Vec<String>,
+    // Inclusive, the environment only creates, changes and destroys VMs with these IDs
+    pub vm_id_range: Option<(u32, u32)>,
+    pub username: Option<String>,
+    pub token_file: Option<String>,
+    pub ssh_key: Option<String>,
+    pub host_key_fingerprints: Vec<String>,
+    // Branch or tag to poll for new commits, e.g. "main". Unset means webhooks only.
+    pub poll_ref: Option<String>,
+    // Directory of the deployment flake inside the repo, needed when it has several
+    pub flake_dir: Option<String>,
+    // Attribute the flake's proxnix and nixosConfigurations outputs are nested under
+    pub attr_prefix: Option<String>,
+    // Replaces policy.max_per_repo for this environment
+    pub limits: Option<ResourceLimits>,
+}
+
+// Every interval_secs plus up to jitter_secs the repos with a poll_ref are fetched
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PollConfig {
+    pub interval_secs: u64,
+    pub jitter_secs: u64,
+}
+
+impl Default for PollConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 60,
+            jitter_secs: 15,
+        }
+    }
+}
+
+// Checkouts of the last keep_commits successful deployments are kept, as is any checkout
+// whose image is still running on a VM. nix store gc is opt-in since it affects the whole host.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GcConfig {
+    pub enabled: bool,
+    pub keep_commits: usize,
+    pub nix_store_gc: bool,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            keep_commits: 5,
+            nix_store_gc: false,
+        }
+    }
+}
+
+// With required set, only commits signed by a key in gpg_home and allowed_gpg_fingerprints,
+// or in the ssh_allowed_signers file, are deployed. GPG signatures are refused while
+// allowed_gpg_fingerprints is empty.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SigningConfig {
+    pub required: bool,
+    pub gpg_home: String,
+    pub allowed_gpg_fingerprints: Vec<String>,
+    pub ssh_allowed_signers: String,
+}
+
+impl Default for SigningConfig {
+    fn default() -> Self {
+        Self {
+            required: false,
+            gpg_home: "/var/lib/proxnix/gnupg".to_string(),
+            allowed_gpg_fingerprints: Vec::new(),
+            ssh_allowed_signers: "/var/lib/proxnix/allowed_signers".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationConfig {
+    pub webhook_url: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    pub nix_build_secs: u64,
+    pub nix_eval_secs: u64,
+    pub qm_secs: u64,
+    pub qm_importdisk_secs: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            nix_build_secs: 2 * 60 * 60,
+            nix_eval_secs: 10 * 60,
+            qm_secs: 5 * 60,
+            qm_importdisk_secs: 30 * 60,
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn nix_build(&self) -> Duration {
+        Duration::from_secs(self.nix_build_secs)
+    }
+
+    pub fn nix_eval(&self) -> Duration {
+        Duration::from_secs(self.nix_eval_secs)
+    }
+
+    pub fn qm(&self) -> Duration {
+        Duration::from_secs(self.qm_secs)
+    }
+
+    pub fn qm_importdisk(&self) -> Duration {
+        Duration::from_secs(self.qm_importdisk_secs)
+    }
+}
+
+// A missing config file is not an error, every setting has a default
+pub fn load(path: &str) -> Result<ControllerConfig> {
+    if !Path::new(path).exists() {
+        info!("No config at {}, using defaults", path);
+        return Ok(ControllerConfig::default());
+    }
+    let raw = std::fs::read_to_string(path)?;
+    let config: ControllerConfig = serde_json::from_str(&raw)?;
+    crate::repos::validate(&config.git.repos).map_err(AppError::ConfigError)?;
+    info!("Loaded config from {}", path);
+    Ok(config)
+}
+
+pub fn init(config: ControllerConfig) {
+    let _ = CONFIG.set(config);
+}
+
+pub fn get() -> &'static ControllerConfig {
+    CONFIG.get_or_init(ControllerConfig::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unknown_keys_are_rejected() {
+        let config: ControllerConfig =
+            serde_json::from_str(r#"{"signing": {"required": true}}"#).unwrap();
+        assert!(config.signing.required);
+        // A typo must not silently fall back to the default
+        assert!(
+            serde_json::from_str::<ControllerConfig>(r#"{"signing": {"require": true}}"#).is_err()
+        );
+        assert!(
+            serde_json::from_str::<ControllerConfig>(
+                r#"{"git": {"repos": [{"url": "u", "ref": ["main"]}]}}"#
+            )
+            .is_err()
+        );
+        assert!(serde_json::from_str::<ControllerConfig>(r#"{"controler_id": "a"}"#).is_err());
+    }
+}
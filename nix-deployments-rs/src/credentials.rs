@@ -0,0 +1,184 @@
+use crate::config::{self, RepoConfig};
+use crate::hostkeys;
+use git2::{CertificateCheckStatus, Cred, CredentialType, RemoteCallbacks};
+use std::path::Path;
+use tracing::{error, info};
+
+// GitHub accepts any username with a token, GitLab wants "oauth2", set it per repo if needed
+const DEFAULT_TOKEN_USERNAME: &str = "x-access-token";
+
+#[derive(Debug, Clone)]
+enum SshCandidate {
+    Agent,
+    Key(String),
+}
+
+// Keeps [A-Za-z0-9.-] and writes every other byte as +xx. Distinct values never end up with
+// the same result, which is safe in paths, ref names and Proxmox tags.
+pub fn escape(value: &str) -> String {
+    let mut out = String::new();
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'.' || byte == b'-' {
+            out.push(byte as char);
+        } else {
+            out.push_str(&format!("+{:02x}", byte));
+        }
+    }
+    out
+}
+
+// Identifies a repo independent of transport and user, so git@github.com:org/infra.git
+// and https://github.com/org/infra.git map to the same github.com_org_infra. Only '/' and
+// ':' become '_', anything else is escaped so org/a_b and org_a/b stay apart.
+pub fn repo_key(repo_url: &str) -> String {
+    let url = repo_url.trim_end_matches('/').trim_end_matches(".git");
+    let url = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+    let url = url.split_once('@').map(|(_, rest)| rest).unwrap_or(url);
+    url.split(['/', ':'])
+        .map(escape)
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+pub fn repo_config(repo_url: &str) -> Option<&'static RepoConfig> {
+    let key = repo_key(repo_url);
+    config::get()
+        .git
+        .repos
+        .iter()
+        .find(|repo| repo_key(&repo.url) == key)
+}
+
+fn ssh_candidates(repo: Option<&RepoConfig>) -> Vec<SshCandidate> {
+    if let Some(key) = repo.and_then(|r| r.ssh_key.clone()) {
+        return vec![SshCandidate::Key(key)];
+    }
+    let git = &config::get().git;
+    let mut candidates = Vec::new();
+    if git.ssh_agent && std::env::var_os("SSH_AUTH_SOCK").is_some() {
+        candidates.push(SshCandidate::Agent);
+    }
+    candidates.extend(
+        git.ssh_keys
+            .iter()
+            .filter(|path| Path::new(path).exists())
+            .cloned()
+            .map(SshCandidate::Key),
+    );
+    candidates
+}
+
+fn read_token(path: &str) -> Result<String, git2::Error> {
+    std::fs::read_to_string(path)
+        .map(|token| token.trim().to_string())
+        .map_err(|e| git2::Error::from_str(&format!("Failed to read token file {}: {}", path, e)))
+}
+
+// libgit2 calls the credentials callback again after every rejected attempt, so each
+// SSH identity and the HTTPS credential are offered once and then it gives up
+pub fn remote_callbacks<'a>(repo_url: &str) -> RemoteCallbacks<'a> {
+    let repo = repo_config(repo_url);
+    let mut ssh = ssh_candidates(repo).into_iter();
+    let mut userpass_tried = false;
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(move |url, username, allowed| {
+        let configured_user = repo.and_then(|r| r.username.as_deref());
+        if allowed.contains(CredentialType::USERNAME) {
+            return Cred::username(configured_user.or(username).unwrap_or("git"));
+        }
+        if allowed.contains(CredentialType::SSH_KEY) {
+            let user = configured_user.or(username).unwrap_or("git");
+            return match ssh.next() {
+                Some(SshCandidate::Agent) => {
+                    info!("Using ssh-agent for {}", url);
+                    Cred::ssh_key_from_agent(user)
+                }
+                Some(SshCandidate::Key(path)) => {
+                    info!("Using SSH key: {}", path);
+                    Cred::ssh_key(user, None, Path::new(&path), None)
+                }
+                None => Err(git2::Error::from_str(
+                    "No usable SSH key for the repo, configure git.ssh_keys or start ssh-agent",
+                )),
+            };
+        }
+        if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) && !userpass_tried {
+            userpass_tried = true;
+            if let Some(token_file) = repo.and_then(|r| r.token_file.as_deref()) {
+                info!("Using token from {} for {}", token_file, url);
+                let user = configured_user.unwrap_or(DEFAULT_TOKEN_USERNAME);
+                return Cred::userpass_plaintext(user, &read_token(token_file)?);
+            }
+            let git_config = git2::Config::open_default()?;
+            return Cred::credential_helper(&git_config, url, configured_user.or(username));
+        }
+        Err(git2::Error::from_str(&format!(
+            "No credentials accepted for {} (allowed: {:?})",
+            url, allowed
+        )))
+    });
+    let port = hostkeys::ssh_host_port(repo_url).map_or(22, |(_, port)| port);
+    callbacks.certificate_check(move |cert, host| {
+        // HTTPS certificates are left to libgit2's normal validation
+        let Some(hostkey) = cert.as_hostkey() else {
+            return Ok(CertificateCheckStatus::CertificatePassthrough);
+        };
+        verify_host_key(hostkey, host, port, repo)
+            .map(|()| CertificateCheckStatus::CertificateOk)
+            .map_err(|e| {
+                error!("{}", e);
+                git2::Error::from_str(&e)
+            })
+    });
+    callbacks
+}
+
+fn verify_host_key(
+    hostkey: &git2::cert::CertHostkey,
+    host: &str,
+    port: u16,
+    repo: Option<&RepoConfig>,
+) -> Result<(), String> {
+    let pins = repo
+        .map(|r| r.host_key_fingerprints.as_slice())
+        .unwrap_or_default();
+    if !pins.is_empty() {
+        let sha256 = hostkey
+            .hash_sha256()
+            .ok_or_else(|| format!("No SHA256 host key fingerprint available for {}", host))?;
+        return hostkeys::verify_pinned(pins, host, sha256);
+    }
+    let (Some(key), Some(key_type)) = (hostkey.hostkey(), hostkey.hostkey_type()) else {
+        return Err(format!("No raw host key available for {}", host));
+    };
+    let known_hosts = hostkeys::load_known_hosts(&config::get().git.known_hosts)?;
+    hostkeys::verify_known_hosts(&known_hosts, host, port, key_type.name(), key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repo_key_ignores_transport() {
+        let key = "github.com_org_infra";
+        assert_eq!(repo_key("git@github.com:org/infra.git"), key);
+        assert_eq!(repo_key("https://github.com/org/infra.git"), key);
+        assert_eq!(repo_key("ssh://git@github.com/org/infra.git"), key);
+        assert_eq!(
+            repo_key("ssh://git@gitea.example.com:2222/org/infra.git"),
+            "gitea.example.com_2222_org_infra"
+        );
+    }
+
+    #[test]
+    fn test_repo_key_is_unambiguous() {
+        assert_ne!(
+            repo_key("git@github.com:a/b_c.git"),
+            repo_key("git@github.com:a_b/c.git")
+        );
+        assert_eq!(repo_key("https://github.com/a/b_c"), "github.com_a_b+5fc");
+        assert_eq!(repo_key("https://github.com/a_b/c"), "github.com_a+5fb_c");
+        assert_eq!(escape("web+1 ü"), "web+2b1+20+c3+bc");
+    }
+}
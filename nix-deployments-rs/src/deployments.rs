@@ -0,0 +1,177 @@
+use crate::credentials::repo_config;
+use crate::repos;
+use crate::types::{DeploymentSnapshot, Result};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+const CURRENT_FILE: &str = "current";
+
+// Commit hashes end up in file paths
+pub fn valid_commit(commit_hash: &str) -> bool {
+    !commit_hash.is_empty() && commit_hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// Each environment has its own history under <deployments_dir>/<environment>
+pub fn environment_dir(dir: &str, environment: &str) -> String {
+    Path::new(dir)
+        .join(environment)
+        .to_string_lossy()
+        .to_string()
+}
+
+pub fn environments(dir: &str) -> Result<Vec<String>> {
+    if !Path::new(dir).exists() {
+        return Ok(Vec::new());
+    }
+    let mut names: Vec<String> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().to_str().map(|s| s.to_string()))
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+// Before environments existed the single repo's snapshots sat directly in deployments_dir.
+// They are moved into the directory of the environment their repo maps to.
+pub fn migrate_legacy(dir: &str) -> Result<()> {
+    let Some(current) = load_current(dir)? else {
+        return Ok(());
+    };
+    let environment = match repo_config(&current.repo_url) {
+        Some(repo) => repos::environment(repo),
+        None => repos::environment(&repos::unconfigured(&current.repo_url)),
+    };
+    let target = environment_dir(dir, &environment);
+    info!("Moving deployment history into environment {}", environment);
+    std::fs::create_dir_all(&target)?;
+    for mut snapshot in list(dir)? {
+        snapshot.environment = environment.clone();
+        write_atomic(
+            &snapshot_path(&target, &snapshot.commit_hash),
+            serde_json::to_string_pretty(&snapshot)?.as_bytes(),
+        )?;
+        std::fs::remove_file(snapshot_path(dir, &snapshot.commit_hash))?;
+    }
+    std::fs::rename(
+        Path::new(dir).join(CURRENT_FILE),
+        Path::new(&target).join(CURRENT_FILE),
+    )?;
+    Ok(())
+}
+
+// Each successful pipeline leaves <dir>/<commit>.json behind, <dir>/current names the one
+// that is live. Only a new successful pipeline moves current.
+pub fn snapshot_path(dir: &str, commit_hash: &str) -> PathBuf {
+    Path::new(dir).join(format!("{}.json", commit_hash))
+}
+
+// Written to a temp file and renamed so a crash mid-write never leaves a torn snapshot
+fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+pub fn save(dir: &str, snapshot: &DeploymentSnapshot) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    write_atomic(
+        &snapshot_path(dir, &snapshot.commit_hash),
+        serde_json::to_string_pretty(snapshot)?.as_bytes(),
+    )?;
+    write_atomic(
+        &Path::new(dir).join(CURRENT_FILE),
+        snapshot.commit_hash.as_bytes(),
+    )?;
+    info!(
+        "Cached deployment snapshot for commit {}",
+        snapshot.commit_hash
+    );
+    Ok(())
+}
+
+pub fn load(dir: &str, commit_hash: &str) -> Result<DeploymentSnapshot> {
+    let raw = std::fs::read_to_string(snapshot_path(dir, commit_hash))?;
+    Ok(serde_json::from_str(&raw)?)
+}
+
+// None until the first pipeline has succeeded
+pub fn load_current(dir: &str) -> Result<Option<DeploymentSnapshot>> {
+    let current = Path::new(dir).join(CURRENT_FILE);
+    if !current.exists() {
+        return Ok(None);
+    }
+    let commit_hash = std::fs::read_to_string(current)?;
+    load(dir, commit_hash.trim()).map(Some)
+}
+
+// Every cached deployment, most recent first
+pub fn list(dir: &str) -> Result<Vec<DeploymentSnapshot>> {
+    if !Path::new(dir).exists() {
+        return Ok(Vec::new());
+    }
+    let mut snapshots: Vec<DeploymentSnapshot> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name();
+            let commit_hash = name.to_str()?.strip_suffix(".json")?;
+            // Other state such as vm_ids.json lives here too
+            if !valid_commit(commit_hash) {
+                return None;
+            }
+            load(dir, commit_hash).ok()
+        })
+        .collect();
+    snapshots.sort_by_key(|snapshot| std::cmp::Reverse(snapshot.deployed_at));
+    Ok(snapshots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::DesiredState;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_save_and_load_current() {
+        let dir = std::env::temp_dir().join(format!("proxnix-deployments-{}", std::process::id()));
+        let dir = dir.to_str().unwrap();
+        assert!(load_current(dir).unwrap().is_none());
+
+        for (deployed_at, commit) in ["aaa", "bbb"].into_iter().enumerate() {
+            let snapshot = DeploymentSnapshot {
+                repo_url: "git@example.com:infra.git".to_string(),
+                environment: String::new(),
+                commit_hash: commit.to_string(),
+                deployed_at: deployed_at as u64,
+                desired: DesiredState {
+                    vms: HashMap::new(),
+                },
+                image_hashes: HashMap::from([("base".to_string(), commit.to_string())]),
+            };
+            save(dir, &snapshot).unwrap();
+        }
+
+        let current = load_current(dir).unwrap().unwrap();
+        assert_eq!(current.commit_hash, "bbb");
+        assert_eq!(current.image_hashes["base"], "bbb");
+        assert_eq!(load(dir, "aaa").unwrap().commit_hash, "aaa");
+        let commits: Vec<String> = list(dir)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.commit_hash)
+            .collect();
+        assert_eq!(commits, vec!["bbb", "aaa"]);
+
+        migrate_legacy(dir).unwrap();
+        assert_eq!(environments(dir).unwrap(), vec!["example.com_infra"]);
+        let moved = environment_dir(dir, "example.com_infra");
+        let current = load_current(&moved).unwrap().unwrap();
+        assert_eq!(current.commit_hash, "bbb");
+        assert_eq!(current.environment, "example.com_infra");
+        assert_eq!(list(&moved).unwrap().len(), 2);
+        assert!(load_current(dir).unwrap().is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,240 @@
+use crate::config::{self, DriftMode};
+use crate::notify::Notification;
+use crate::qm::qm_set_resources;
+use crate::repos;
+use crate::state::full_diff;
+use crate::types::{
+    DeploymentSnapshot, DriftClass, DriftEntry, DriftReport, FieldChange, Result, StateDiff,
+    UpdateAction, VMUpdate,
+};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+#[derive(Debug, Default)]
+pub struct DriftState {
+    pub last_check: Option<Instant>,
+    pub report: Option<DriftReport>,
+    pub checks_total: u64,
+    pub check_failures_total: u64,
+    pub corrections_total: u64,
+}
+
+// Power state is the reconciler's job every tick, drift only covers configuration
+fn drift_fields(update: &VMUpdate) -> Vec<FieldChange> {
+    update
+        .changed_fields
+        .iter()
+        .filter(|f| **f != FieldChange::PowerState)
+        .cloned()
+        .collect()
+}
+
+pub fn classify(diff: &StateDiff) -> Vec<DriftEntry> {
+    let mut entries = Vec::new();
+    for update in &diff.to_update {
+        let fields = drift_fields(update);
+        if fields.is_empty() {
+            continue;
+        }
+        // Re-derive the action without power state, it may have been the only in-place field
+        let class = match update.required_action {
+            UpdateAction::Protected => DriftClass::Protected,
+            _ if fields.contains(&FieldChange::Disk)
+                || fields.contains(&FieldChange::Image)
+                || fields.contains(&FieldChange::VmId) =>
+            {
+                DriftClass::Rebuild
+            }
+            _ => DriftClass::InPlace,
+        };
+        entries.push(DriftEntry {
+            name: update.name.clone(),
+            vm_id: update.config.vm_id,
+            class,
+            fields,
+            corrected: false,
+        });
+    }
+    for config in &diff.to_create {
+        entries.push(DriftEntry {
+            name: config.name.clone(),
+            vm_id: config.vm_id,
+            class: DriftClass::Missing,
+            fields: Vec::new(),
+            corrected: false,
+        });
+    }
+    for vm in &diff.to_delete {
+        entries.push(DriftEntry {
+            name: vm.vm_name.clone(),
+            vm_id: vm.vm_id,
+            class: DriftClass::Unexpected,
+            fields: Vec::new(),
+            corrected: false,
+        });
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    entries
+}
+
+// Diffs live state against what was last deployed. In correct mode only in-place drift is
+// fixed, anything that would need a rebuild, create or destroy waits for a pipeline run.
+pub fn check_drift(snapshot: &DeploymentSnapshot, mode: DriftMode) -> Result<DriftReport> {
+    let repo = repos::find(&snapshot.environment)
+        .unwrap_or_else(|| repos::unconfigured(&snapshot.repo_url));
+    let diff = full_diff(
+        &snapshot.desired,
+        &snapshot.image_hashes,
+        &repos::scope(&repo)?,
+    )?;
+    let mut entries = classify(&diff);
+
+    if mode == DriftMode::Correct {
+        for entry in entries
+            .iter_mut()
+            .filter(|e| e.class == DriftClass::InPlace)
+        {
+            let Some(update) = diff.to_update.iter().find(|u| u.name == entry.name) else {
+                continue;
+            };
+            let update = VMUpdate {
+                changed_fields: entry.fields.clone(),
+                ..update.clone()
+            };
+            match qm_set_resources(entry.vm_id, &update) {
+                Ok(_) => {
+                    info!("Drift: corrected {:?} on {}", entry.fields, entry.name);
+                    entry.corrected = true;
+                }
+                Err(e) => warn!("Drift: failed to correct {}: {}", entry.name, e),
+            }
+        }
+    }
+
+    Ok(DriftReport {
+        commit_hash: snapshot.commit_hash.clone(),
+        checked_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        entries,
+    })
+}
+
+// Whether a drift check is due, marking it started if so. Called under the drift lock, the
+// check itself (check_drift) runs without it.
+pub fn start_if_due(state: &mut DriftState) -> bool {
+    let drift_config = &config::get().drift;
+    if drift_config.mode == DriftMode::Off {
+        return false;
+    }
+    let now = Instant::now();
+    if state.last_check.is_some_and(|last| {
+        now.duration_since(last) < Duration::from_secs(drift_config.interval_secs)
+    }) {
+        return false;
+    }
+    state.last_check = Some(now);
+    state.checks_total += 1;
+    true
+}
+
+// Records a finished check under the drift lock. Returns the notifications to send once
+// the lock is released.
+pub fn record(
+    snapshot: &DeploymentSnapshot,
+    result: Result<DriftReport>,
+    state: &mut DriftState,
+) -> Vec<Notification> {
+    let report = match result {
+        Ok(report) => report,
+        Err(e) => {
+            warn!("Drift check failed: {:?}", e);
+            state.check_failures_total += 1;
+            return Vec::new();
+        }
+    };
+    state.corrections_total += report.entries.iter().filter(|e| e.corrected).count() as u64;
+
+    let mut notifications = Vec::new();
+    let outstanding: Vec<&DriftEntry> = report.entries.iter().filter(|e| !e.corrected).collect();
+    let previous: Vec<&DriftEntry> = state
+        .report
+        .as_ref()
+        .map(|r| r.entries.iter().filter(|e| !e.corrected).collect())
+        .unwrap_or_default();
+    if outstanding.is_empty() {
+        info!(
+            "Drift check: live state matches commit {}",
+            snapshot.commit_hash
+        );
+    } else if outstanding != previous {
+        // Only notify when the drift changes, not on every check
+        let summary: Vec<String> = outstanding
+            .iter()
+            .map(|e| format!("{} ({:?} {:?})", e.name, e.class, e.fields))
+            .collect();
+        warn!("Drift detected: {}", summary.join(", "));
+        notifications.push(Notification {
+            event: "drift".to_string(),
+            vm: None,
+            message: format!(
+                "Live state differs from commit {}: {}",
+                snapshot.commit_hash,
+                summary.join(", ")
+            ),
+        });
+    }
+    for entry in report.entries.iter().filter(|e| e.corrected) {
+        notifications.push(Notification {
+            event: "drift_corrected".to_string(),
+            vm: Some(entry.name.clone()),
+            message: format!("Corrected {:?} on {}", entry.fields, entry.name),
+        });
+    }
+    state.report = Some(report);
+    notifications
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::fixtures::{deployed_vm, vm_config};
+
+    fn update(name: &str, fields: Vec<FieldChange>, action: UpdateAction) -> VMUpdate {
+        VMUpdate {
+            name: name.to_string(),
+            config: vm_config(name, 100),
+            deployed: deployed_vm(name, 100),
+            changed_fields: fields,
+            required_action: action,
+        }
+    }
+
+    #[test]
+    fn test_classify_ignores_power_state() {
+        let diff = StateDiff {
+            to_create: Vec::new(),
+            to_update: vec![
+                update("a", vec![FieldChange::PowerState], UpdateAction::InPlace),
+                update(
+                    "b",
+                    vec![FieldChange::PowerState, FieldChange::Memory],
+                    UpdateAction::InPlace,
+                ),
+                update(
+                    "c",
+                    vec![FieldChange::Memory, FieldChange::Disk],
+                    UpdateAction::Rebuild,
+                ),
+            ],
+            to_delete: Vec::new(),
+        };
+        let entries = classify(&diff);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "b");
+        assert_eq!(entries[0].class, DriftClass::InPlace);
+        assert_eq!(entries[0].fields, vec![FieldChange::Memory]);
+        assert_eq!(entries[1].class, DriftClass::Rebuild);
+    }
+}
@@ -0,0 +1,342 @@
+use crate::logs::PipelineLog;
+use crate::types::{AppError, Result};
+use std::cell::RefCell;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Output, Stdio};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tokio::runtime::Runtime;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+// Program plus this many leading words make up a command's label, see describe()
+const MAX_LABEL_WORDS: usize = 3;
+
+// External commands are driven from sync code (spawn_blocking and rayon threads),
+// so they get their own runtime rather than borrowing the server's
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+// What the commands run on a thread belong to: the token that cancels them and
+// the pipeline log (and stream within it) that their output goes to
+#[derive(Clone, Default)]
+pub struct ExecContext {
+    pub cancel: CancellationToken,
+    pub log: Option<Arc<PipelineLog>>,
+    pub stream: String,
+}
+
+impl ExecContext {
+    pub fn with_stream(&self, stream: impl Into<String>) -> Self {
+        Self {
+            stream: stream.into(),
+            ..self.clone()
+        }
+    }
+}
+
+thread_local! {
+    static CONTEXT: RefCell<ExecContext> = RefCell::new(ExecContext::default());
+}
+
+fn runtime() -> &'static Runtime {
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("proxnix-exec")
+            .enable_all()
+            .build()
+            .expect("Failed to build exec runtime")
+    })
+}
+
+struct ContextGuard(Option<ExecContext>);
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        if let Some(previous) = self.0.take() {
+            CONTEXT.with(|c| *c.borrow_mut() = previous);
+        }
+    }
+}
+
+// Runs f with ctx as the context for every command it executes on this thread.
+// The previous context is restored afterwards, even on panic, since blocking pool threads are reused.
+pub fn with_context<T>(ctx: ExecContext, f: impl FnOnce() -> T) -> T {
+    let _guard = ContextGuard(Some(CONTEXT.with(|c| c.replace(ctx))));
+    f()
+}
+
+pub fn with_cancel<T>(token: CancellationToken, f: impl FnOnce() -> T) -> T {
+    with_context(
+        ExecContext {
+            cancel: token,
+            ..current()
+        },
+        f,
+    )
+}
+
+pub fn with_stream<T>(stream: impl Into<String>, f: impl FnOnce() -> T) -> T {
+    with_context(current().with_stream(stream), f)
+}
+
+pub fn current() -> ExecContext {
+    CONTEXT.with(|c| c.borrow().clone())
+}
+
+pub fn current_cancel() -> CancellationToken {
+    current().cancel
+}
+
+// Writes a line to the current pipeline log, if there is one
+pub fn log_line(line: &str) {
+    let ctx = current();
+    if let Some(log) = ctx.log {
+        log.write(&ctx.stream, line);
+    }
+}
+
+pub fn check_cancelled() -> Result<()> {
+    if current_cancel().is_cancelled() {
+        return Err(AppError::Cancelled("operation cancelled".to_string()));
+    }
+    Ok(())
+}
+
+// Sleeps for duration, returning early with an error if the current token is cancelled
+pub fn sleep(duration: Duration) -> Result<()> {
+    let token = current_cancel();
+    runtime().block_on(async {
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => Ok(()),
+            _ = token.cancelled() => Err(AppError::Cancelled("cancelled while waiting".to_string())),
+        }
+    })
+}
+
+// Label for the logs, which are streamed to anyone who can reach the API: the program and its
+// subcommand, e.g. "qm set 105". Options, paths, URLs and anything else that may carry a
+// secret are left out.
+fn describe(cmd: &Command) -> String {
+    let mut label = cmd.get_program().to_string_lossy().to_string();
+    let mut args = cmd.get_args().map(|a| a.to_string_lossy());
+    for arg in args.by_ref().take(MAX_LABEL_WORDS) {
+        let word = !arg.starts_with('-')
+            && arg
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !word {
+            label.push_str(" ...");
+            return label;
+        }
+        label.push(' ');
+        label.push_str(&arg);
+    }
+    if args.next().is_some() {
+        label.push_str(" ...");
+    }
+    label
+}
+
+// Runs cmd in its own process group so a timeout or cancel takes out anything it spawned too.
+// stdout and stderr are read line by line as they arrive as well as returned in full.
+pub fn run(cmd: Command, timeout: Duration) -> Result<Output> {
+    run_inner(cmd, None, timeout)
+}
+
+// Same as run, with input written to the command's stdin
+pub fn run_with_input(cmd: Command, input: Vec<u8>, timeout: Duration) -> Result<Output> {
+    run_inner(cmd, Some(input), timeout)
+}
+
+fn run_inner(mut cmd: Command, input: Option<Vec<u8>>, timeout: Duration) -> Result<Output> {
+    let label = describe(&cmd);
+    let ctx = current();
+    cmd.process_group(0)
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    runtime().block_on(run_async(cmd, input, label, timeout, ctx))
+}
+
+async fn run_async(
+    cmd: Command,
+    input: Option<Vec<u8>>,
+    label: String,
+    timeout: Duration,
+    ctx: ExecContext,
+) -> Result<Output> {
+    let token = ctx.cancel.clone();
+    if let Some(log) = &ctx.log {
+        log.write(&ctx.stream, &format!("$ {}", label));
+    }
+    if token.is_cancelled() {
+        return Err(AppError::Cancelled(format!("not starting '{}'", label)));
+    }
+    let mut child = tokio::process::Command::from(cmd)
+        .kill_on_drop(true)
+        .spawn()?;
+    let pid = child.id();
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        // Dropping stdin once written closes it so the command sees EOF
+        tokio::spawn(async move {
+            let _ = stdin.write_all(&input).await;
+        });
+    }
+    let stdout = tokio::spawn(capture(child.stdout.take(), label.clone(), ctx.clone()));
+    let stderr = tokio::spawn(capture(child.stderr.take(), label.clone(), ctx));
+
+    let status = tokio::select! {
+        status = child.wait() => status?,
+        _ = tokio::time::sleep(timeout) => {
+            warn!("'{}' exceeded its {:?} timeout, killing", label, timeout);
+            kill_group(pid);
+            let _ = child.wait().await;
+            return Err(AppError::CmdTimeout(format!("'{}' exceeded {:?}", label, timeout)));
+        }
+        _ = token.cancelled() => {
+            warn!("'{}' cancelled, killing", label);
+            kill_group(pid);
+            let _ = child.wait().await;
+            return Err(AppError::Cancelled(format!("'{}' was cancelled", label)));
+        }
+    };
+
+    Ok(Output {
+        status,
+        stdout: stdout.await.unwrap_or_default(),
+        stderr: stderr.await.unwrap_or_default(),
+    })
+}
+
+async fn capture(
+    stream: Option<impl AsyncRead + Unpin>,
+    label: String,
+    ctx: ExecContext,
+) -> Vec<u8> {
+    let Some(stream) = stream else {
+        return Vec::new();
+    };
+    let mut reader = BufReader::new(stream);
+    let mut captured = Vec::new();
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                let text = String::from_utf8_lossy(&line);
+                let text = text.trim_end();
+                debug!("[{}] {}", label, text);
+                if let Some(log) = &ctx.log {
+                    log.write(&ctx.stream, text);
+                }
+                captured.extend_from_slice(&line);
+            }
+        }
+    }
+    captured
+}
+
+fn kill_group(pid: Option<u32>) {
+    if let Some(pid) = pid {
+        // The child was started with process_group(0), so its pid is also the group id
+        unsafe {
+            libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn test_describe_leaves_out_arguments() {
+        let mut cmd = Command::new("qm");
+        cmd.args(["set", "105", "--tags", "proxnix"]);
+        assert_eq!(describe(&cmd), "qm set 105 ...");
+        let mut cmd = Command::new("curl");
+        cmd.args(["-sS", "https://hooks.example.com/T0KEN"]);
+        assert_eq!(describe(&cmd), "curl ...");
+        let mut cmd = Command::new("pvesm");
+        cmd.arg("status");
+        assert_eq!(describe(&cmd), "pvesm status");
+    }
+
+    #[test]
+    fn test_run_captures_output() {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("echo out; echo err >&2");
+        let output = run(cmd, Duration::from_secs(5)).unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "out\n");
+        assert_eq!(String::from_utf8_lossy(&output.stderr), "err\n");
+    }
+
+    #[test]
+    fn test_run_with_input() {
+        let output = run_with_input(
+            Command::new("cat"),
+            b"signed data".to_vec(),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "signed data");
+    }
+
+    #[test]
+    fn test_run_times_out() {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("sleep 30");
+        let start = Instant::now();
+        let result = run(cmd, Duration::from_millis(200));
+        assert!(matches!(result, Err(AppError::CmdTimeout(_))));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_run_cancelled() {
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            canceller.cancel();
+        });
+        let result = with_cancel(token, || {
+            let mut cmd = Command::new("sh");
+            cmd.arg("-c").arg("sleep 30");
+            run(cmd, Duration::from_secs(30))
+        });
+        assert!(matches!(result, Err(AppError::Cancelled(_))));
+        assert!(!current_cancel().is_cancelled());
+    }
+
+    #[test]
+    fn test_run_writes_pipeline_log() {
+        let dir = std::env::temp_dir().join(format!("proxnix-exec-test-{}", std::process::id()));
+        let dir = dir.to_str().unwrap();
+        let log = PipelineLog::create(dir, "test-run").unwrap();
+        let ctx = ExecContext {
+            log: Some(log),
+            stream: "qm/test-vm".to_string(),
+            ..ExecContext::default()
+        };
+        with_context(ctx, || {
+            let mut cmd = Command::new("sh");
+            cmd.arg("-c").arg("echo hello");
+            run(cmd, Duration::from_secs(5)).unwrap();
+        });
+        let lines = crate::logs::read_log(dir, "test-run").unwrap();
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|l| l.stream == "qm/test-vm"));
+        assert_eq!(lines[1].line, "hello");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
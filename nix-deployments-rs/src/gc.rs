@@ -0,0 +1,246 @@
+use crate::config;
+use crate::deployments;
+use crate::exec;
+use crate::git::git_remove_checkout;
+use crate::state::load_state;
+use crate::types::{AppError, GcReport, Result};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::{info, warn};
+
+// Apparent size of everything under path, symlinks (the nix out-links) are not followed
+pub fn dir_size(path: &Path) -> u64 {
+    let Ok(meta) = std::fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !meta.is_dir() {
+        return meta.len();
+    }
+    std::fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| dir_size(&entry.path()))
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+// Nix hashes of the images a checkout's out-links point at, i.e. <checkout>/<image>/result
+fn out_link_hashes(checkout: &Path) -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(checkout) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| std::fs::read_link(entry.path().join("result")).ok())
+        .filter_map(|target| {
+            target
+                .to_str()?
+                .strip_prefix("/nix/store/")?
+                .split('-')
+                .next()
+                .map(|hash| hash.to_string())
+        })
+        .collect()
+}
+
+// Checkouts as (environment, commit, path), laid out as <checkout_dir>/<environment>/<commit>.
+// Checkouts from before environments had their own directory sit directly in checkout_dir and
+// have no environment.
+fn list_checkouts(checkout_dir: &Path) -> Vec<(Option<String>, String, PathBuf)> {
+    let entries = |dir: &Path| -> Vec<(String, PathBuf)> {
+        std::fs::read_dir(dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .filter_map(|entry| {
+                        Some((entry.file_name().to_str()?.to_string(), entry.path()))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    let mut checkouts = Vec::new();
+    for (name, path) in entries(checkout_dir) {
+        // A worktree has a .git file, an environment's directory doesn't
+        if path.join(".git").exists() {
+            checkouts.push((None, name, path));
+            continue;
+        }
+        checkouts.extend(
+            entries(&path)
+                .into_iter()
+                .map(|(commit, path)| (Some(name.clone()), commit, path)),
+        );
+    }
+    checkouts
+}
+
+// Parses the summary line of `nix store gc`, e.g. "12 store paths deleted, 1.50 GiB freed"
+pub fn parse_store_gc(stderr: &str) -> Option<(u64, u64)> {
+    let line = stderr
+        .lines()
+        .rev()
+        .find(|l| l.contains("store paths deleted"))?;
+    let (deleted, freed) = line.split_once(',')?;
+    let deleted = deleted.split_whitespace().next()?.parse().ok()?;
+    let mut parts = freed.split_whitespace();
+    let amount: f64 = parts.next()?.parse().ok()?;
+    let unit = match parts.next()? {
+        "KiB" => 1u64 << 10,
+        "MiB" => 1 << 20,
+        "GiB" => 1 << 30,
+        "TiB" => 1 << 40,
+        _ => 1,
+    };
+    Some((deleted, (amount * unit as f64) as u64))
+}
+
+fn nix_store_gc() -> Result<(u64, u64)> {
+    let mut cmd = Command::new("nix");
+    cmd.arg("store").arg("gc");
+    let output = exec::run(cmd, config::get().timeouts.nix_build())?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        return Err(AppError::CmdError(format!(
+            "nix store gc failed (exit: {:?}): {}",
+            output.status.code(),
+            stderr
+        )));
+    }
+    Ok(parse_store_gc(&stderr).unwrap_or_default())
+}
+
+// Removes checkouts (and with them their out-links, which are the GC roots of old images)
+// that are neither among the last keep_commits deployments of their environment, nor backing
+// a running VM, nor in use by a running pipeline. in_use is asked only after the checkouts
+// are listed, so a pipeline that started in between is already included.
+pub fn collect(in_use: &dyn Fn() -> HashSet<(String, String)>) -> Result<GcReport> {
+    let gc = &config::get().gc;
+    let deployments_dir = &config::get().deployments_dir;
+    let mut keep = HashSet::new();
+    for environment in deployments::environments(deployments_dir)? {
+        let dir = deployments::environment_dir(deployments_dir, &environment);
+        keep.extend(
+            deployments::list(&dir)?
+                .into_iter()
+                .take(gc.keep_commits)
+                .map(|snapshot| (environment.clone(), snapshot.commit_hash)),
+        );
+        if let Some(current) = deployments::load_current(&dir)? {
+            keep.insert((environment.clone(), current.commit_hash));
+        }
+    }
+    // If Proxmox can't be asked nothing is known to be unused, so nothing is removed
+    let deployed_hashes: HashSet<String> = load_state()?
+        .vms
+        .into_values()
+        .filter_map(|vm| vm.nix_hash)
+        .collect();
+
+    let mut report = GcReport::default();
+    let checkouts = list_checkouts(Path::new(&config::get().git.checkout_dir));
+    keep.extend(in_use());
+    for (environment, commit_hash, path) in checkouts {
+        exec::check_cancelled()?;
+        let kept = match &environment {
+            Some(environment) => keep.contains(&(environment.clone(), commit_hash.clone())),
+            None => keep.iter().any(|(_, kept)| *kept == commit_hash),
+        };
+        if kept {
+            continue;
+        }
+        let name = match &environment {
+            Some(environment) => format!("{}/{}", environment, commit_hash),
+            None => commit_hash,
+        };
+        if let Some(hash) = out_link_hashes(&path)
+            .into_iter()
+            .find(|hash| deployed_hashes.contains(hash))
+        {
+            info!(
+                "GC: keeping checkout {}, its image {} is still deployed",
+                name, hash
+            );
+            continue;
+        }
+        let size = dir_size(&path);
+        match git_remove_checkout(&path) {
+            Ok(()) => {
+                info!("GC: removed checkout {} ({} bytes)", name, size);
+                report.checkout_bytes_freed += size;
+                report.removed_checkouts.push(name);
+            }
+            Err(e) => warn!("GC: failed to remove checkout {}: {:?}", name, e),
+        }
+    }
+    report.removed_checkouts.sort();
+
+    if gc.nix_store_gc {
+        let (deleted, freed) = nix_store_gc()?;
+        info!(
+            "GC: nix store gc deleted {} paths, {} bytes",
+            deleted, freed
+        );
+        report.store_paths_deleted = Some(deleted);
+        report.store_bytes_freed = Some(freed);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_store_gc() {
+        let stderr = "finding garbage collector roots...\n\
+                      deleting '/nix/store/aaa-nixos.qcow2'\n\
+                      2 store paths deleted, 1.50 GiB freed\n";
+        assert_eq!(parse_store_gc(stderr), Some((2, 1610612736)));
+        assert_eq!(
+            parse_store_gc("0 store paths deleted, 0.00 MiB freed"),
+            Some((0, 0))
+        );
+        assert_eq!(parse_store_gc("error: something"), None);
+    }
+
+    #[test]
+    fn test_list_checkouts() {
+        let base = std::env::temp_dir().join(format!("proxnix-checkouts-{}", std::process::id()));
+        std::fs::create_dir_all(base.join("prod").join("abc")).unwrap();
+        std::fs::create_dir_all(base.join("staging").join("abc")).unwrap();
+        std::fs::create_dir_all(base.join("def")).unwrap();
+        std::fs::write(base.join("def").join(".git"), "gitdir: /mirror").unwrap();
+        let mut checkouts: Vec<(Option<String>, String)> = list_checkouts(&base)
+            .into_iter()
+            .map(|(environment, commit, _)| (environment, commit))
+            .collect();
+        checkouts.sort();
+        assert_eq!(
+            checkouts,
+            vec![
+                (None, "def".to_string()),
+                (Some("prod".to_string()), "abc".to_string()),
+                (Some("staging".to_string()), "abc".to_string()),
+            ]
+        );
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_out_link_hashes() {
+        let base = std::env::temp_dir().join(format!("proxnix-gc-{}", std::process::id()));
+        std::fs::create_dir_all(base.join("web")).unwrap();
+        std::fs::create_dir_all(base.join("empty")).unwrap();
+        std::os::unix::fs::symlink(
+            "/nix/store/abc123-nixos-disk-image",
+            base.join("web").join("result"),
+        )
+        .unwrap();
+        assert_eq!(out_link_hashes(&base), vec!["abc123".to_string()]);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
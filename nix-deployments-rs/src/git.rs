@@ -1,61 +1,402 @@
+use crate::config;
+use crate::credentials::{escape, remote_callbacks, repo_key};
 use crate::types::{AppError, Result};
-use git2::{FetchOptions, Oid, RemoteCallbacks, Repository, build::RepoBuilder};
-use std::path::Path;
+use git2::build::CheckoutBuilder;
+use git2::{
+    FetchOptions, Oid, Repository, SubmoduleUpdateOptions, WorktreeAddOptions, WorktreePruneOptions,
+};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
 use tracing::info;
 
-const SSH_KEY_CANDIDATES: &[&str] = &[
-    "/root/.ssh/id_ed25519",
-    "/root/.ssh/id_rsa",
-    "/root/.ssh/id_ecdsa",
+// Upstream branches go under refs/remotes so they never clash with the local
+// branches that back the per-commit worktrees
+const FETCH_REFSPECS: &[&str] = &[
+    "+refs/heads/*:refs/remotes/origin/*",
+    "+refs/tags/*:refs/tags/*",
 ];
 
-fn find_ssh_key() -> Option<&'static str> {
-    SSH_KEY_CANDIDATES.iter().copied().find(|p| Path::new(p).exists())
-}
-
-pub fn git_clone(repo_url: &str, dest_path: &str) -> Result<Repository> {
-    info!("Cloning {} to {}", repo_url, dest_path);
-    let key_path = find_ssh_key()
-        .ok_or_else(|| AppError::GitError("No SSH private key found in /root/.ssh/".to_string()))?;
-    info!("Using SSH key: {}", key_path);
-    let mut callbacks = RemoteCallbacks::new();
-    callbacks.credentials(move |_url, username, _allowed| {
-        git2::Cred::ssh_key(
-            username.unwrap_or("git"),
-            None,
-            Path::new(key_path),
-            None,
-        )
-    });
+fn fetch_options<'a>(repo_url: &str) -> FetchOptions<'a> {
     let mut fetch_opts = FetchOptions::new();
-    fetch_opts.remote_callbacks(callbacks);
-    let mut builder = RepoBuilder::new();
-    builder.fetch_options(fetch_opts);
-    let repo = builder
-        .clone(repo_url, Path::new(dest_path))
-        .map_err(|e| AppError::GitError(e.to_string()))?;
-    info!("Clone complete: {}", dest_path);
+    fetch_opts.remote_callbacks(remote_callbacks(repo_url));
+    fetch_opts
+}
+
+// One bare mirror per repo URL, e.g. git@github.com:org/infra.git -> github.com_org_infra.git
+pub fn mirror_path(mirror_dir: &str, repo_url: &str) -> PathBuf {
+    Path::new(mirror_dir).join(format!("{}.git", repo_key(repo_url)))
+}
+
+static MIRROR_LOCKS: OnceLock<Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>> = OnceLock::new();
+
+// Environments of the same repo share its mirror. Fetches, worktree adds and prunes of one
+// mirror take turns, whichever environment or poll they are for.
+fn mirror_lock(path: &Path) -> Arc<Mutex<()>> {
+    MIRROR_LOCKS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .entry(path.to_path_buf())
+        .or_default()
+        .clone()
+}
+
+// Runs f on the repo's mirror, creating it first if needed, while holding the mirror's lock
+pub fn with_mirror<T>(repo_url: &str, f: impl FnOnce(&Repository) -> Result<T>) -> Result<T> {
+    let lock = mirror_lock(&mirror_path(&config::get().git.mirror_dir, repo_url));
+    let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+    f(&git_mirror(repo_url)?)
+}
+
+// Each environment checks out into a directory of its own, so two environments deploying the
+// same commit never replace each other's checkout or out-links
+pub fn checkout_path(environment: &str, commit_hash: &str) -> String {
+    Path::new(&config::get().git.checkout_dir)
+        .join(environment)
+        .join(commit_hash)
+        .to_string_lossy()
+        .to_string()
+}
+
+// Worktree names are unique per mirror, which environments of one repo share
+fn worktree_name(environment: &str, oid: Oid) -> String {
+    format!("{}-{}", escape(environment), oid)
+}
+
+fn git_mirror(repo_url: &str) -> Result<Repository> {
+    let path = mirror_path(&config::get().git.mirror_dir, repo_url);
+    if path.exists() {
+        let repo = Repository::open_bare(&path).map_err(|e| AppError::GitError(e.to_string()))?;
+        // The same repo may now be configured over another transport
+        if repo.find_remote("origin")?.url() != Some(repo_url) {
+            info!("Pointing mirror {} at {}", path.display(), repo_url);
+            repo.remote_set_url("origin", repo_url)?;
+        }
+        return Ok(repo);
+    }
+    info!("Creating mirror of {} at {}", repo_url, path.display());
+    std::fs::create_dir_all(&path)?;
+    let repo = Repository::init_bare(&path)?;
+    repo.remote_with_fetch("origin", repo_url, FETCH_REFSPECS[0])?;
+    repo.remote_add_fetch("origin", FETCH_REFSPECS[1])?;
+    Ok(repo)
+}
+
+// Updates every branch and tag of the mirror from origin
+pub fn git_fetch(mirror: &Repository) -> Result<()> {
+    let mut remote = mirror.find_remote("origin")?;
+    let url = remote.url().unwrap_or_default().to_string();
+    info!("Fetching {} into mirror", url);
+    remote
+        .fetch(FETCH_REFSPECS, Some(&mut fetch_options(&url)), None)
+        .map_err(|e| AppError::GitError(e.to_string()))
+}
+
+// Commit a fetched branch or tag points at. Full ref names work too.
+pub fn resolve_ref(mirror: &Repository, git_ref: &str) -> Result<Oid> {
+    let candidates = [
+        format!("refs/remotes/origin/{}", git_ref),
+        format!("refs/tags/{}", git_ref),
+        git_ref.to_string(),
+    ];
+    candidates
+        .iter()
+        .find_map(|name| mirror.find_reference(name).ok())
+        .ok_or_else(|| AppError::GitError(format!("Ref {} not found in mirror", git_ref)))?
+        .peel_to_commit()
+        .map(|commit| commit.id())
+        .map_err(|e| AppError::GitError(e.to_string()))
+}
+
+// Fetches only when the commit is not in the mirror yet. If the branches don't contain it
+// (force push, unusual ref) the commit itself is asked for as a last resort.
+pub fn git_fetch_commit(mirror: &Repository, oid: Oid) -> Result<()> {
+    if mirror.find_commit(oid).is_ok() {
+        info!("Commit {} already in mirror", oid);
+        return Ok(());
+    }
+    git_fetch(mirror)?;
+    if mirror.find_commit(oid).is_ok() {
+        return Ok(());
+    }
+    let mut remote = mirror.find_remote("origin")?;
+    let url = remote.url().unwrap_or_default().to_string();
+    info!("Commit {} not on any branch, fetching it directly", oid);
+    let _ = remote.fetch(&[oid.to_string()], Some(&mut fetch_options(&url)), None);
+    mirror.find_commit(oid).map(|_| ()).map_err(|_| {
+        AppError::GitError(format!(
+            "Commit {} not found in {} after fetching",
+            oid, url
+        ))
+    })
+}
+
+fn add_worktree(mirror: &Repository, oid: Oid, name: &str, dest_path: &str) -> Result<Repository> {
+    // A leftover entry, e.g. its directory was cleaned out of /tmp, blocks adding it again
+    if let Ok(worktree) = mirror.find_worktree(name) {
+        worktree.prune(Some(
+            WorktreePruneOptions::new()
+                .valid(true)
+                .locked(true)
+                .working_tree(true),
+        ))?;
+    }
+    let dest = Path::new(dest_path);
+    if dest.exists() {
+        std::fs::remove_dir_all(dest)?;
+    }
+    if let Some(parent) = dest.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    info!("Adding worktree for {} at {}", oid, dest_path);
+    let commit = mirror.find_commit(oid)?;
+    let branch = mirror.branch(&format!("proxnix/{}", name), &commit, true)?;
+    let mut opts = WorktreeAddOptions::new();
+    opts.reference(Some(branch.get()));
+    let worktree = mirror.worktree(name, dest, Some(&opts))?;
+    let repo = Repository::open_from_worktree(&worktree)?;
+    repo.set_head_detached(oid)?;
+    repo.checkout_head(Some(CheckoutBuilder::new().force()))?;
+    update_submodules(&repo)?;
     Ok(repo)
 }
 
-pub fn git_checkout(repo: &Repository, commit_hash: &str) -> Result<()> {
-    info!("Checking out commit {}", commit_hash);
-    let commit_oid = Oid::from_str(commit_hash)?;
-    let _commit = repo.find_commit(commit_oid)?;
-    repo.set_head_detached(commit_oid)?;
-    repo.checkout_head(None)?;
-    info!("Checkout complete");
+// Checks out every submodule at the commit the parent records, recursively. Each is fetched
+// with the credentials configured for its own URL.
+fn update_submodules(repo: &Repository) -> Result<()> {
+    for mut submodule in repo.submodules()? {
+        let name = submodule.name().unwrap_or_default().to_string();
+        let url = submodule.url().unwrap_or_default().to_string();
+        info!("Updating submodule {} from {}", name, url);
+        let mut opts = SubmoduleUpdateOptions::new();
+        opts.fetch(fetch_options(&url));
+        submodule
+            .update(true, Some(&mut opts))
+            .map_err(|e| AppError::GitError(format!("Submodule {}: {}", name, e)))?;
+        update_submodules(&submodule.open()?)?;
+    }
     Ok(())
 }
 
-pub fn git_ensure_commit(repo_url: &str, dest_path: &str, commit_hash: &str) -> Result<Repository> {
-    let repo = if Path::new(dest_path).exists() {
-        info!("Repo already exists at {}, opening", dest_path);
-        Repository::open(dest_path).map_err(|e| AppError::GitError(e.to_string()))?
-    } else {
-        git_clone(repo_url, dest_path)?
+// Deletes a per-commit checkout and its worktree entry and branch in the mirror.
+// Anything that is not a worktree (an old full clone, a broken directory) is just deleted.
+pub fn git_remove_checkout(path: &Path) -> Result<()> {
+    let Some(mirror) = Repository::open(path)
+        .ok()
+        .and_then(|repo| Repository::open_bare(repo.commondir()).ok())
+    else {
+        std::fs::remove_dir_all(path)?;
+        return Ok(());
     };
-    git_checkout(&repo, commit_hash)?;
+    let lock = mirror_lock(mirror.path());
+    let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+    // Found by path, checkouts from before environments had their own are named differently
+    let name = mirror.worktrees()?.iter().flatten().find_map(|name| {
+        let worktree = mirror.find_worktree(name).ok()?;
+        (worktree.path() == path).then(|| name.to_string())
+    });
+    std::fs::remove_dir_all(path)?;
+    let Some(name) = name else {
+        return Ok(());
+    };
+    if let Ok(worktree) = mirror.find_worktree(&name) {
+        worktree.prune(Some(
+            WorktreePruneOptions::new().valid(true).working_tree(true),
+        ))?;
+    }
+    if let Ok(mut branch) =
+        mirror.find_branch(&format!("proxnix/{}", name), git2::BranchType::Local)
+    {
+        branch.delete()?;
+    }
+    Ok(())
+}
+
+// Decides whether a commit may be checked out, given the repo it is in. Runs before any file
+// of the commit is written or any of its submodules fetched.
+pub type Verify<'a> = &'a dyn Fn(&Repository) -> Result<()>;
 
+// Makes checkout_path(environment, commit_hash) a checkout of the commit. An existing
+// checkout already at the commit is reused, anything else is replaced by a fresh worktree of
+// the repo's mirror.
+pub fn git_ensure_commit(
+    repo_url: &str,
+    environment: &str,
+    commit_hash: &str,
+    verify: Verify,
+) -> Result<Repository> {
+    let oid = Oid::from_str(commit_hash)?;
+    let dest_path = checkout_path(environment, commit_hash);
+    if let Ok(repo) = Repository::open(&dest_path)
+        && repo.head().ok().and_then(|head| head.target()) == Some(oid)
+    {
+        verify(&repo)?;
+        info!("Reusing checkout of {} at {}", commit_hash, dest_path);
+        return Ok(repo);
+    }
+    let repo = with_mirror(repo_url, |mirror| {
+        git_fetch_commit(mirror, oid)?;
+        verify(mirror)?;
+        add_worktree(mirror, oid, &worktree_name(environment, oid), &dest_path)
+    })?;
+    info!("Checkout complete: {}", dest_path);
     Ok(repo)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mirror_path() {
+        assert_eq!(
+            mirror_path("/m", "git@github.com:org/infra.git"),
+            PathBuf::from("/m/github.com_org_infra.git")
+        );
+        assert_eq!(
+            mirror_path("/m", "https://gitea.example.com/org/infra"),
+            PathBuf::from("/m/gitea.example.com_org_infra.git")
+        );
+    }
+
+    fn commit_file(repo: &Repository, contents: &str) -> Oid {
+        let workdir = repo.workdir().unwrap();
+        std::fs::write(workdir.join("flake.nix"), contents).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("flake.nix")).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = git2::Signature::now("test", "test@example.com").unwrap();
+        let parent = repo.head().ok().and_then(|h| h.peel_to_commit().ok());
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+        repo.commit(Some("HEAD"), &sig, &sig, contents, &tree, &parents)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_worktree_from_mirror() {
+        let base = std::env::temp_dir().join(format!("proxnix-git-{}", std::process::id()));
+        let upstream_path = base.join("upstream");
+        let upstream = Repository::init(&upstream_path).unwrap();
+        let first = commit_file(&upstream, "first");
+        let url = upstream_path.to_str().unwrap();
+
+        let mirror = Repository::init_bare(base.join("mirror.git")).unwrap();
+        mirror
+            .remote_with_fetch("origin", url, FETCH_REFSPECS[0])
+            .unwrap();
+        git_fetch_commit(&mirror, first).unwrap();
+
+        let dest = base.join("checkouts").join(first.to_string());
+        let dest = dest.to_str().unwrap();
+        add_worktree(&mirror, first, &first.to_string(), dest).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(Path::new(dest).join("flake.nix")).unwrap(),
+            "first"
+        );
+
+        // Another environment at the same commit gets a worktree of its own
+        let staging = base
+            .join("checkouts")
+            .join("staging")
+            .join(first.to_string());
+        add_worktree(
+            &mirror,
+            first,
+            &worktree_name("staging", first),
+            staging.to_str().unwrap(),
+        )
+        .unwrap();
+        git_remove_checkout(&staging).unwrap();
+        assert!(!staging.exists());
+        assert!(
+            mirror
+                .find_worktree(&worktree_name("staging", first))
+                .is_err()
+        );
+        assert!(Path::new(dest).join("flake.nix").exists());
+        assert!(mirror.find_worktree(&first.to_string()).is_ok());
+
+        // A commit pushed after the mirror was created is fetched incrementally
+        let second = commit_file(&upstream, "second");
+        assert!(mirror.find_commit(second).is_err());
+        git_fetch_commit(&mirror, second).unwrap();
+        let dest2 = base.join("checkouts").join(second.to_string());
+        add_worktree(
+            &mirror,
+            second,
+            &second.to_string(),
+            dest2.to_str().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dest2.join("flake.nix")).unwrap(),
+            "second"
+        );
+
+        // Re-adding after the checkout directory vanished replaces the stale worktree
+        std::fs::remove_dir_all(dest).unwrap();
+        add_worktree(&mirror, first, &first.to_string(), dest).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(Path::new(dest).join("flake.nix")).unwrap(),
+            "first"
+        );
+
+        git_remove_checkout(&dest2).unwrap();
+        assert!(!dest2.exists());
+        assert!(mirror.find_worktree(&second.to_string()).is_err());
+        assert!(
+            mirror
+                .find_branch(&format!("proxnix/{}", second), git2::BranchType::Local)
+                .is_err()
+        );
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_worktree_with_submodule() {
+        let base = std::env::temp_dir().join(format!("proxnix-submodule-{}", std::process::id()));
+        let lib_path = base.join("lib");
+        let lib = Repository::init(&lib_path).unwrap();
+        commit_file(&lib, "lib");
+
+        let upstream_path = base.join("upstream");
+        let upstream = Repository::init(&upstream_path).unwrap();
+        commit_file(&upstream, "root");
+        let mut submodule = upstream
+            .submodule(lib_path.to_str().unwrap(), Path::new("vendor/lib"), true)
+            .unwrap();
+        submodule.clone(None).unwrap();
+        submodule.add_finalize().unwrap();
+        let mut index = upstream.index().unwrap();
+        let tree = upstream.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = git2::Signature::now("test", "test@example.com").unwrap();
+        let parent = upstream.head().unwrap().peel_to_commit().unwrap();
+        let with_submodule = upstream
+            .commit(Some("HEAD"), &sig, &sig, "add lib", &tree, &[&parent])
+            .unwrap();
+
+        let mirror = Repository::init_bare(base.join("mirror.git")).unwrap();
+        mirror
+            .remote_with_fetch("origin", upstream_path.to_str().unwrap(), FETCH_REFSPECS[0])
+            .unwrap();
+        git_fetch_commit(&mirror, with_submodule).unwrap();
+        let dest = base.join("checkouts").join(with_submodule.to_string());
+        add_worktree(
+            &mirror,
+            with_submodule,
+            &with_submodule.to_string(),
+            dest.to_str().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dest.join("vendor/lib/flake.nix")).unwrap(),
+            "lib"
+        );
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,210 @@
+use crate::config::CrashLoopPolicy;
+use std::collections::HashMap;
+use std::io::Read;
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+const CONSOLE_TAIL_LINES: usize = 50;
+
+#[derive(Debug, Clone)]
+struct RestartState {
+    name: String,
+    starts: Vec<Instant>,
+    next_attempt: Option<Instant>,
+    crash_looping: bool,
+    last_console: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct VMHealth {
+    pub vm_id: u32,
+    pub restarts_in_window: usize,
+    pub crash_looping: bool,
+    pub next_attempt_in_secs: Option<u64>,
+    pub last_console: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StartDecision {
+    Start,
+    Backoff(Duration),
+}
+
+// Tracks how often the periodic reconciler has had to start each VM so one that dies
+// on boot is retried with exponential backoff instead of every tick forever
+#[derive(Debug, Default)]
+pub struct RestartTracker {
+    vms: HashMap<u32, RestartState>,
+}
+
+impl RestartTracker {
+    pub fn decide(&self, vm_id: u32, now: Instant) -> StartDecision {
+        match self.vms.get(&vm_id).and_then(|s| s.next_attempt) {
+            Some(next) if next > now => StartDecision::Backoff(next - now),
+            _ => StartDecision::Start,
+        }
+    }
+
+    // Records a start attempt, returns true if this attempt tipped the VM into crash looping
+    pub fn record_start(
+        &mut self,
+        vm_id: u32,
+        name: &str,
+        now: Instant,
+        policy: &CrashLoopPolicy,
+    ) -> bool {
+        let state = self.vms.entry(vm_id).or_insert_with(|| RestartState {
+            name: name.to_string(),
+            starts: Vec::new(),
+            next_attempt: None,
+            crash_looping: false,
+            last_console: None,
+        });
+        let window = Duration::from_secs(policy.window_secs);
+        state.starts.retain(|t| now.duration_since(*t) < window);
+        state.starts.push(now);
+
+        let exponent = state.starts.len().saturating_sub(1) as u32;
+        let backoff = Duration::from_secs(policy.base_backoff_secs)
+            .saturating_mul(2u32.saturating_pow(exponent))
+            .min(Duration::from_secs(policy.max_backoff_secs));
+        state.next_attempt = Some(now + backoff);
+
+        let was_looping = state.crash_looping;
+        state.crash_looping = state.starts.len() >= policy.max_starts as usize;
+        state.crash_looping && !was_looping
+    }
+
+    // A VM seen running for stable_secs since its last start is considered recovered
+    pub fn record_running(&mut self, vm_id: u32, now: Instant, policy: &CrashLoopPolicy) -> bool {
+        let stable = Duration::from_secs(policy.stable_secs);
+        let recovered = self
+            .vms
+            .get(&vm_id)
+            .and_then(|s| s.starts.last())
+            .map(|last| now.duration_since(*last) >= stable)
+            .unwrap_or(false);
+        if recovered {
+            self.vms.remove(&vm_id);
+        }
+        recovered
+    }
+
+    pub fn set_console(&mut self, vm_id: u32, console: String) {
+        if let Some(state) = self.vms.get_mut(&vm_id) {
+            state.last_console = Some(console);
+        }
+    }
+
+    pub fn is_crash_looping(&self, vm_id: u32) -> bool {
+        self.vms.get(&vm_id).map(|s| s.crash_looping).unwrap_or(false)
+    }
+
+    pub fn snapshot(&self, now: Instant) -> HashMap<String, VMHealth> {
+        self.vms
+            .iter()
+            .map(|(vm_id, state)| {
+                (
+                    state.name.clone(),
+                    VMHealth {
+                        vm_id: *vm_id,
+                        restarts_in_window: state.starts.len(),
+                        crash_looping: state.crash_looping,
+                        next_attempt_in_secs: state
+                            .next_attempt
+                            .map(|next| next.saturating_duration_since(now).as_secs()),
+                        last_console: state.last_console.clone(),
+                    },
+                )
+            })
+            .collect()
+    }
+}
+
+// Reads whatever the VM writes to its serial0 socket for up to duration and keeps the tail.
+// The socket only carries live output, so this is called right after a start.
+pub fn capture_serial_console(vm_id: u32, duration: Duration) -> std::io::Result<String> {
+    let mut stream = UnixStream::connect(format!("/var/run/qemu-server/{}.serial0", vm_id))?;
+    stream.set_read_timeout(Some(Duration::from_millis(500)))?;
+    let deadline = Instant::now() + duration;
+    let mut captured = Vec::new();
+    let mut buf = [0u8; 4096];
+    while Instant::now() < deadline {
+        match stream.read(&mut buf) {
+            // The VM went away, which is what we are waiting to see
+            Ok(0) => break,
+            Ok(n) => captured.extend_from_slice(&buf[..n]),
+            Err(e)
+                if e.kind() == std::io::ErrorKind::WouldBlock
+                    || e.kind() == std::io::ErrorKind::TimedOut => {}
+            Err(_) => break,
+        }
+    }
+    let text = String::from_utf8_lossy(&captured);
+    let lines: Vec<&str> = text.lines().collect();
+    Ok(lines[lines.len().saturating_sub(CONSOLE_TAIL_LINES)..].join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> CrashLoopPolicy {
+        CrashLoopPolicy {
+            max_starts: 3,
+            window_secs: 600,
+            base_backoff_secs: 10,
+            max_backoff_secs: 60,
+            stable_secs: 300,
+            console_capture_secs: 30,
+        }
+    }
+
+    #[test]
+    fn test_backoff_grows_until_crash_loop() {
+        let policy = policy();
+        let mut tracker = RestartTracker::default();
+        let start = Instant::now();
+
+        assert_eq!(tracker.decide(100, start), StartDecision::Start);
+        assert!(!tracker.record_start(100, "web", start, &policy));
+        assert_eq!(
+            tracker.decide(100, start + Duration::from_secs(5)),
+            StartDecision::Backoff(Duration::from_secs(5))
+        );
+
+        let second = start + Duration::from_secs(10);
+        assert_eq!(tracker.decide(100, second), StartDecision::Start);
+        assert!(!tracker.record_start(100, "web", second, &policy));
+        assert_eq!(
+            tracker.decide(100, second),
+            StartDecision::Backoff(Duration::from_secs(20))
+        );
+
+        let third = second + Duration::from_secs(20);
+        assert!(tracker.record_start(100, "web", third, &policy));
+        assert!(tracker.is_crash_looping(100));
+        assert_eq!(
+            tracker.decide(100, third),
+            StartDecision::Backoff(Duration::from_secs(40))
+        );
+        let fourth = third + Duration::from_secs(40);
+        assert!(!tracker.record_start(100, "web", fourth, &policy));
+        // Capped at max_backoff_secs
+        assert_eq!(
+            tracker.decide(100, fourth),
+            StartDecision::Backoff(Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn test_stable_vm_resets() {
+        let policy = policy();
+        let mut tracker = RestartTracker::default();
+        let start = Instant::now();
+        tracker.record_start(100, "web", start, &policy);
+        assert!(!tracker.record_running(100, start + Duration::from_secs(60), &policy));
+        assert!(tracker.record_running(100, start + Duration::from_secs(300), &policy));
+        assert!(tracker.snapshot(start).is_empty());
+    }
+}
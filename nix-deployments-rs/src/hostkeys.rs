@@ -0,0 +1,294 @@
+use base64::Engine;
+use base64::alphabet::STANDARD;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::path::Path;
+use tracing::warn;
+
+// Encodes unpadded, the way OpenSSH prints SHA256 fingerprints, and decodes the padded keys
+// and salts in known_hosts
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &STANDARD,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+pub fn base64_encode(bytes: &[u8]) -> String {
+    BASE64.encode(bytes)
+}
+
+pub fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    BASE64.decode(text).ok()
+}
+
+pub fn fingerprint(sha256: &[u8]) -> String {
+    format!("SHA256:{}", base64_encode(sha256))
+}
+
+// What OpenSSH hashes known_hosts hostnames with, the salt is the key
+fn hashed_host_matches(salt: &[u8], hash: &[u8], host: &str) -> bool {
+    let Ok(mut mac) = Hmac::<Sha1>::new_from_slice(salt) else {
+        return false;
+    };
+    mac.update(host.as_bytes());
+    mac.verify_slice(hash).is_ok()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KnownHost {
+    pub patterns: Vec<String>,
+    // Salt and HMAC-SHA1 of a hashed entry (|1|salt|hash), patterns is empty then
+    pub hashed: Option<(Vec<u8>, Vec<u8>)>,
+    pub key_type: String,
+    pub key: Vec<u8>,
+    pub revoked: bool,
+}
+
+// Patterns are compared literally, so an entry only written for a wildcard or negated pattern
+// never matches and the host is treated as unknown
+fn warn_unsupported(patterns: &[String]) {
+    for pattern in patterns {
+        if pattern.contains(['*', '?', '!']) {
+            warn!(
+                "known_hosts pattern {} uses wildcards or negation, which are not supported, list the host by name",
+                pattern
+            );
+        }
+    }
+}
+
+// Plain and hashed (HashKnownHosts yes) entries. @cert-authority lines and unknown hash
+// formats are skipped.
+pub fn parse_known_hosts(contents: &str) -> Vec<KnownHost> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                return None;
+            }
+            let mut fields = line.split_whitespace();
+            let mut hosts = fields.next()?;
+            let mut revoked = false;
+            if hosts.starts_with('@') {
+                if hosts != "@revoked" {
+                    return None;
+                }
+                revoked = true;
+                hosts = fields.next()?;
+            }
+            let (patterns, hashed) = match hosts.strip_prefix("|1|") {
+                Some(hashed) => {
+                    let (salt, hash) = hashed.split_once('|')?;
+                    (
+                        Vec::new(),
+                        Some((base64_decode(salt)?, base64_decode(hash)?)),
+                    )
+                }
+                None if hosts.starts_with('|') => return None,
+                None => {
+                    let patterns: Vec<String> = hosts.split(',').map(|p| p.to_string()).collect();
+                    warn_unsupported(&patterns);
+                    (patterns, None)
+                }
+            };
+            Some(KnownHost {
+                patterns,
+                hashed,
+                key_type: fields.next()?.to_string(),
+                key: base64_decode(fields.next()?)?,
+                revoked,
+            })
+        })
+        .collect()
+}
+
+fn host_matches(entry: &KnownHost, host: &str, port: u16) -> bool {
+    let wanted = if port == 22 {
+        host.to_string()
+    } else {
+        format!("[{}]:{}", host, port)
+    };
+    match &entry.hashed {
+        Some((salt, hash)) => hashed_host_matches(salt, hash, &wanted),
+        None => entry.patterns.contains(&wanted),
+    }
+}
+
+// Checks the key a server presented against known_hosts. Unknown hosts are rejected, there is
+// nobody to ask whether to trust them.
+pub fn verify_known_hosts(
+    known_hosts: &[KnownHost],
+    host: &str,
+    port: u16,
+    key_type: &str,
+    key: &[u8],
+) -> Result<(), String> {
+    let entries: Vec<&KnownHost> = known_hosts
+        .iter()
+        .filter(|entry| host_matches(entry, host, port))
+        .collect();
+    if entries.iter().any(|e| e.revoked && e.key == key) {
+        return Err(format!("Host key for {} is marked as revoked", host));
+    }
+    if entries.iter().any(|e| !e.revoked && e.key == key) {
+        return Ok(());
+    }
+    if entries.iter().any(|e| !e.revoked && e.key_type == key_type) {
+        return Err(format!(
+            "HOST KEY MISMATCH for {}: the {} key presented does not match known_hosts, refusing to fetch",
+            host, key_type
+        ));
+    }
+    Err(format!(
+        "No {} host key for {} in known_hosts, add it with `ssh-keyscan -p {} -t {} {}`",
+        key_type, host, port, key_type, host
+    ))
+}
+
+pub fn verify_pinned(pins: &[String], host: &str, sha256: &[u8]) -> Result<(), String> {
+    let presented = fingerprint(sha256);
+    if pins.contains(&presented) {
+        return Ok(());
+    }
+    Err(format!(
+        "HOST KEY MISMATCH for {}: presented {} is not one of the pinned fingerprints",
+        host, presented
+    ))
+}
+
+pub fn load_known_hosts(path: &str) -> Result<Vec<KnownHost>, String> {
+    if !Path::new(path).exists() {
+        return Err(format!(
+            "known_hosts file {} does not exist, host keys cannot be verified",
+            path
+        ));
+    }
+    std::fs::read_to_string(path)
+        .map(|contents| parse_known_hosts(&contents))
+        .map_err(|e| format!("Failed to read {}: {}", path, e))
+}
+
+// Host and port the SSH transport connects to, for scp-style and ssh:// URLs
+pub fn ssh_host_port(repo_url: &str) -> Option<(String, u16)> {
+    if let Some(rest) = repo_url.strip_prefix("ssh://") {
+        let authority = rest.split('/').next()?;
+        let authority = authority
+            .rsplit_once('@')
+            .map(|(_, h)| h)
+            .unwrap_or(authority);
+        return Some(match authority.rsplit_once(':') {
+            Some((host, port)) => (host.to_string(), port.parse().ok()?),
+            None => (authority.to_string(), 22),
+        });
+    }
+    let (authority, _) = repo_url.split_once(':')?;
+    let host = authority
+        .rsplit_once('@')
+        .map(|(_, h)| h)
+        .unwrap_or(authority);
+    Some((host.to_string(), 22))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base64_round_trip() {
+        for input in [&b""[..], b"f", b"fo", b"foo", b"foob", b"fooba", b"foobar"] {
+            assert_eq!(base64_decode(&base64_encode(input)).unwrap(), input);
+        }
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64_encode(b"fooba"), "Zm9vYmE");
+        assert_eq!(base64_decode("Zm9vYg==").unwrap(), b"foob");
+    }
+
+    #[test]
+    fn test_verify_known_hosts() {
+        let good = base64_encode(b"good-key");
+        let old = base64_encode(b"old-key");
+        let known = parse_known_hosts(&format!(
+            "# comment\n\
+             github.com,140.82.121.4 ssh-ed25519 {good}\n\
+             [gitea.example.com]:2222 ssh-ed25519 {good}\n\
+             @revoked github.com ssh-rsa {old}\n\
+             |2|unknown|format ssh-ed25519 {good}\n\
+             *.example.org ssh-ed25519 {good}\n"
+        ));
+        assert_eq!(known.len(), 4);
+
+        assert!(verify_known_hosts(&known, "github.com", 22, "ssh-ed25519", b"good-key").is_ok());
+        assert!(
+            verify_known_hosts(
+                &known,
+                "gitea.example.com",
+                2222,
+                "ssh-ed25519",
+                b"good-key"
+            )
+            .is_ok()
+        );
+        // Right host, wrong port
+        assert!(
+            verify_known_hosts(&known, "gitea.example.com", 22, "ssh-ed25519", b"good-key")
+                .is_err()
+        );
+        let mismatch =
+            verify_known_hosts(&known, "github.com", 22, "ssh-ed25519", b"evil-key").unwrap_err();
+        assert!(mismatch.contains("MISMATCH"));
+        let revoked =
+            verify_known_hosts(&known, "github.com", 22, "ssh-rsa", b"old-key").unwrap_err();
+        assert!(revoked.contains("revoked"));
+        assert!(verify_known_hosts(&known, "other.com", 22, "ssh-ed25519", b"good-key").is_err());
+        // Wildcards are not expanded
+        assert!(
+            verify_known_hosts(&known, "git.example.org", 22, "ssh-ed25519", b"good-key").is_err()
+        );
+    }
+
+    #[test]
+    fn test_hashed_known_hosts() {
+        let good = base64_encode(b"good-key");
+        let known = parse_known_hosts(&format!(
+            "|1|MDEyMzQ1Njc4OWFiY2RlZmdoaWo=|anUhMiNmCXr96buiAF9of6zM1wM= ssh-ed25519 {good}\n\
+             |1|MDEyMzQ1Njc4OWFiY2RlZmdoaWo=|WsnGq9SJvfX/NplG0p7cwO5DlVQ= ssh-ed25519 {good}\n"
+        ));
+        assert_eq!(known.len(), 2);
+        assert!(verify_known_hosts(&known, "github.com", 22, "ssh-ed25519", b"good-key").is_ok());
+        assert!(
+            verify_known_hosts(
+                &known,
+                "gitea.example.com",
+                2222,
+                "ssh-ed25519",
+                b"good-key"
+            )
+            .is_ok()
+        );
+        assert!(
+            verify_known_hosts(&known, "github.com", 22, "ssh-ed25519", b"evil-key")
+                .unwrap_err()
+                .contains("MISMATCH")
+        );
+        assert!(verify_known_hosts(&known, "gitlab.com", 22, "ssh-ed25519", b"good-key").is_err());
+    }
+
+    #[test]
+    fn test_ssh_host_port() {
+        assert_eq!(
+            ssh_host_port("git@github.com:org/infra.git"),
+            Some(("github.com".to_string(), 22))
+        );
+        assert_eq!(
+            ssh_host_port("ssh://git@gitea.example.com:2222/org/infra.git"),
+            Some(("gitea.example.com".to_string(), 2222))
+        );
+        assert_eq!(
+            ssh_host_port("ssh://gitea.example.com/org/infra.git"),
+            Some(("gitea.example.com".to_string(), 22))
+        );
+    }
+}
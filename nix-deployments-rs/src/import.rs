@@ -0,0 +1,316 @@
+use crate::alloc::{Allocations, alloc_tag};
+use crate::build::image_hash;
+use crate::config::{self, RepoConfig};
+use crate::deployments;
+use crate::git::{checkout_path, git_ensure_commit};
+use crate::nix::{eval_image_paths, eval_vm_config, resolve_flake};
+use crate::qm::qm_set_tags;
+use crate::repos;
+use crate::signing::trusted;
+use crate::state::{
+    diff_state, from_qm_list, is_system_tag, parse_qm_config, parse_qm_list, parse_vm_config,
+    qm_config, qm_list, with_config,
+};
+use crate::types::{AppError, DeployedState, DesiredState, ImportReport, Result};
+use std::collections::HashMap;
+use tracing::info;
+
+// Takes over a VM proxnix did not create, e.g. one made by hand or by Terraform, without
+// recreating it. The VM gets the owner tag of the environment, after which it is diffed and
+// managed like any VM the environment created.
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+pub struct ImportRequest {
+    pub vm_id: u32,
+    // The VMConfig in the repo that describes this VM
+    pub name: String,
+    // Needed when more than one environment is configured
+    #[serde(default)]
+    pub environment: Option<String>,
+    // Commit to read the VMConfig from, defaults to the environment's current deployment
+    #[serde(default)]
+    pub commit: Option<String>,
+    // Record the VM's disk as built from this image type, so the next deployment doesn't
+    // rebuild it. Without it the disk is treated as unknown and rebuilt.
+    #[serde(default)]
+    pub image_type: Option<String>,
+    // Without it nothing is changed, only the report is returned
+    #[serde(default)]
+    pub confirm: bool,
+}
+
+fn import_error(message: String) -> AppError {
+    AppError::ImportError(message)
+}
+
+pub fn environment_repo(environment: Option<&str>) -> Result<RepoConfig> {
+    let dir = &config::get().deployments_dir;
+    match environment {
+        Some(name) => {
+            if let Some(repo) = repos::find(name) {
+                return Ok(repo);
+            }
+            // Environments of unconfigured repos only exist through their deployments
+            deployments::load_current(&deployments::environment_dir(dir, name))?
+                .and_then(|snapshot| repos::unconfigured_if_allowed(&snapshot.repo_url))
+                .ok_or_else(|| import_error(format!("Unknown environment {}", name)))
+        }
+        None => match config::get().git.repos.as_slice() {
+            [repo] => Ok(repo.clone()),
+            [] => Err(import_error(
+                "No repos are configured, name the environment to import into".to_string(),
+            )),
+            _ => Err(import_error(
+                "Several environments are configured, name the one to import into".to_string(),
+            )),
+        },
+    }
+}
+
+// Desired state and image hashes at the commit the VM is imported against
+struct Source {
+    commit_hash: String,
+    desired: DesiredState,
+    image_hashes: HashMap<String, String>,
+}
+
+fn source(repo: &RepoConfig, commit: Option<&str>, image_type: Option<&str>) -> Result<Source> {
+    let environment = repos::environment(repo);
+    let current = deployments::load_current(&deployments::environment_dir(
+        &config::get().deployments_dir,
+        &environment,
+    ))?;
+    // The last deployment has everything already evaluated
+    if let Some(snapshot) = current
+        && commit.is_none_or(|c| c == snapshot.commit_hash)
+    {
+        return Ok(Source {
+            commit_hash: snapshot.commit_hash,
+            desired: snapshot.desired,
+            image_hashes: snapshot.image_hashes,
+        });
+    }
+    let Some(commit_hash) = commit else {
+        return Err(import_error(format!(
+            "{} has not been deployed yet, name the commit to import against",
+            environment
+        )));
+    };
+    if !deployments::valid_commit(commit_hash) {
+        return Err(import_error(format!("Invalid commit hash {}", commit_hash)));
+    }
+
+    let dest_path = checkout_path(&environment, commit_hash);
+    git_ensure_commit(&repo.url, &environment, commit_hash, &trusted(commit_hash))?;
+    let flake = resolve_flake(&dest_path, Some(repo))?;
+    let desired = parse_vm_config(&eval_vm_config(&flake)?)?;
+    let mut image_hashes = HashMap::new();
+    if let Some(image_type) = image_type {
+        let paths = eval_image_paths(image_type, &flake)?;
+        image_hashes.insert(image_type.to_string(), image_hash(&paths)?);
+    }
+    Ok(Source {
+        commit_hash: commit_hash.to_string(),
+        desired,
+        image_hashes,
+    })
+}
+
+// Works out the tags the VM would get and what the next deployment would change on it.
+// Nothing on the VM is touched.
+pub fn plan(request: &ImportRequest) -> Result<ImportReport> {
+    let repo = environment_repo(request.environment.as_deref())?;
+    let scope = repos::scope(&repo)?;
+
+    let row = parse_qm_list(&qm_list()?)?
+        .into_iter()
+        .find(|row| row.vm_id == request.vm_id)
+        .ok_or_else(|| import_error(format!("VM {} does not exist", request.vm_id)))?;
+    let live = with_config(
+        from_qm_list(row),
+        &parse_qm_config(&qm_config(request.vm_id)?)?,
+    );
+    if let Some(owner) = live
+        .tags
+        .iter()
+        .find(|tag| tag.starts_with(repos::OWNER_TAG_PREFIX))
+    {
+        return Err(import_error(format!(
+            "{} ({}) is already managed ({})",
+            live.vm_name, live.vm_id, owner
+        )));
+    }
+    if !scope.owns(live.vm_id) {
+        return Err(AppError::OwnershipError(format!(
+            "{} may not manage {} ({}): outside its vm_id_range or owned by another environment",
+            scope.environment, live.vm_name, live.vm_id
+        )));
+    }
+
+    let source = source(
+        &repo,
+        request.commit.as_deref(),
+        request.image_type.as_deref(),
+    )?;
+    let mut config = source
+        .desired
+        .vms
+        .get(&request.name)
+        .cloned()
+        .ok_or_else(|| {
+            import_error(format!(
+                "No VM named {} in the config of {} at {}",
+                request.name, scope.environment, source.commit_hash
+            ))
+        })?;
+    // A VM without a vm_id keeps the one it has, as if it had been allocated
+    if config.vm_id == 0 {
+        config.vm_id = live.vm_id;
+        config.vm_id_allocated = true;
+    }
+    if config.vm_id != live.vm_id {
+        return Err(import_error(format!(
+            "{} has vm_id {} in the config, not {}",
+            config.name, config.vm_id, live.vm_id
+        )));
+    }
+
+    let nix_hash = match &request.image_type {
+        Some(image_type) if *image_type != config.image_type => {
+            return Err(import_error(format!(
+                "{} is configured with image_type {}, not {}",
+                config.name, config.image_type, image_type
+            )));
+        }
+        Some(image_type) => Some(source.image_hashes.get(image_type).cloned().ok_or_else(
+            || {
+                import_error(format!(
+                    "No image hash for {} at {}",
+                    image_type, source.commit_hash
+                ))
+            },
+        )?),
+        None => None,
+    };
+    let mut tags = vec!["proxnix".to_string(), scope.owner_tag.clone()];
+    tags.extend(nix_hash.iter().map(|hash| format!("nix-{}", hash)));
+    tags.push(format!("commit-{}", source.commit_hash));
+    if config.vm_id_allocated {
+        tags.push(alloc_tag(&config.name));
+    }
+    tags.extend(live.tags.iter().filter(|tag| !is_system_tag(tag)).cloned());
+
+    let adopted = crate::types::DeployedVM {
+        nix_hash,
+        tags: tags.clone(),
+        ..live
+    };
+    let diff = diff_state(
+        &DeployedState {
+            vms: HashMap::from([(adopted.vm_id, adopted)]),
+        },
+        &DesiredState {
+            vms: HashMap::from([(config.name.clone(), config.clone())]),
+        },
+        &source.image_hashes,
+    );
+    let update = diff.to_update.into_iter().next();
+    Ok(ImportReport {
+        environment: scope.environment,
+        vm_id: request.vm_id,
+        name: config.name.clone(),
+        commit_hash: source.commit_hash,
+        changed_fields: update
+            .as_ref()
+            .map(|u| u.changed_fields.clone())
+            .unwrap_or_default(),
+        required_action: update.map(|u| u.required_action),
+        tags,
+        applied: false,
+    })
+}
+
+// Writing the tags is all it takes, from then on the VM is in the environment's scope
+pub fn apply(mut report: ImportReport) -> Result<ImportReport> {
+    qm_set_tags(report.vm_id, &report.tags)?;
+    if report.tags.contains(&alloc_tag(&report.name)) {
+        let mut allocations = Allocations::load(&report.environment);
+        allocations.record(&report.name, report.vm_id);
+        allocations.save(&report.environment)?;
+    }
+    info!(
+        "Imported {} ({}) into {}",
+        report.name, report.vm_id, report.environment
+    );
+    report.applied = true;
+    Ok(report)
+}
+
+pub fn import(request: &ImportRequest) -> Result<ImportReport> {
+    let report = plan(request)?;
+    if request.confirm {
+        apply(report)
+    } else {
+        Ok(report)
+    }
+}
+
+// proxnix --import <vm_id> <name> [--environment <name>] [--commit <hash>] [--image-type <type>] [--yes]
+pub fn parse_args(args: &[String]) -> std::result::Result<ImportRequest, String> {
+    let mut positional = Vec::new();
+    let mut request = ImportRequest::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| {
+            args.next()
+                .cloned()
+                .ok_or_else(|| format!("{} needs a value", flag))
+        };
+        match arg.as_str() {
+            "--environment" => request.environment = Some(value(arg)?),
+            "--commit" => request.commit = Some(value(arg)?),
+            "--image-type" => request.image_type = Some(value(arg)?),
+            "--yes" => request.confirm = true,
+            flag if flag.starts_with("--") => return Err(format!("Unknown option {}", flag)),
+            _ => positional.push(arg.clone()),
+        }
+    }
+    let [vm_id, name] = positional.as_slice() else {
+        return Err("Usage: proxnix --import <vm_id> <name> [--environment <name>] [--commit <hash>] [--image-type <type>] [--yes]".to_string());
+    };
+    request.vm_id = vm_id
+        .parse()
+        .map_err(|_| format!("Invalid VM id {}", vm_id))?;
+    request.name = name.clone();
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(raw: &str) -> Vec<String> {
+        raw.split_whitespace().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(
+            parse_args(&args(
+                "105 web --environment prod --image-type webserver --yes"
+            )),
+            Ok(ImportRequest {
+                vm_id: 105,
+                name: "web".to_string(),
+                environment: Some("prod".to_string()),
+                commit: None,
+                image_type: Some("webserver".to_string()),
+                confirm: true,
+            })
+        );
+        assert_eq!(parse_args(&args("105 web")).map(|r| r.confirm), Ok(false));
+        assert!(parse_args(&args("web 105")).is_err());
+        assert!(parse_args(&args("105")).is_err());
+        assert!(parse_args(&args("105 web --commit")).is_err());
+        assert!(parse_args(&args("105 web --force")).is_err());
+    }
+}
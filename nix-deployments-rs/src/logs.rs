@@ -0,0 +1,138 @@
+use crate::types::{Result, RunReport};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use tracing::warn;
+
+const LIVE_BUFFER: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct LogLine {
+    pub seq: u64,
+    pub stream: String,
+    pub line: String,
+}
+
+struct LogWriter {
+    file: File,
+    next_seq: u64,
+}
+
+// One log per pipeline run. Every line is appended to <log_dir>/<id>.log as
+// "[stream] line" and broadcast to anyone following the run live.
+pub struct PipelineLog {
+    pub id: String,
+    writer: Mutex<LogWriter>,
+    tx: broadcast::Sender<LogLine>,
+}
+
+impl PipelineLog {
+    pub fn create(log_dir: &str, id: &str) -> Result<Arc<Self>> {
+        std::fs::create_dir_all(log_dir)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path(log_dir, id))?;
+        let (tx, _) = broadcast::channel(LIVE_BUFFER);
+        Ok(Arc::new(Self {
+            id: id.to_string(),
+            writer: Mutex::new(LogWriter { file, next_seq: 0 }),
+            tx,
+        }))
+    }
+
+    pub fn write(&self, stream: &str, line: &str) {
+        let Ok(mut writer) = self.writer.lock() else {
+            return;
+        };
+        if let Err(e) = writeln!(writer.file, "[{}] {}", stream, line) {
+            warn!("Failed to write pipeline log {}: {}", self.id, e);
+        }
+        let seq = writer.next_seq;
+        writer.next_seq += 1;
+        // Sent while holding the lock so seq order matches file order
+        let _ = self.tx.send(LogLine {
+            seq,
+            stream: stream.to_string(),
+            line: line.to_string(),
+        });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<LogLine> {
+        self.tx.subscribe()
+    }
+}
+
+pub fn new_pipeline_id(commit_hash: &str) -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let short: String = commit_hash.chars().take(12).collect();
+    format!("{}-{}", secs, short)
+}
+
+// Ids end up in file paths, only accept what new_pipeline_id produces
+pub fn valid_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+pub fn log_path(log_dir: &str, id: &str) -> PathBuf {
+    Path::new(log_dir).join(format!("{}.log", id))
+}
+
+pub fn report_path(log_dir: &str, id: &str) -> PathBuf {
+    Path::new(log_dir).join(format!("{}.report.json", id))
+}
+
+pub fn write_report(log_dir: &str, id: &str, report: &RunReport) -> Result<()> {
+    std::fs::write(report_path(log_dir, id), serde_json::to_string_pretty(report)?)?;
+    Ok(())
+}
+
+pub fn read_report(log_dir: &str, id: &str) -> Result<RunReport> {
+    let raw = std::fs::read_to_string(report_path(log_dir, id))?;
+    Ok(serde_json::from_str(&raw)?)
+}
+
+fn parse_line(seq: u64, raw: &str) -> LogLine {
+    let raw = raw.trim_end_matches('\n');
+    let (stream, line) = raw
+        .strip_prefix('[')
+        .and_then(|rest| rest.split_once("] "))
+        .unwrap_or(("", raw));
+    LogLine {
+        seq,
+        stream: stream.to_string(),
+        line: line.to_string(),
+    }
+}
+
+// Only complete lines are returned, a line still being written is picked up from the live stream
+pub fn read_log(log_dir: &str, id: &str) -> Result<Vec<LogLine>> {
+    let raw = std::fs::read_to_string(log_path(log_dir, id))?;
+    Ok(raw
+        .split_inclusive('\n')
+        .filter(|l| l.ends_with('\n'))
+        .enumerate()
+        .map(|(seq, l)| parse_line(seq as u64, l))
+        .collect())
+}
+
+pub fn list_logs(log_dir: &str) -> Result<Vec<String>> {
+    let mut ids: Vec<String> = std::fs::read_dir(log_dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_suffix(".log"))
+                .map(|id| id.to_string())
+        })
+        .collect();
+    ids.sort();
+    Ok(ids)
+}
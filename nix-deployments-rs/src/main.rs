@@ -1,24 +1,149 @@
-use axum::{Json, Router, extract::State, http::StatusCode, routing::post};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::{
+    Json, Router,
+    extract::{ConnectInfo, Path, Query, Request, State},
+    http::{StatusCode, header},
+    middleware::{self, Next},
+    response::Response,
+    routing::{get, post},
+};
+use futures_util::stream::{self, Stream, StreamExt};
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
 use std::env;
 use std::fs;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::{RwLock, Semaphore};
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::{OwnedSemaphorePermit, RwLock, Semaphore, broadcast};
+use tokio_util::sync::CancellationToken;
 use tracing::{error, info, warn};
 
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+
+struct RunningPipeline {
+    cancel: CancellationToken,
+    commit_hash: String,
+}
+
+// A commit that arrived while its environment was busy. Only the latest one per environment
+// is kept, it supersedes anything queued before it.
+struct QueuedPipeline {
+    repo: config::RepoConfig,
+    commit_hash: String,
+    trigger: types::PipelineTrigger,
+}
+
+// Pipelines, deployment history and drift are all per environment, see repos.rs
 #[derive(Clone)]
 struct AppState {
-    semaphore: Arc<Semaphore>,
-    last_repo: Arc<RwLock<Option<(String, String)>>>,
+    // One per environment, held by its pipeline or by periodic work on its VMs
+    locks: Arc<RwLock<HashMap<String, Arc<Semaphore>>>>,
+    shutdown: CancellationToken,
+    pipelines: Arc<RwLock<HashMap<String, RunningPipeline>>>,
+    queued: Arc<RwLock<HashMap<String, QueuedPipeline>>>,
+    live_logs: Arc<RwLock<HashMap<String, Arc<logs::PipelineLog>>>>,
+    restarts: Arc<RwLock<health::RestartTracker>>,
+    deployments: Arc<RwLock<HashMap<String, types::DeploymentSnapshot>>>,
+    drift: Arc<RwLock<HashMap<String, drift::DriftState>>>,
+    api_token: Option<Arc<String>>,
+}
+
+impl AppState {
+    async fn lock(&self, environment: &str) -> Arc<Semaphore> {
+        self.locks
+            .write()
+            .await
+            .entry(environment.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(1)))
+            .clone()
+    }
+
+    // Whoever holds an environment's permit gives it back here. A pipeline queued meanwhile
+    // starts with it right away, so neither periodic work nor another pipeline loses a push.
+    async fn release(&self, environment: &str, permit: OwnedSemaphorePermit) {
+        let mut queued = self.queued.write().await;
+        match queued.remove(environment) {
+            Some(next) if !self.shutdown.is_cancelled() => {
+                drop(queued);
+                info!(
+                    "Starting queued {:?} for {} commit {}",
+                    next.trigger, environment, next.commit_hash
+                );
+                launch_pipeline(self, next, permit);
+            }
+            _ => drop(permit),
+        }
+    }
+
+    // Environments and commits running pipelines have checked out, for GC to leave alone.
+    // Called from blocking code.
+    fn in_use(&self) -> impl Fn() -> HashSet<(String, String)> + Send + 'static {
+        let pipelines = self.pipelines.clone();
+        move || {
+            pipelines
+                .blocking_read()
+                .iter()
+                .map(|(environment, p)| (environment.clone(), p.commit_hash.clone()))
+                .collect()
+        }
+    }
 }
 
+mod alloc;
+mod auth;
 mod build;
+mod capacity;
+mod config;
+mod credentials;
+mod deployments;
+mod drift;
+mod exec;
+mod gc;
 mod git;
+mod health;
+mod hostkeys;
+mod import;
+mod logs;
 mod nix;
+mod notify;
 mod parsing;
+mod policy;
+mod poll;
 mod qm;
+mod repos;
+mod signing;
 mod state;
 mod types;
+mod validate;
+
+// Guards the routes that cancel, collect, roll back or import, see auth.rs
+async fn require_token(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> std::result::Result<Response, StatusCode> {
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    if !auth::authorized(
+        state.api_token.as_deref().map(|t| t.as_str()),
+        authorization,
+        peer.ip(),
+    ) {
+        warn!(
+            "Refused {} {} from {}",
+            request.method(),
+            request.uri().path(),
+            peer
+        );
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(next.run(request).await)
+}
 
 #[axum::debug_handler]
 async fn webhook_handler(
@@ -33,59 +158,712 @@ async fn webhook_handler(
         }
     };
 
-    let git_repo_url = parsed.repository.clone();
-    let current_git_commit = parsed.hash.clone();
+    let Some(environments) = repos::for_push(&parsed.repository, parsed.git_ref.as_deref()) else {
+        warn!("Refusing push of {}: not in git.repos", parsed.repository);
+        return StatusCode::FORBIDDEN;
+    };
+    if environments.is_empty() {
+        info!(
+            "Ignoring push of {} to {:?}: no environment tracks that ref",
+            parsed.repository, parsed.git_ref
+        );
+        return StatusCode::OK;
+    }
+    let mut status = StatusCode::OK;
+    for repo in environments {
+        let started = start_pipeline(
+            &state,
+            repo,
+            parsed.hash.clone(),
+            types::PipelineTrigger::Push,
+        )
+        .await;
+        if started.is_none() {
+            status = StatusCode::ACCEPTED;
+        }
+    }
+    status
+}
 
-    let permit = match state.semaphore.try_acquire_owned() {
-        Ok(permit) => permit,
-        Err(_) => {
-            warn!(
-                "Pipeline already running, rejecting webhook for commit {}",
-                current_git_commit
+// Shared by pushes, polls and rollbacks, returns the pipeline id. If the environment is busy
+// the commit is queued instead and None returned, see AppState::release.
+async fn start_pipeline(
+    state: &AppState,
+    repo: config::RepoConfig,
+    current_git_commit: String,
+    trigger: types::PipelineTrigger,
+) -> Option<String> {
+    let environment = repos::environment(&repo);
+    let lock = state.lock(&environment).await;
+    // Held until queued or started, so a permit being released can't miss this commit
+    let mut queued = state.queued.write().await;
+    let Ok(permit) = lock.try_acquire_owned() else {
+        info!(
+            "{} is busy, queueing {:?} for commit {}",
+            environment, trigger, current_git_commit
+        );
+        if let Some(replaced) = queued.insert(
+            environment.clone(),
+            QueuedPipeline {
+                repo,
+                commit_hash: current_git_commit,
+                trigger,
+            },
+        ) {
+            info!(
+                "Dropping queued commit {} of {}, a newer one replaced it",
+                replaced.commit_hash, environment
             );
-            return StatusCode::TOO_MANY_REQUESTS;
+        }
+        return None;
+    };
+    drop(queued);
+    Some(launch_pipeline(
+        state,
+        QueuedPipeline {
+            repo,
+            commit_hash: current_git_commit,
+            trigger,
+        },
+        permit,
+    ))
+}
+
+// Runs a pipeline holding the environment's permit, which is released or handed to the next
+// queued pipeline when it finishes
+fn launch_pipeline(state: &AppState, next: QueuedPipeline, permit: OwnedSemaphorePermit) -> String {
+    let QueuedPipeline {
+        repo,
+        commit_hash: current_git_commit,
+        trigger,
+    } = next;
+    let environment = repos::environment(&repo);
+    let git_repo_url = repo.url.clone();
+    let cancel = state.shutdown.child_token();
+    let pipeline_id = logs::new_pipeline_id(&current_git_commit);
+    let log = match logs::PipelineLog::create(&config::get().log_dir, &pipeline_id) {
+        Ok(log) => Some(log),
+        Err(e) => {
+            warn!("Failed to create pipeline log {}: {:?}", pipeline_id, e);
+            None
         }
     };
+    let ctx = exec::ExecContext {
+        cancel: cancel.clone(),
+        log: log.clone(),
+        stream: "pipeline".to_string(),
+    };
+
+    let task_id = pipeline_id.clone();
+    let state = state.clone();
+    tokio::spawn(async move {
+        state.pipelines.write().await.insert(
+            environment.clone(),
+            RunningPipeline {
+                cancel,
+                commit_hash: current_git_commit.clone(),
+            },
+        );
+        if let Some(log) = log {
+            state
+                .live_logs
+                .write()
+                .await
+                .insert(pipeline_id.clone(), log);
+        }
+        let pipelines = state.pipelines.clone();
+        let live_logs = state.live_logs.clone();
+        let deployments_state = state.deployments.clone();
+        let in_use = state.in_use();
+        let released = environment.clone();
+        let _ = tokio::task::spawn_blocking(move || {
+            info!(
+                "Pipeline {} ({:?}) started for {} repo: {}, commit: {}",
+                pipeline_id, trigger, environment, git_repo_url, current_git_commit
+            );
+            let result = exec::with_context(ctx, || {
+                exec::log_line(&format!(
+                    "Pipeline ({:?}) started for {} repo: {}, commit: {}",
+                    trigger, environment, git_repo_url, current_git_commit
+                ));
+                let mut result = build::run_pipeline(&repo, &current_git_commit);
+                match &mut result {
+                    Ok(outcome) => {
+                        outcome.report.trigger = trigger;
+                        // Saved before GC so this commit counts as one of the deployments to keep
+                        let dir =
+                            deployments::environment_dir(&config::get().deployments_dir, &environment);
+                        if let Err(e) = deployments::save(&dir, &outcome.snapshot) {
+                            warn!("Failed to persist deployment snapshot: {:?}", e);
+                        }
+                        if config::get().gc.enabled {
+                            match exec::with_stream("gc", || gc::collect(&in_use)) {
+                                Ok(gc_report) => {
+                                    exec::log_line(&format!(
+                                        "gc: removed {} checkouts ({} bytes), nix store: {:?} bytes",
+                                        gc_report.removed_checkouts.len(),
+                                        gc_report.checkout_bytes_freed,
+                                        gc_report.store_bytes_freed
+                                    ));
+                                    outcome.report.gc = Some(gc_report);
+                                }
+                                Err(e) => warn!("Garbage collection failed: {:?}", e),
+                            }
+                        }
+                        let report = &outcome.report;
+                        for image in &report.images {
+                            exec::log_line(&format!(
+                                "image {} (nix hash: {}): {:?}",
+                                image.image_type, image.nix_hash, image.status
+                            ));
+                        }
+                        for vm in &report.vms {
+                            exec::log_line(&format!(
+                                "{} (id: {}): {:?}, shutdown: {:?}",
+                                vm.name, vm.vm_id, vm.action, vm.shutdown
+                            ));
+                        }
+                        if let Err(e) = logs::write_report(&config::get().log_dir, &pipeline_id, report)
+                        {
+                            warn!("Failed to write run report for {}: {:?}", pipeline_id, e);
+                        }
+                        exec::log_line("Pipeline finished");
+                    }
+                    Err(e) => exec::log_line(&format!("Pipeline failed: {}", e)),
+                }
+                result
+            });
+            match result {
+                Ok(outcome) => {
+                    info!(
+                        "Pipeline {} finished for repo: {}, commit: {}",
+                        pipeline_id, git_repo_url, current_git_commit
+                    );
+                    deployments_state
+                        .blocking_write()
+                        .insert(environment.clone(), outcome.snapshot);
+                }
+                Err(types::AppError::UntrustedCommit(reason)) => {
+                    error!("Pipeline {} rejected: {}", pipeline_id, reason);
+                    notify::send(&notify::Notification {
+                        event: "commit_rejected".to_string(),
+                        vm: None,
+                        message: format!(
+                            "Refusing to deploy {} from {}: {}",
+                            current_git_commit, git_repo_url, reason
+                        ),
+                    });
+                }
+                Err(e) => error!(
+                    "Pipeline {} failed for repo: {}, commit: {}, error: {:?}",
+                    pipeline_id, git_repo_url, current_git_commit, e
+                ),
+            }
+            pipelines.blocking_write().remove(&environment);
+            live_logs.blocking_write().remove(&pipeline_id);
+        })
+        .await;
+        state.release(&released, permit).await;
+    });
+
+    task_id
+}
+
+#[derive(serde::Deserialize)]
+struct EnvironmentQuery {
+    environment: Option<String>,
+}
+
+// Redeploys a commit that was deployed successfully before. It goes through the normal
+// pipeline, images still in the store are linked rather than rebuilt. If the commit was
+// deployed to several environments, ?environment= picks one.
+async fn rollback_handler(
+    State(state): State<AppState>,
+    Path(commit_hash): Path<String>,
+    Query(query): Query<EnvironmentQuery>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    if !deployments::valid_commit(&commit_hash) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let dir = &config::get().deployments_dir;
+    let candidates = match query.environment {
+        Some(environment) => vec![environment],
+        None => deployments::environments(dir).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+    };
+    let mut found: Vec<(String, types::DeploymentSnapshot)> = candidates
+        .into_iter()
+        .filter_map(|environment| {
+            let snapshot = deployments::load(
+                &deployments::environment_dir(dir, &environment),
+                &commit_hash,
+            )
+            .ok()?;
+            Some((environment, snapshot))
+        })
+        .collect();
+    let (environment, snapshot) = match found.len() {
+        0 => return Err(StatusCode::NOT_FOUND),
+        1 => found.remove(0),
+        _ => return Err(StatusCode::CONFLICT),
+    };
+    let from = state
+        .deployments
+        .read()
+        .await
+        .get(&environment)
+        .map(|current| current.commit_hash.clone());
+    warn!(
+        "Rolling back {} from {:?} to commit {}",
+        environment, from, snapshot.commit_hash
+    );
+    let Some(repo) =
+        repos::find(&environment).or_else(|| repos::unconfigured_if_allowed(&snapshot.repo_url))
+    else {
+        warn!("Refusing rollback of {}: not in git.repos", environment);
+        return Err(StatusCode::FORBIDDEN);
+    };
+    let pipeline_id = start_pipeline(
+        &state,
+        repo,
+        snapshot.commit_hash,
+        types::PipelineTrigger::Rollback { from },
+    )
+    .await;
+    Ok(Json(
+        serde_json::json!({ "pipeline_id": pipeline_id, "queued": pipeline_id.is_none() }),
+    ))
+}
+
+// Takes over an existing VM, see import.rs. Without "confirm": true only the report is
+// returned. Holds the environment's lock so no pipeline runs while the tags change.
+async fn import_handler(
+    State(state): State<AppState>,
+    Json(request): Json<import::ImportRequest>,
+) -> Result<Json<types::ImportReport>, (StatusCode, String)> {
+    let repo = import::environment_repo(request.environment.as_deref())
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let environment = repos::environment(&repo);
+    let Ok(permit) = state.lock(&environment).await.try_acquire_owned() else {
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            format!("{} is busy, try again later", environment),
+        ));
+    };
+    let cancel = state.shutdown.child_token();
+    let result =
+        tokio::task::spawn_blocking(move || exec::with_cancel(cancel, || import::import(&request)))
+            .await;
+    state.release(&environment, permit).await;
+    let result = result.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    match result {
+        Ok(report) => Ok(Json(report)),
+        Err(
+            e @ (types::AppError::ImportError(_)
+            | types::AppError::OwnershipError(_)
+            | types::AppError::UntrustedCommit(_)),
+        ) => {
+            warn!("Import refused: {}", e);
+            Err((StatusCode::CONFLICT, e.to_string()))
+        }
+        Err(e) => {
+            error!("Import failed: {:?}", e);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+        }
+    }
+}
+
+// proxnix --import, see import::parse_args. Shows the report and asks before tagging the VM.
+// Unlike the API it can't take the environment's lock, so don't run it during a deployment.
+fn import_cli(args: &[String]) -> i32 {
+    let request = match import::parse_args(args) {
+        Ok(request) => request,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+    let report = match import::plan(&request) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    println!(
+        "{} ({}) -> {} at commit {}",
+        report.name, report.vm_id, report.environment, report.commit_hash
+    );
+    println!("tags: {}", report.tags.join(";"));
+    match &report.required_action {
+        None => println!("matches its config, the next deployment leaves it alone"),
+        Some(action) => println!(
+            "differs from its config in {:?}, the next deployment will apply: {:?}",
+            report.changed_fields, action
+        ),
+    }
+    if !request.confirm {
+        print!("Import? [y/N] ");
+        let _ = std::io::Write::flush(&mut std::io::stdout());
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer).is_err()
+            || !answer.trim().eq_ignore_ascii_case("y")
+        {
+            println!("Nothing changed");
+            return 0;
+        }
+    }
+    match import::apply(report) {
+        Ok(report) => {
+            println!("Imported {} ({})", report.name, report.vm_id);
+            0
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+// proxnix --validate [<dir or json file>] [--environment <name>]. A directory is a checkout
+// whose flake is evaluated like the pipeline would, the environment supplying flake_dir and
+// attr_prefix. A JSON file is the proxnix output itself. Storages and bridges are only
+// checked when run on the Proxmox host.
+fn validate_cli(args: &[String]) -> i32 {
+    let mut path = ".".to_string();
+    let mut environment = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--environment" => environment = args.next().cloned(),
+            _ => path = arg.clone(),
+        }
+    }
+    let repo = match environment.as_deref().map(|name| (name, repos::find(name))) {
+        Some((name, None)) => {
+            eprintln!("Unknown environment {}", name);
+            return 2;
+        }
+        Some((_, repo)) => repo,
+        None => None,
+    };
+
+    let evaluated = if std::path::Path::new(&path).is_file() {
+        fs::read_to_string(&path)
+            .map(|raw| (raw, validate::Host::discover(None)))
+            .map_err(types::AppError::from)
+    } else {
+        nix::resolve_flake(&path, repo.as_ref()).and_then(|flake| {
+            let raw = nix::eval_vm_config(&flake)?;
+            let image_types = nix::list_nix_configs(&flake)?;
+            Ok((raw, validate::Host::discover(Some(image_types))))
+        })
+    };
+    let (raw, host) = match evaluated {
+        Ok(evaluated) => evaluated,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    for (check, skipped) in [
+        ("storages", host.storages.is_none()),
+        ("bridges", host.bridges.is_none()),
+    ] {
+        if skipped {
+            println!("Not a Proxmox host, {} are not checked", check);
+        }
+    }
+    // Policies on the plan itself (deletes, renames) need the live cluster and aren't checked
+    let checked = validate::validate(&raw, &host).and_then(|desired| {
+        let policy = &config::get().policy;
+        let problems =
+            policy::check_desired(&desired, policy, policy::repo_limits(policy, repo.as_ref()));
+        if problems.is_empty() {
+            Ok(desired)
+        } else {
+            Err(problems)
+        }
+    });
+    match checked {
+        Ok(desired) => {
+            println!("OK, {} VMs", desired.vms.len());
+            0
+        }
+        Err(problems) => {
+            for problem in &problems {
+                println!("{}", problem);
+            }
+            println!("{} problems", problems.len());
+            1
+        }
+    }
+}
 
+async fn list_deployments_handler() -> Result<Json<serde_json::Value>, StatusCode> {
+    let dir = &config::get().deployments_dir;
+    let mut deployments = Vec::new();
+    for environment in
+        deployments::environments(dir).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
     {
-        let mut guard = state.last_repo.write().await;
-        *guard = Some((git_repo_url.clone(), current_git_commit.clone()));
+        let history = deployments::list(&deployments::environment_dir(dir, &environment))
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        deployments.extend(history.into_iter().map(|d| (environment.clone(), d)));
     }
+    deployments.sort_by_key(|(_, d)| std::cmp::Reverse(d.deployed_at));
+    let history: Vec<serde_json::Value> = deployments
+        .iter()
+        .map(|(environment, d)| {
+            serde_json::json!({
+                "environment": environment,
+                "commit_hash": d.commit_hash,
+                "repo_url": d.repo_url,
+                "deployed_at": d.deployed_at,
+            })
+        })
+        .collect();
+    Ok(Json(serde_json::json!({ "deployments": history })))
+}
 
-    tokio::task::spawn_blocking(move || {
-        info!(
-            "Pipeline started for repo: {}, commit: {}",
-            git_repo_url, current_git_commit
-        );
-        match build::run_pipeline(&git_repo_url, &current_git_commit) {
-            Ok(_) => info!(
-                "Pipeline finished for repo: {}, commit: {}",
-                git_repo_url, current_git_commit
-            ),
-            Err(e) => error!(
-                "Pipeline failed for repo: {}, commit: {}, error: {:?}",
-                git_repo_url, current_git_commit, e
-            ),
+// Cancels the pipeline of ?environment=, or every running pipeline, and drops what was
+// queued behind it
+async fn cancel_handler(
+    State(state): State<AppState>,
+    Query(query): Query<EnvironmentQuery>,
+) -> StatusCode {
+    let mut cancelled = 0;
+    state.queued.write().await.retain(|environment, next| {
+        let matches = query.environment.as_ref().is_none_or(|e| e == environment);
+        if matches {
+            warn!(
+                "Dropping queued commit {} of {}",
+                next.commit_hash, environment
+            );
+            cancelled += 1;
+        }
+        !matches
+    });
+    let pipelines = state.pipelines.read().await;
+    for (environment, pipeline) in pipelines.iter() {
+        if query.environment.as_ref().is_none_or(|e| e == environment) {
+            warn!("Cancelling running pipeline of {}", environment);
+            pipeline.cancel.cancel();
+            cancelled += 1;
+        }
+    }
+    if cancelled == 0 {
+        StatusCode::NOT_FOUND
+    } else {
+        StatusCode::OK
+    }
+}
+
+async fn gc_handler(State(state): State<AppState>) -> Result<Json<types::GcReport>, StatusCode> {
+    let cancel = state.shutdown.child_token();
+    let in_use = state.in_use();
+    let result =
+        tokio::task::spawn_blocking(move || exec::with_cancel(cancel, || gc::collect(&in_use)))
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    match result {
+        Ok(report) => Ok(Json(report)),
+        Err(e) => {
+            error!("Garbage collection failed: {:?}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn status_handler(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let vms = state.restarts.read().await.snapshot(Instant::now());
+    let drift: HashMap<String, Option<types::DriftReport>> = state
+        .drift
+        .read()
+        .await
+        .iter()
+        .map(|(environment, drift)| (environment.clone(), drift.report.clone()))
+        .collect();
+    let deployments = state.deployments.read().await.clone();
+    Json(serde_json::json!({ "vms": vms, "drift": drift, "deployments": deployments }))
+}
+
+// Prometheus text format, drift summed over all environments
+async fn metrics_handler(State(state): State<AppState>) -> String {
+    let drift = state.drift.read().await;
+    let restarts = state.restarts.read().await.snapshot(Instant::now());
+    let mut out = String::new();
+    let mut gauge = |name: &str, help: &str, value: u64| {
+        out.push_str(&format!(
+            "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n"
+        ));
+    };
+    gauge(
+        "proxnix_crash_looping_vms",
+        "Managed VMs currently crash looping",
+        restarts.values().filter(|v| v.crash_looping).count() as u64,
+    );
+    let outstanding = |class: types::DriftClass| {
+        drift
+            .values()
+            .filter_map(|d| d.report.as_ref())
+            .map(|r| {
+                r.entries
+                    .iter()
+                    .filter(|e| e.class == class && !e.corrected)
+                    .count()
+            })
+            .sum::<usize>() as u64
+    };
+    gauge(
+        "proxnix_drift_in_place_vms",
+        "VMs with uncorrected in-place drift",
+        outstanding(types::DriftClass::InPlace),
+    );
+    gauge(
+        "proxnix_drift_rebuild_vms",
+        "VMs whose drift needs a rebuild",
+        outstanding(types::DriftClass::Rebuild),
+    );
+    gauge(
+        "proxnix_drift_missing_vms",
+        "Desired VMs missing from Proxmox",
+        outstanding(types::DriftClass::Missing),
+    );
+    gauge(
+        "proxnix_drift_checks_total",
+        "Drift checks run",
+        drift.values().map(|d| d.checks_total).sum(),
+    );
+    gauge(
+        "proxnix_drift_check_failures_total",
+        "Drift checks that failed",
+        drift.values().map(|d| d.check_failures_total).sum(),
+    );
+    gauge(
+        "proxnix_drift_corrections_total",
+        "In-place drift corrections applied",
+        drift.values().map(|d| d.corrections_total).sum(),
+    );
+    out
+}
+
+async fn list_pipelines_handler() -> Result<Json<Vec<String>>, StatusCode> {
+    logs::list_logs(&config::get().log_dir)
+        .map(Json)
+        .map_err(|_| StatusCode::NOT_FOUND)
+}
+
+async fn pipeline_report_handler(
+    Path(id): Path<String>,
+) -> Result<Json<types::RunReport>, StatusCode> {
+    if !logs::valid_id(&id) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    logs::read_report(&config::get().log_dir, &id)
+        .map(Json)
+        .map_err(|_| StatusCode::NOT_FOUND)
+}
+
+// Replays everything logged so far, then follows the pipeline live until it finishes.
+// For a finished pipeline this is just the replay.
+async fn pipeline_logs_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
+    if !logs::valid_id(&id) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    // Subscribe before reading the file so nothing falls between the two
+    let live = state.live_logs.read().await.get(&id).map(|l| l.subscribe());
+    let history = logs::read_log(&config::get().log_dir, &id).map_err(|_| StatusCode::NOT_FOUND)?;
+    let replayed = history.len() as u64;
+
+    let follow = stream::unfold(live, move |mut live| async move {
+        let rx = live.as_mut()?;
+        loop {
+            match rx.recv().await {
+                Ok(line) if line.seq < replayed => continue,
+                Ok(line) => return Some((line, live)),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("Log follower lagged, skipped {} lines", n);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
         }
-        drop(permit);
     });
+    let events = stream::iter(history)
+        .chain(follow)
+        .map(|line| Ok(Event::default().event(line.stream).data(line.line)));
 
-    StatusCode::OK
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+// For hosts the forge can't reach. New heads of polled refs go through start_pipeline like
+// a webhook, queued behind a running pipeline of the same environment.
+async fn poll_loop(state: AppState) {
+    let mut seen = poll::PollState::load();
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(poll::next_delay()) => {},
+            _ = state.shutdown.cancelled() => break,
+        }
+        let heads = tokio::task::spawn_blocking(poll::poll_all)
+            .await
+            .unwrap_or_default();
+        for (repo, head) in heads {
+            let environment = repos::environment(&repo);
+            let git_ref = repo.poll_ref.clone().unwrap_or_default();
+            let commit = match head {
+                Ok(commit) => commit,
+                Err(e) => {
+                    warn!("Failed to poll {} {}: {:?}", environment, git_ref, e);
+                    continue;
+                }
+            };
+            if !seen.is_new(&environment, &commit) {
+                continue;
+            }
+            // Already deployed, e.g. the webhook got there first
+            let deployed = state
+                .deployments
+                .read()
+                .await
+                .get(&environment)
+                .is_some_and(|d| d.commit_hash == commit);
+            if deployed {
+                seen.mark(&environment, &commit);
+                continue;
+            }
+            info!("New commit {} on {} {}", commit, environment, git_ref);
+            let trigger = types::PipelineTrigger::Poll { git_ref };
+            start_pipeline(&state, repo, commit.clone(), trigger).await;
+            seen.mark(&environment, &commit);
+        }
+    }
+}
+
+async fn shutdown_signal(shutdown: CancellationToken) {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
+    }
+    info!("Shutdown requested, cancelling running work");
+    shutdown.cancel();
 }
 
 fn init() {
     fs::create_dir_all("/var/lib/proxnix").expect("Failed to create /var/lib/proxnix");
+    fs::create_dir_all(&config::get().deployments_dir)
+        .expect("Failed to create deployments directory");
+    fs::create_dir_all(&config::get().git.mirror_dir)
+        .expect("Failed to create git mirror directory");
     println!("Init complete");
 }
 
 #[tokio::main]
 async fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.get(1).map(|s| s.as_str()) == Some("--init") {
-        init();
-        return;
-    }
-
     tracing_subscriber::fmt()
         .with_env_filter(
             tracing_subscriber::EnvFilter::try_from_default_env()
@@ -93,45 +871,190 @@ async fn main() {
         )
         .init();
 
-    let last_repo = Arc::new(RwLock::new(None));
+    let config_path =
+        env::var("PROXNIX_CONFIG").unwrap_or_else(|_| config::DEFAULT_CONFIG_PATH.to_string());
+    config::init(config::load(&config_path).expect("Failed to load config"));
+    // Creates the configured directories, so it needs the config loaded first
+    if args.get(1).map(|s| s.as_str()) == Some("--init") {
+        init();
+        return;
+    }
+    // Commands run nix and qm through exec, which blocks, so they get a blocking thread too
+    let command: Option<fn(&[String]) -> i32> = match args.get(1).map(|s| s.as_str()) {
+        Some("--import") => Some(import_cli),
+        Some("--validate") => Some(validate_cli),
+        _ => None,
+    };
+    if let Some(command) = command {
+        let rest = args[2..].to_vec();
+        let code = tokio::task::spawn_blocking(move || command(&rest))
+            .await
+            .unwrap_or(1);
+        std::process::exit(code);
+    }
+
+    let deployments_dir = &config::get().deployments_dir;
+    if let Err(e) = deployments::migrate_legacy(deployments_dir) {
+        warn!("Failed to migrate deployment history: {:?}", e);
+    }
+    let mut current_deployments = HashMap::new();
+    for environment in deployments::environments(deployments_dir).unwrap_or_default() {
+        match deployments::load_current(&deployments::environment_dir(
+            deployments_dir,
+            &environment,
+        )) {
+            Ok(Some(snapshot)) => {
+                info!(
+                    "Loaded cached deployment of {} for commit {}",
+                    environment, snapshot.commit_hash
+                );
+                current_deployments.insert(environment, snapshot);
+            }
+            Ok(None) => {}
+            Err(e) => warn!(
+                "Failed to load cached deployment of {}: {:?}",
+                environment, e
+            ),
+        }
+    }
     let app_state = AppState {
-        semaphore: Arc::new(Semaphore::new(1)),
-        last_repo,
+        locks: Arc::new(RwLock::new(HashMap::new())),
+        shutdown: CancellationToken::new(),
+        pipelines: Arc::new(RwLock::new(HashMap::new())),
+        queued: Arc::new(RwLock::new(HashMap::new())),
+        live_logs: Arc::new(RwLock::new(HashMap::new())),
+        restarts: Arc::new(RwLock::new(health::RestartTracker::default())),
+        deployments: Arc::new(RwLock::new(current_deployments)),
+        drift: Arc::new(RwLock::new(HashMap::new())),
+        api_token: auth::load_token()
+            .expect("Failed to load API token")
+            .map(Arc::new),
     };
+    if app_state.api_token.is_none() {
+        info!("No api.token_file set, cancel, gc, rollback and import only accept local requests");
+    }
 
     let periodic_state = app_state.clone();
     tokio::spawn(async move {
         let mut interval = tokio::time::interval(Duration::from_secs(10));
         loop {
-            interval.tick().await;
-            let permit = match periodic_state.semaphore.clone().try_acquire_owned() {
-                Ok(p) => p,
-                Err(_) => {
-                    info!("Pipeline is running, skipping periodic reconcile");
-                    continue;
-                }
-            };
-            let lr = periodic_state.last_repo.read().await.clone();
-            match lr {
-                None => {
-                    info!("No pipeline has run yet")
-                }
-                Some((_, commit_hash)) => {
-                    let dest_path = format!("{}/{}", nix::BASE_REPO_PATH, commit_hash);
-                    tokio::task::spawn_blocking(move || {
-                        build::ensure_vms_running(&dest_path);
-                        drop(permit);
-                    });
-                }
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = periodic_state.shutdown.cancelled() => break,
+            }
+            let snapshots = periodic_state.deployments.read().await.clone();
+            if snapshots.is_empty() {
+                info!("No pipeline has run yet");
+            }
+            for (environment, snapshot) in snapshots {
+                let permit = match periodic_state.lock(&environment).await.try_acquire_owned() {
+                    Ok(p) => p,
+                    Err(_) => {
+                        info!(
+                            "Pipeline is running for {}, skipping periodic reconcile",
+                            environment
+                        );
+                        continue;
+                    }
+                };
+                let cancel = periodic_state.shutdown.child_token();
+                let restarts = periodic_state.restarts.clone();
+                let drift_state = periodic_state.drift.clone();
+                let queued = periodic_state.queued.clone();
+                let state = periodic_state.clone();
+                let released = environment.clone();
+                tokio::spawn(async move {
+                    let _ = tokio::task::spawn_blocking(move || {
+                        exec::with_cancel(cancel, || {
+                            // Both only lock to read and record, so /status and other
+                            // environments aren't held up by qm or a drift check
+                            let crash_loops =
+                                build::ensure_vms_running(&snapshot.desired, &restarts);
+                            // Console capture takes a while and needs no permit, so it doesn't
+                            // hold up the drift check or a pipeline for this environment
+                            if !crash_loops.is_empty() {
+                                let restarts = restarts.clone();
+                                tokio::task::spawn_blocking(move || {
+                                    for (vm_id, name) in crash_loops {
+                                        build::report_crash_loop(vm_id, &name, &restarts);
+                                    }
+                                });
+                            }
+                            // A pipeline queued meanwhile goes first, it redoes the diff anyway
+                            if queued.blocking_read().contains_key(&environment) {
+                                return;
+                            }
+                            let due = drift::start_if_due(
+                                drift_state
+                                    .blocking_write()
+                                    .entry(environment.clone())
+                                    .or_default(),
+                            );
+                            if due {
+                                let result =
+                                    drift::check_drift(&snapshot, config::get().drift.mode);
+                                let notifications = drift::record(
+                                    &snapshot,
+                                    result,
+                                    drift_state.blocking_write().entry(environment).or_default(),
+                                );
+                                notifications.iter().for_each(notify::send);
+                            }
+                        });
+                    })
+                    .await;
+                    state.release(&released, permit).await;
+                });
             }
         }
     });
 
+    if !poll::polled_repos().is_empty() {
+        tokio::spawn(poll_loop(app_state.clone()));
+    }
+
+    let shutdown = app_state.shutdown.clone();
+    let pipelines = app_state.pipelines.clone();
+    let protected = Router::new()
+        .route("/pipeline/cancel", post(cancel_handler))
+        .route("/gc", post(gc_handler))
+        .route("/rollback/{commit}", post(rollback_handler))
+        .route("/import", post(import_handler))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            require_token,
+        ));
     let app = Router::new()
+        .merge(protected)
         .route("/whlisten", post(webhook_handler))
+        .route("/deployments", get(list_deployments_handler))
+        .route("/status", get(status_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/pipelines", get(list_pipelines_handler))
+        .route("/pipelines/{id}/logs", get(pipeline_logs_handler))
+        .route("/pipelines/{id}/report", get(pipeline_report_handler))
         .with_state(app_state);
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:6780").await.unwrap();
     info!("Listening on 0.0.0.0:6780");
-    axum::serve(listener, app).await.unwrap_or_default()
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal(shutdown))
+    .await
+    .unwrap_or_default();
+
+    // Running commands have been killed by the cancel, give the pipelines a moment to unwind
+    let unwound = async {
+        while !pipelines.read().await.is_empty() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    };
+    if tokio::time::timeout(SHUTDOWN_GRACE, unwound).await.is_err() {
+        warn!(
+            "Pipelines still running after {:?}, exiting anyway",
+            SHUTDOWN_GRACE
+        );
+    }
 }
@@ -1,9 +1,11 @@
-use crate::types::{AppError, Result};
+use crate::config::{self, RepoConfig};
+use crate::exec;
+use crate::types::{AppError, ImagePaths, Result};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use tracing::info;
 
-pub const BASE_REPO_PATH: &str = "/tmp/proxnix/repos";
+const BUILD_ERROR_TAIL_LINES: usize = 20;
 
 fn walk_for_file(dir: &Path, filename: &str, results: &mut Vec<PathBuf>) -> Result<()> {
     for entry in std::fs::read_dir(dir)? {
@@ -20,35 +22,99 @@ fn walk_for_file(dir: &Path, filename: &str, results: &mut Vec<PathBuf>) -> Resu
     Ok(())
 }
 
-pub fn find_in_repo(repo_path: &str, filename: &str) -> Result<String> {
-    let mut results = Vec::new();
-    walk_for_file(Path::new(repo_path), filename, &mut results)?;
-    match results.len() {
-        0 => Err(AppError::CmdError(format!(
-            "'{}' not found in repo",
-            filename
-        ))),
-        1 => Ok(results.remove(0).to_string_lossy().to_string()),
-        n => Err(AppError::CmdError(format!(
-            "Found {} copies of '{}' in repo, expected exactly 1",
-            n, filename
-        ))),
+// Where the deployment flake lives in a checkout and how its outputs are addressed
+#[derive(Debug, Clone)]
+pub struct Flake {
+    pub dir: PathBuf,
+    pub attr_prefix: Option<String>,
+    // Without ?submodules=1 nix evaluates the flake as if submodules were empty
+    pub submodules: bool,
+}
+
+impl Flake {
+    pub fn attr(&self, path: &str) -> String {
+        let query = if self.submodules { "?submodules=1" } else { "" };
+        match &self.attr_prefix {
+            Some(prefix) => format!(".{}#{}.{}", query, prefix, path),
+            None => format!(".{}#{}", query, path),
+        }
     }
 }
 
-pub fn eval_vm_config(repo_path: &str) -> Result<String> {
-    let flake_path = find_in_repo(repo_path, "flake.nix")?;
-    let nix_dir = Path::new(&flake_path)
-        .parent()
-        .ok_or_else(|| AppError::CmdError("Failed to get parent path".to_string()))?;
+fn find_flakes(repo_path: &str) -> Result<Vec<String>> {
+    let mut results = Vec::new();
+    walk_for_file(Path::new(repo_path), "flake.nix", &mut results)?;
+    let mut flakes: Vec<String> = results
+        .iter()
+        .filter_map(|path| path.strip_prefix(repo_path).ok())
+        .map(|path| path.to_string_lossy().to_string())
+        .collect();
+    flakes.sort();
+    Ok(flakes)
+}
+
+// The repo's configured flake_dir, else a flake.nix at the root, else the only flake.nix in
+// the repo. Several candidates and no configuration is an error rather than a guess.
+pub fn resolve_flake(repo_path: &str, repo: Option<&RepoConfig>) -> Result<Flake> {
+    let root = Path::new(repo_path);
+    let attr_prefix = repo.and_then(|r| r.attr_prefix.clone());
+    let submodules = root.join(".gitmodules").exists();
+    if let Some(flake_dir) = repo.and_then(|r| r.flake_dir.as_deref()) {
+        let relative = Path::new(flake_dir);
+        if relative.is_absolute()
+            || relative
+                .components()
+                .any(|c| matches!(c, std::path::Component::ParentDir))
+        {
+            return Err(AppError::CmdError(format!(
+                "flake_dir '{}' must be a path inside the repo",
+                flake_dir
+            )));
+        }
+        let dir = root.join(relative);
+        if !dir.join("flake.nix").exists() {
+            return Err(AppError::CmdError(format!(
+                "No flake.nix in configured flake_dir '{}'",
+                flake_dir
+            )));
+        }
+        return Ok(Flake {
+            dir,
+            attr_prefix,
+            submodules,
+        });
+    }
+    let flakes = find_flakes(repo_path)?;
+    let dir = match flakes.as_slice() {
+        [] => {
+            return Err(AppError::CmdError(
+                "'flake.nix' not found in repo".to_string(),
+            ));
+        }
+        _ if flakes.iter().any(|f| f == "flake.nix") => root.to_path_buf(),
+        [only] => root.join(only).parent().unwrap_or(root).to_path_buf(),
+        _ => {
+            return Err(AppError::CmdError(format!(
+                "Found {} flakes in repo and none at its root, set flake_dir for the repo to one of: {}",
+                flakes.len(),
+                flakes.join(", ")
+            )));
+        }
+    };
+    Ok(Flake {
+        dir,
+        attr_prefix,
+        submodules,
+    })
+}
 
-    let nix_eval = Command::new("nix")
-        .current_dir(nix_dir)
+pub fn eval_vm_config(flake: &Flake) -> Result<String> {
+    let mut cmd = Command::new("nix");
+    cmd.current_dir(&flake.dir)
         .arg("eval")
-        .arg(".#proxnix")
-        .arg("--json")
-        .output()
-        .map_err(|e| AppError::CmdError(format!("Failed to run nix eval: {}", e)))?;
+        .arg(flake.attr("proxnix"))
+        .arg("--json");
+    let nix_eval = exec::run(cmd, config::get().timeouts.nix_eval())?;
     if !nix_eval.status.success() {
         let stderr = String::from_utf8_lossy(&nix_eval.stderr);
         return Err(AppError::CmdError(format!(
@@ -62,21 +128,15 @@ pub fn eval_vm_config(repo_path: &str) -> Result<String> {
     Ok(output_string)
 }
 
-pub fn list_nix_configs(repo_path: &str) -> Result<Vec<String>> {
-    let flake_path = find_in_repo(repo_path, "flake.nix")?;
-    let nix_dir = Path::new(&flake_path)
-        .parent()
-        .ok_or_else(|| AppError::CmdError("flake.nix has no parent directory".to_string()))?;
-
-    let nix_eval = Command::new("nix")
-        .current_dir(nix_dir)
+pub fn list_nix_configs(flake: &Flake) -> Result<Vec<String>> {
+    let mut cmd = Command::new("nix");
+    cmd.current_dir(&flake.dir)
         .arg("eval")
-        .arg(".#nixosConfigurations")
+        .arg(flake.attr("nixosConfigurations"))
         .arg("--apply")
         .arg("builtins.attrNames")
-        .arg("--json")
-        .output()
-        .map_err(|e| AppError::CmdError(format!("Failed to run nix eval: {}", e)))?;
+        .arg("--json");
+    let nix_eval = exec::run(cmd, config::get().timeouts.nix_eval())?;
     if !nix_eval.status.success() {
         let stderr = String::from_utf8_lossy(&nix_eval.stderr);
         return Err(AppError::CmdError(format!(
@@ -92,36 +152,131 @@ pub fn list_nix_configs(repo_path: &str) -> Result<Vec<String>> {
     Ok(parsed)
 }
 
-pub fn nix_build(config_name: &str, repo_path: &str) -> Result<String> {
-    let flake_path = find_in_repo(repo_path, "flake.nix")?;
-    let nix_dir = Path::new(&flake_path)
-        .parent()
-        .ok_or_else(|| AppError::CmdError("flake.nix has no parent directory".to_string()))?;
+fn qcow2_attr(flake: &Flake, config_name: &str) -> String {
+    flake.attr(&format!(
+        "nixosConfigurations.{}.config.system.build.qcow2",
+        config_name
+    ))
+}
+
+pub fn eval_image_paths(config_name: &str, flake: &Flake) -> Result<ImagePaths> {
+    let mut cmd = Command::new("nix");
+    cmd.current_dir(&flake.dir)
+        .arg("eval")
+        .arg(qcow2_attr(flake, config_name))
+        .arg("--apply")
+        .arg("d: { inherit (d) drvPath outPath; }")
+        .arg("--json");
+    let nix_eval = exec::run(cmd, config::get().timeouts.nix_eval())?;
+    if !nix_eval.status.success() {
+        let stderr = String::from_utf8_lossy(&nix_eval.stderr);
+        return Err(AppError::CmdError(format!(
+            "Nix eval of image paths failed for '{}' (exit: {:?}): {}",
+            config_name,
+            nix_eval.status.code(),
+            stderr
+        )));
+    }
+    Ok(serde_json::from_slice(&nix_eval.stdout)?)
+}
+
+// Only asks the local store, substituters are not queried
+pub fn store_path_exists(store_path: &str) -> Result<bool> {
+    let mut cmd = Command::new("nix");
+    cmd.arg("path-info").arg(store_path);
+    let output = exec::run(cmd, config::get().timeouts.nix_eval())?;
+    Ok(output.status.success())
+}
+
+// Splits `nix build --dry-run` output into derivations that would be built and paths that would be fetched
+pub fn parse_dry_run(stderr: &str) -> (Vec<String>, Vec<String>) {
+    let mut built = Vec::new();
+    let mut fetched = Vec::new();
+    let mut section: Option<&mut Vec<String>> = None;
+    for line in stderr.lines() {
+        let trimmed = line.trim();
+        if trimmed.contains("will be built") {
+            section = Some(&mut built);
+        } else if trimmed.contains("will be fetched") {
+            section = Some(&mut fetched);
+        } else if trimmed.starts_with("/nix/store/") && line.starts_with(' ') {
+            if let Some(paths) = section.as_mut() {
+                paths.push(trimmed.to_string());
+            }
+        } else {
+            section = None;
+        }
+    }
+    (built, fetched)
+}
+
+// Derivations nix would have to build locally to produce the image, empty if it can all be substituted
+pub fn dry_run_builds(config_name: &str, flake: &Flake) -> Result<Vec<String>> {
+    let mut cmd = Command::new("nix");
+    cmd.current_dir(&flake.dir)
+        .arg("build")
+        .arg(qcow2_attr(flake, config_name))
+        .arg("--dry-run");
+    let dry_run = exec::run(cmd, config::get().timeouts.nix_eval())?;
+    if !dry_run.status.success() {
+        let stderr = String::from_utf8_lossy(&dry_run.stderr);
+        return Err(AppError::CmdError(format!(
+            "Nix dry run failed for '{}' (exit: {:?}): {}",
+            config_name,
+            dry_run.status.code(),
+            stderr
+        )));
+    }
+    Ok(parse_dry_run(&String::from_utf8_lossy(&dry_run.stderr)).0)
+}
+
+// Points the out-link at a store path that already exists, keeping it a GC root without a rebuild
+pub fn link_store_path(config_name: &str, store_path: &str, repo_path: &str) -> Result<String> {
+    let result_path = format!("{}/{}/result", repo_path, config_name);
+    let mut cmd = Command::new("nix");
+    cmd.arg("build")
+        .arg(store_path)
+        .arg("--out-link")
+        .arg(&result_path);
+    let output = exec::run(cmd, config::get().timeouts.nix_eval())?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(AppError::CmdError(format!(
+            "Failed to link {} for '{}' (exit: {:?}): {}",
+            store_path,
+            config_name,
+            output.status.code(),
+            stderr
+        )));
+    }
+    Ok(result_path)
+}
 
+pub fn nix_build(config_name: &str, flake: &Flake, repo_path: &str) -> Result<String> {
     info!(
         "Running nix build for config '{}' in {}",
         config_name,
-        nix_dir.display()
+        flake.dir.display()
     );
     let result_path = format!("{}/{}/result", repo_path, config_name);
-    let nix_build = Command::new("nix")
-        .current_dir(nix_dir)
+    let mut cmd = Command::new("nix");
+    cmd.current_dir(&flake.dir)
         .arg("build")
-        .arg(format!(
-            ".#nixosConfigurations.{}.config.system.build.qcow2",
-            config_name
-        ))
+        .arg(qcow2_attr(flake, config_name))
         .arg("--out-link")
         .arg(&result_path)
-        .output()
-        .map_err(|e| AppError::CmdError(format!("Failed to run nix build: {}", e)))?;
+        .arg("--print-build-logs");
+    let nix_build = exec::run(cmd, config::get().timeouts.nix_build())?;
     if !nix_build.status.success() {
+        // The full output is in the pipeline log, keep the error itself readable
         let stderr = String::from_utf8_lossy(&nix_build.stderr);
+        let lines: Vec<&str> = stderr.lines().collect();
+        let tail = lines[lines.len().saturating_sub(BUILD_ERROR_TAIL_LINES)..].join("\n");
         return Err(AppError::CmdError(format!(
-            "Nix build failed for '{}' (exit: {:?}): {}",
+            "Nix build failed for '{}' (exit: {:?}), last lines:\n{}",
             config_name,
             nix_build.status.code(),
-            stderr
+            tail
         )));
     }
     info!("Nix build succeeded for '{}': {}", config_name, result_path);
@@ -141,3 +296,70 @@ pub fn configure_dirs(configs: Vec<String>, repo_path: &str) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dry_run() {
+        let stderr = "\
+these 2 derivations will be built:
+  /nix/store/aaa-nixos.qcow2.drv
+  /nix/store/bbb-etc.drv
+this path will be fetched (1.20 MiB download, 5.00 MiB unpacked):
+  /nix/store/ccc-bash-5.2
+";
+        let (built, fetched) = parse_dry_run(stderr);
+        assert_eq!(
+            built,
+            vec!["/nix/store/aaa-nixos.qcow2.drv", "/nix/store/bbb-etc.drv"]
+        );
+        assert_eq!(fetched, vec!["/nix/store/ccc-bash-5.2"]);
+        assert_eq!(parse_dry_run(""), (Vec::new(), Vec::new()));
+    }
+
+    #[test]
+    fn test_resolve_flake() {
+        let base = std::env::temp_dir().join(format!("proxnix-flake-{}", std::process::id()));
+        let repo_path = base.to_str().unwrap();
+        let touch = |path: &str| {
+            let path = base.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "{}").unwrap();
+        };
+
+        touch("definitions/nix/flake.nix");
+        let flake = resolve_flake(repo_path, None).unwrap();
+        assert_eq!(flake.dir, base.join("definitions/nix"));
+        assert_eq!(flake.attr("proxnix"), ".#proxnix");
+
+        touch("tools/flake.nix");
+        let err = resolve_flake(repo_path, None).unwrap_err().to_string();
+        assert!(err.contains("definitions/nix/flake.nix, tools/flake.nix"));
+
+        let repo = RepoConfig {
+            flake_dir: Some("tools".to_string()),
+            attr_prefix: Some("infra".to_string()),
+            ..Default::default()
+        };
+        touch(".gitmodules");
+        let flake = resolve_flake(repo_path, Some(&repo)).unwrap();
+        assert_eq!(flake.dir, base.join("tools"));
+        assert_eq!(
+            flake.attr("nixosConfigurations"),
+            ".?submodules=1#infra.nixosConfigurations"
+        );
+        let escape = RepoConfig {
+            flake_dir: Some("../elsewhere".to_string()),
+            ..Default::default()
+        };
+        assert!(resolve_flake(repo_path, Some(&escape)).is_err());
+
+        // A root flake wins over nested ones, e.g. in submodules
+        touch("flake.nix");
+        assert_eq!(resolve_flake(repo_path, None).unwrap().dir, base);
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
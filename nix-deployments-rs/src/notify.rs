@@ -0,0 +1,73 @@
+use crate::config;
+use crate::exec;
+use std::process::Command;
+use std::time::Duration;
+use tracing::{info, warn};
+
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Notification {
+    pub event: String,
+    pub vm: Option<String>,
+    pub message: String,
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn curl_config(url: &str, body: &str) -> String {
+    format!("url = {}\ndata-raw = {}\n", quote(url), quote(body))
+}
+
+// Best effort: POSTs the notification as JSON to the configured webhook via curl.
+// A failed notification is logged and otherwise ignored.
+pub fn send(notification: &Notification) {
+    info!("Notification [{}]: {}", notification.event, notification.message);
+    let Some(url) = &config::get().notifications.webhook_url else {
+        return;
+    };
+    let body = match serde_json::to_string(notification) {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Failed to serialise notification: {}", e);
+            return;
+        }
+    };
+    // URL and body go in through a curl config on stdin, the URL usually holds a secret
+    // and argv is visible to everyone on the host
+    let mut cmd = Command::new("curl");
+    cmd.arg("-fsS")
+        .arg("-X")
+        .arg("POST")
+        .arg("-H")
+        .arg("Content-Type: application/json")
+        .arg("-K")
+        .arg("-");
+    match exec::run_with_input(cmd, curl_config(url, &body).into_bytes(), NOTIFY_TIMEOUT) {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => warn!(
+            "Notification webhook failed (exit: {:?}): {}",
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr)
+        ),
+        Err(e) => warn!("Notification webhook failed: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_curl_config_quotes() {
+        assert_eq!(
+            curl_config(
+                "https://hooks.example.com/T0KEN",
+                r#"{"message":"line\nnext"}"#
+            ),
+            "url = \"https://hooks.example.com/T0KEN\"\ndata-raw = \"{\\\"message\\\":\\\"line\\\\nnext\\\"}\"\n"
+        );
+    }
+}
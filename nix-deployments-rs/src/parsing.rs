@@ -1,5 +1,6 @@
 use serde_json::Value;
 
+use crate::credentials;
 use crate::types::{AppError, ParsedWebhook, Result};
 
 pub fn webhook_parse(webhook: serde_json::Value) -> Result<ParsedWebhook> {
@@ -10,16 +11,70 @@ pub fn webhook_parse(webhook: serde_json::Value) -> Result<ParsedWebhook> {
         "could not find commit hash".to_string(),
     ))?;
 
-    let repo = find_string(&webhook, &|s| s.contains("ssh://") && s.contains(".git")).ok_or(
-        AppError::ParsingModuleError("could not find repo url".to_string()),
-    )?;
+    let mut urls = Vec::new();
+    collect_strings(&webhook, &is_clone_url, &mut urls);
+    let repo = choose_repo_url(&urls).ok_or(AppError::ParsingModuleError(
+        "could not find repo url".to_string(),
+    ))?;
+
+    // GitHub, Gitea and GitLab all send the pushed ref as "ref": "refs/heads/main"
+    let git_ref = webhook
+        .get("ref")
+        .and_then(|r| r.as_str())
+        .map(|r| r.to_string());
 
     Ok(ParsedWebhook {
         repository: repo,
         hash,
+        git_ref,
     })
 }
 
+fn is_scp_url(s: &str) -> bool {
+    !s.contains("://")
+        && s.split_once(':').is_some_and(|(host, path)| {
+            host.contains('@') && !host.contains('/') && !path.is_empty()
+        })
+}
+
+fn is_ssh_url(s: &str) -> bool {
+    s.starts_with("ssh://") || is_scp_url(s)
+}
+
+// Payloads carry several URLs for the repo, the web page ones don't end in .git
+pub fn is_clone_url(s: &str) -> bool {
+    s.ends_with(".git") && (is_ssh_url(s) || s.starts_with("https://") || s.starts_with("http://"))
+}
+
+// A repo listed in the config is fetched from its configured url whatever the payload says,
+// otherwise SSH is preferred over HTTPS
+pub fn choose_repo_url(urls: &[String]) -> Option<String> {
+    if let Some(repo) = urls.iter().find_map(|url| credentials::repo_config(url)) {
+        return Some(repo.url.clone());
+    }
+    urls.iter()
+        .find(|url| is_ssh_url(url))
+        .or_else(|| urls.first())
+        .cloned()
+}
+
+pub fn collect_strings(
+    json: &serde_json::Value,
+    predicate: &impl Fn(&str) -> bool,
+    out: &mut Vec<String>,
+) {
+    match json {
+        Value::String(s) if predicate(s) => out.push(s.clone()),
+        Value::Array(array) => array
+            .iter()
+            .for_each(|a| collect_strings(a, predicate, out)),
+        Value::Object(map) => map
+            .values()
+            .for_each(|v| collect_strings(v, predicate, out)),
+        _ => {}
+    }
+}
+
 pub fn find_string(json: &serde_json::Value, predicate: &impl Fn(&str) -> bool) -> Option<String> {
     match json {
         Value::String(s) => {
@@ -36,7 +91,7 @@ pub fn find_string(json: &serde_json::Value, predicate: &impl Fn(&str) -> bool)
                     return result;
                 }
             }
-            return None;
+            None
         }
         Value::Object(map) => {
             for v in map.values() {
@@ -45,10 +100,54 @@ pub fn find_string(json: &serde_json::Value, predicate: &impl Fn(&str) -> bool)
                     return result;
                 }
             }
-            return None;
-        }
-        _ => {
-            return None;
+            None
         }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    #[test]
+    fn test_prefers_ssh_url() {
+        let payload = serde_json::json!({
+            "ref": "refs/heads/main",
+            "after": HASH,
+            "repository": {
+                "html_url": "https://gitea.example.com/org/infra",
+                "clone_url": "https://gitea.example.com/org/infra.git",
+                "ssh_url": "git@gitea.example.com:org/infra.git"
+            }
+        });
+        let parsed = webhook_parse(payload).unwrap();
+        assert_eq!(parsed.repository, "git@gitea.example.com:org/infra.git");
+        assert_eq!(parsed.hash, HASH);
+        assert_eq!(parsed.git_ref.as_deref(), Some("refs/heads/main"));
+    }
+
+    #[test]
+    fn test_https_only() {
+        let payload = serde_json::json!({
+            "after": HASH,
+            "repository": {
+                "html_url": "https://github.com/org/infra",
+                "clone_url": "https://github.com/org/infra.git"
+            }
+        });
+        let parsed = webhook_parse(payload).unwrap();
+        assert_eq!(parsed.repository, "https://github.com/org/infra.git");
+    }
+
+    #[test]
+    fn test_clone_urls() {
+        assert!(is_clone_url("ssh://git@host:2222/org/infra.git"));
+        assert!(is_clone_url("git@host:org/infra.git"));
+        assert!(is_clone_url("https://host/org/infra.git"));
+        assert!(!is_clone_url("https://host/org/infra"));
+        assert!(!is_clone_url("someone@example.com"));
     }
 }
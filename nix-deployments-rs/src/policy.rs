@@ -0,0 +1,298 @@
+use crate::config::{self, PolicyConfig, RepoConfig, ResourceLimits};
+use crate::repos;
+use crate::types::{AppError, DesiredState, Result, StateDiff, VMConfig};
+use crate::validate::{Problem, problem};
+
+// Exact names, or prefixes when ending in '*' as with repo refs
+fn name_matches(patterns: &[String], name: &str) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == pattern,
+        })
+}
+
+fn vcpus(vm: &VMConfig) -> u64 {
+    u64::from(vm.cores) * u64::from(vm.sockets)
+}
+
+// Each resource of a VM or environment that is over its limit
+fn over_limits(
+    limits: &ResourceLimits,
+    memory_mb: u64,
+    vcpus: u64,
+    disk_gb: u64,
+) -> Vec<(&'static str, u64, u64)> {
+    [
+        ("memory_mb", memory_mb, limits.memory_mb),
+        ("vcpus", vcpus, limits.vcpus),
+        ("disk_gb", disk_gb, limits.disk_gb),
+    ]
+    .into_iter()
+    .filter_map(|(field, used, limit)| {
+        limit
+            .filter(|limit| used > *limit)
+            .map(|limit| (field, used, limit))
+    })
+    .collect()
+}
+
+// Checks the desired state alone, so it also runs for --validate. VMs without a vm_id yet
+// skip the ID check.
+pub fn check_desired(
+    desired: &DesiredState,
+    policy: &PolicyConfig,
+    repo_limits: &ResourceLimits,
+) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut names: Vec<&String> = desired.vms.keys().collect();
+    names.sort();
+    for name in names {
+        let vm = &desired.vms[name];
+        let path = format!("vms.{}", name);
+        if !policy.allowed_storages.is_empty()
+            && !policy.allowed_storages.contains(&vm.storage_location)
+        {
+            problems.push(problem(
+                format!("{}.storage_location", path),
+                format!(
+                    "{} is not allowed, use one of {}",
+                    vm.storage_location,
+                    policy.allowed_storages.join(", ")
+                ),
+            ));
+        }
+        if !policy.allowed_bridges.is_empty()
+            && !policy.allowed_bridges.contains(&vm.network_bridge)
+        {
+            problems.push(problem(
+                format!("{}.network_bridge", path),
+                format!(
+                    "{} is not allowed, use one of {}",
+                    vm.network_bridge,
+                    policy.allowed_bridges.join(", ")
+                ),
+            ));
+        }
+        if vm.vm_id != 0
+            && !policy.allowed_vm_ids.is_empty()
+            && !policy
+                .allowed_vm_ids
+                .iter()
+                .any(|(start, end)| (*start..=*end).contains(&vm.vm_id))
+        {
+            let ranges: Vec<String> = policy
+                .allowed_vm_ids
+                .iter()
+                .map(|(start, end)| format!("{}-{}", start, end))
+                .collect();
+            problems.push(problem(
+                format!("{}.vm_id", path),
+                format!(
+                    "{} is outside the allowed VM IDs {}",
+                    vm.vm_id,
+                    ranges.join(", ")
+                ),
+            ));
+        }
+        for (field, used, limit) in over_limits(
+            &policy.max_per_vm,
+            u64::from(vm.memory_mb),
+            vcpus(vm),
+            u64::from(vm.disk_gb),
+        ) {
+            problems.push(problem(
+                format!("{}.{}", path, field),
+                format!("{} is over the limit of {} per VM", used, limit),
+            ));
+        }
+        if !vm.protected && name_matches(&policy.protected_names, &vm.name) {
+            problems.push(problem(
+                format!("{}.protected", path),
+                "has to be true for this VM",
+            ));
+        }
+    }
+
+    let total = |resource: fn(&VMConfig) -> u64| desired.vms.values().map(resource).sum();
+    for (field, used, limit) in over_limits(
+        repo_limits,
+        total(|vm| u64::from(vm.memory_mb)),
+        total(vcpus),
+        total(|vm| u64::from(vm.disk_gb)),
+    ) {
+        problems.push(problem(
+            "vms",
+            format!(
+                "{} add up to {}, over the limit of {} for the environment",
+                field, used, limit
+            ),
+        ));
+    }
+    problems
+}
+
+// What the plan does to VMs that have to stay protected. Their configs can't drop
+// `protected`, but removing or renaming them would still destroy or unprotect them.
+pub fn check_diff(diff: &StateDiff, policy: &PolicyConfig) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    for vm in &diff.to_delete {
+        if name_matches(&policy.protected_names, &vm.vm_name) {
+            problems.push(problem(
+                format!("vms.{}", vm.vm_name),
+                format!(
+                    "would be destroyed ({}), but has to stay protected",
+                    vm.vm_id
+                ),
+            ));
+        }
+    }
+    for update in &diff.to_update {
+        let old_name = &update.deployed.vm_name;
+        if *old_name != update.config.name
+            && name_matches(&policy.protected_names, old_name)
+            && !name_matches(&policy.protected_names, &update.config.name)
+        {
+            problems.push(problem(
+                format!("vms.{}", update.config.name),
+                format!("renamed from {}, which has to stay protected", old_name),
+            ));
+        }
+    }
+    problems
+}
+
+pub fn repo_limits<'a>(
+    policy: &'a PolicyConfig,
+    repo: Option<&'a RepoConfig>,
+) -> &'a ResourceLimits {
+    repo.and_then(|repo| repo.limits.as_ref())
+        .unwrap_or(&policy.max_per_repo)
+}
+
+// Runs on the final plan, after VM IDs are allocated and before anything is built or changed
+pub fn enforce(repo: &RepoConfig, desired: &DesiredState, diff: &StateDiff) -> Result<()> {
+    let policy = &config::get().policy;
+    let mut problems = check_desired(desired, policy, repo_limits(policy, Some(repo)));
+    problems.extend(check_diff(diff, policy));
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(AppError::PolicyError(format!(
+        "{}\n{} violations in {}",
+        problems
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        problems.len(),
+        repos::environment(repo)
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::fixtures::{deployed_vm, vm_config};
+    use crate::types::{FieldChange, UpdateAction, VMUpdate};
+    use std::collections::HashMap;
+
+    fn vm(name: &str, vm_id: u32, memory_mb: u32, protected: bool) -> (String, VMConfig) {
+        let config = VMConfig {
+            memory_mb,
+            protected,
+            ..vm_config(name, vm_id)
+        };
+        (name.to_string(), config)
+    }
+
+    fn policy() -> PolicyConfig {
+        PolicyConfig {
+            allowed_storages: vec!["local-lvm".to_string()],
+            allowed_bridges: vec!["vmbr1".to_string()],
+            allowed_vm_ids: vec![(100, 199)],
+            max_per_vm: ResourceLimits {
+                memory_mb: Some(4096),
+                vcpus: Some(4),
+                disk_gb: None,
+            },
+            max_per_repo: ResourceLimits {
+                memory_mb: Some(6144),
+                ..Default::default()
+            },
+            protected_names: vec!["db-*".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_check_desired() {
+        let desired = DesiredState {
+            vms: HashMap::from([
+                vm("db-01", 100, 2048, false),
+                vm("web", 250, 8192, false),
+                vm("new", 0, 1024, false),
+            ]),
+        };
+        let problems: Vec<String> = check_desired(&desired, &policy(), &policy().max_per_repo)
+            .iter()
+            .map(|p| p.to_string())
+            .collect();
+        assert_eq!(
+            problems,
+            vec![
+                "vms.db-01.network_bridge: vmbr0 is not allowed, use one of vmbr1",
+                "vms.db-01.protected: has to be true for this VM",
+                "vms.new.network_bridge: vmbr0 is not allowed, use one of vmbr1",
+                "vms.web.network_bridge: vmbr0 is not allowed, use one of vmbr1",
+                "vms.web.vm_id: 250 is outside the allowed VM IDs 100-199",
+                "vms.web.memory_mb: 8192 is over the limit of 4096 per VM",
+                "vms: memory_mb add up to 11264, over the limit of 6144 for the environment",
+            ]
+        );
+
+        let repo = RepoConfig {
+            limits: Some(ResourceLimits::default()),
+            ..Default::default()
+        };
+        assert_eq!(
+            repo_limits(&policy(), Some(&repo)),
+            &ResourceLimits::default()
+        );
+        assert!(
+            check_desired(
+                &desired,
+                &PolicyConfig::default(),
+                &ResourceLimits::default()
+            )
+            .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_check_diff() {
+        let (_, renamed) = vm("web-01", 101, 1024, true);
+        let diff = StateDiff {
+            to_create: Vec::new(),
+            to_update: vec![VMUpdate {
+                name: renamed.name.clone(),
+                config: renamed,
+                deployed: deployed_vm("db-02", 101),
+                changed_fields: vec![FieldChange::Name],
+                required_action: UpdateAction::Protected,
+            }],
+            to_delete: vec![deployed_vm("db-01", 100), deployed_vm("old", 102)],
+        };
+        let problems: Vec<String> = check_diff(&diff, &policy())
+            .iter()
+            .map(|p| p.to_string())
+            .collect();
+        assert_eq!(
+            problems,
+            vec![
+                "vms.db-01: would be destroyed (100), but has to stay protected",
+                "vms.web-01: renamed from db-02, which has to stay protected",
+            ]
+        );
+    }
+}
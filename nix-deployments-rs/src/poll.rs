@@ -0,0 +1,147 @@
+use crate::config::{self, RepoConfig};
+use crate::git::{git_fetch, resolve_ref, with_mirror};
+use crate::types::{AppError, Result};
+use git2::Repository;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+const STATE_FILE: &str = "poll.json";
+
+// Repos with a poll_ref
+pub fn polled_repos() -> Vec<RepoConfig> {
+    config::get()
+        .git
+        .repos
+        .iter()
+        .filter(|repo| repo.poll_ref.is_some())
+        .cloned()
+        .collect()
+}
+
+// Jitter keeps several controllers polling the same forge from fetching in lockstep
+pub fn next_delay() -> Duration {
+    let poll = &config::get().poll;
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64)
+        .unwrap_or(0);
+    let jitter = nanos % (poll.jitter_secs * 1000 + 1);
+    Duration::from_secs(poll.interval_secs) + Duration::from_millis(jitter)
+}
+
+pub fn poll_head(mirror: &Repository, git_ref: &str) -> Result<String> {
+    git_fetch(mirror)?;
+    Ok(resolve_ref(mirror, git_ref)?.to_string())
+}
+
+// Fetches every polled repo, returning each one's head commit. A pipeline fetching into the
+// same mirror is waited for, see git::with_mirror.
+pub fn poll_all() -> Vec<(RepoConfig, Result<String>)> {
+    polled_repos()
+        .into_iter()
+        .map(|repo| {
+            let git_ref = repo.poll_ref.as_deref().unwrap_or_default();
+            let head = with_mirror(&repo.url, |mirror| poll_head(mirror, git_ref));
+            (repo, head)
+        })
+        .collect()
+}
+
+// Last commit handed to the pipeline per environment, so a head is only deployed once. It is kept
+// on disk so a restart doesn't redeploy the polled head over a rollback.
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct PollState {
+    seen: HashMap<String, String>,
+}
+
+impl PollState {
+    fn path() -> PathBuf {
+        Path::new(&config::get().git.mirror_dir).join(STATE_FILE)
+    }
+
+    pub fn load() -> Self {
+        std::fs::read_to_string(Self::path())
+            .ok()
+            .and_then(|raw| serde_json::from_str(&raw).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn is_new(&self, environment: &str, commit_hash: &str) -> bool {
+        self.seen.get(environment).map(|c| c.as_str()) != Some(commit_hash)
+    }
+
+    pub fn mark(&mut self, environment: &str, commit_hash: &str) {
+        self.seen
+            .insert(environment.to_string(), commit_hash.to_string());
+        let saved = serde_json::to_string(self)
+            .map_err(AppError::from)
+            .and_then(|raw| Ok(std::fs::write(Self::path(), raw)?));
+        if let Err(e) = saved {
+            warn!("Failed to save poll state: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::Oid;
+
+    fn commit_to_branch(repo: &Repository, branch: &str, contents: &str) -> Oid {
+        let blob = repo.blob(contents.as_bytes()).unwrap();
+        let mut tree = repo.treebuilder(None).unwrap();
+        tree.insert("flake.nix", blob, 0o100644).unwrap();
+        let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+        let sig = git2::Signature::now("test", "test@example.com").unwrap();
+        let refname = format!("refs/heads/{}", branch);
+        let parent = repo
+            .find_reference(&refname)
+            .ok()
+            .and_then(|r| r.peel_to_commit().ok());
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+        repo.commit(Some(&refname), &sig, &sig, contents, &tree, &parents)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_poll_bare_upstream() {
+        let base = std::env::temp_dir().join(format!("proxnix-poll-{}", std::process::id()));
+        let upstream = Repository::init_bare(base.join("upstream.git")).unwrap();
+        let first = commit_to_branch(&upstream, "main", "first");
+        commit_to_branch(&upstream, "other", "other");
+
+        let mirror = Repository::init_bare(base.join("mirror.git")).unwrap();
+        mirror
+            .remote_with_fetch(
+                "origin",
+                base.join("upstream.git").to_str().unwrap(),
+                "+refs/heads/*:refs/remotes/origin/*",
+            )
+            .unwrap();
+
+        let head = poll_head(&mirror, "main").unwrap();
+        assert_eq!(head, first.to_string());
+        let mut state = PollState::default();
+        assert!(state.is_new("prod", &head));
+        state.seen.insert("prod".to_string(), head.clone());
+        assert!(!state.is_new("prod", &head));
+
+        // Nothing pushed, same head
+        assert_eq!(poll_head(&mirror, "main").unwrap(), head);
+
+        // Pushes to other branches don't move the tracked ref
+        commit_to_branch(&upstream, "other", "other 2");
+        assert_eq!(poll_head(&mirror, "main").unwrap(), head);
+
+        let second = commit_to_branch(&upstream, "main", "second");
+        let head = poll_head(&mirror, "main").unwrap();
+        assert_eq!(head, second.to_string());
+        assert!(state.is_new("prod", &head));
+        assert!(state.is_new("staging", &first.to_string()));
+
+        assert!(poll_head(&mirror, "missing").is_err());
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
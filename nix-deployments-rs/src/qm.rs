@@ -1,58 +1,356 @@
-use crate::types::{AppError, FieldChange, Result, VMConfig, VMUpdate};
+use crate::alloc::alloc_tag;
+use crate::config;
+use crate::exec;
+use crate::state::{is_system_tag, parse_qm_config, qm_config};
+use crate::types::{
+    AppError, FieldChange, QMCommandError, QMErrorKind, Result, StartupOrder, VMConfig, VMUpdate,
+};
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
 use std::process::Command;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
 
-// TODO Parse the output from this and pattern match to see if it has failed and add some cases to retry
-pub fn qm_create(config: &VMConfig, nix_hash: &str, commit_hash: &str) -> Result<String> {
-    let qm_create = Command::new("qm")
-        .arg("create")
-        .arg(config.vm_id.to_string())
-        .arg("--name")
-        .arg(&config.name)
-        .arg("--memory")
-        .arg(config.memory_mb.to_string())
-        .arg("--cores")
-        .arg(config.cores.to_string())
-        .arg("--net0")
-        .arg(format!("virtio,bridge={}", config.network_bridge))
-        .arg("--scsihw")
-        .arg(config.scsi_hw.to_string())
-        .arg("--tags")
-        .arg(format!("proxnix;nix-{};commit-{}", nix_hash, commit_hash))
-        .output()?;
-    if !qm_create.status.success() {
-        let stderr = String::from_utf8_lossy(&qm_create.stderr);
-        return Err(AppError::CmdError(format!(
-            "qm create has failed with exit code: {:?}: {}",
-            qm_create.status.code(),
-            stderr
-        )));
+const LOCK_WAIT_TIMEOUT: Duration = Duration::from_secs(300);
+const LOCK_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn once() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
     }
-    let stdout_bytes = qm_create.stdout;
-    let output_string = String::from_utf8(stdout_bytes)?;
 
-    Ok(output_string)
+    // Exponential backoff with equal jitter: half of the capped delay is fixed,
+    // the other half is random so parallel callers don't retry in lockstep
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let capped = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        let half = capped / 2;
+        half + half.mul_f64(jitter())
+    }
 }
 
-pub fn qm_stop(vm_id: &u32) -> Result<String> {
-    let qm_stop = Command::new("qm")
-        .arg("stop")
-        .arg(vm_id.to_string())
-        .output()?;
-    if !qm_stop.status.success() {
-        let stderr = String::from_utf8_lossy(&qm_stop.stderr);
-        if stderr.contains("not running") {
-            return Ok(String::new());
+// Random value in [0, 1) without pulling in a rand dependency
+fn jitter() -> f64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let hash = RandomState::new().hash_one(nanos);
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Only messages Proxmox prints for conditions that clear up on their own are retryable:
+// the per-VM config lock, the cluster-wide storage lock and QMP commands to a busy VM timing out.
+// Anything else, including unrelated errors that merely mention "busy" or "timeout", is fatal.
+pub fn classify_qm_error(stderr: &str) -> QMErrorKind {
+    let stderr = stderr.to_lowercase();
+    let lines: Vec<&str> = stderr.lines().map(str::trim).collect();
+    if lines.iter().any(|line| {
+        (line.starts_with("vm ") && line.contains(" is locked ("))
+            || line.starts_with("vm is locked (")
+            || (line.starts_with("can't lock file '/var/lock/qemu-server/")
+                && line.ends_with("got timeout"))
+    }) {
+        QMErrorKind::Locked
+    } else if lines.iter().any(|line| {
+        (line.starts_with("cfs-lock 'storage-") && line.ends_with("got lock request timeout"))
+            || (line.starts_with("can't lock file '/var/lock/pve-manager/pve-storage-")
+                && line.ends_with("got timeout"))
+    }) {
+        QMErrorKind::StorageBusy
+    } else if lines.iter().any(|line| {
+        line.starts_with("vm ")
+            && line.contains(" qmp command '")
+            && line.ends_with("- got timeout")
+    }) {
+        QMErrorKind::Timeout
+    } else {
+        QMErrorKind::Fatal
+    }
+}
+
+pub fn wait_for_unlock(vm_id: u32, timeout: Duration) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        // If the config can't be read there is no lock to wait on, the command
+        // itself will surface the real error
+        let lock = match qm_config(vm_id).and_then(|raw| parse_qm_config(&raw)) {
+            Ok(config) => config.lock,
+            Err(_) => return Ok(()),
+        };
+        let Some(lock) = lock else {
+            return Ok(());
+        };
+        if Instant::now() >= deadline {
+            return Err(AppError::QMError(QMCommandError {
+                operation: "wait for unlock".to_string(),
+                vm_id: Some(vm_id),
+                exit_code: None,
+                stderr: format!("VM {} still locked ({}) after {:?}", vm_id, lock, timeout),
+                kind: QMErrorKind::Locked,
+            }));
+        }
+        info!("VM {} is locked ({}), waiting", vm_id, lock);
+        exec::sleep(LOCK_POLL_INTERVAL)?;
+    }
+}
+
+// Checked before each retry of a command that is not safe to repeat blindly, such as qm create.
+// Some(output) if the failed attempt took effect after all, which then counts as success.
+pub type Settled<'a> = &'a dyn Fn() -> Result<Option<String>>;
+
+// Every qm invocation goes through here so transient failures are retried the same way.
+// When vm_id is set a lock error waits for the lock to clear instead of sleeping blindly.
+// Only for commands that can be repeated as is: set, start, stop, config, resize.
+pub fn run_qm(operation: &str, vm_id: Option<u32>, args: &[String]) -> Result<String> {
+    run_qm_with_timeout(operation, vm_id, args, config::get().timeouts.qm())
+}
+
+pub fn run_qm_with_timeout(
+    operation: &str,
+    vm_id: Option<u32>,
+    args: &[String],
+    timeout: Duration,
+) -> Result<String> {
+    run_qm_with_policy(
+        operation,
+        vm_id,
+        args,
+        timeout,
+        &RetryPolicy::default(),
+        None,
+    )
+}
+
+pub fn run_qm_with_policy(
+    operation: &str,
+    vm_id: Option<u32>,
+    args: &[String],
+    timeout: Duration,
+    policy: &RetryPolicy,
+    settled: Option<Settled>,
+) -> Result<String> {
+    let mut attempt = 0;
+    loop {
+        let mut cmd = Command::new("qm");
+        cmd.args(args);
+        let output = exec::run(cmd, timeout)?;
+        if output.status.success() {
+            return Ok(String::from_utf8(output.stdout)?);
         }
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        let error = QMCommandError {
+            operation: operation.to_string(),
+            vm_id,
+            exit_code: output.status.code(),
+            kind: classify_qm_error(&stderr),
+            stderr,
+        };
+        attempt += 1;
+        if !error.kind.is_retryable() || attempt >= policy.max_attempts {
+            return Err(AppError::QMError(error));
+        }
+        warn!(
+            "qm {} failed ({:?}), attempt {}/{}: {}",
+            operation,
+            error.kind,
+            attempt,
+            policy.max_attempts,
+            error.stderr.trim()
+        );
+        match (error.kind, vm_id) {
+            (QMErrorKind::Locked, Some(id)) => wait_for_unlock(id, LOCK_WAIT_TIMEOUT)?,
+            _ => exec::sleep(policy.delay(attempt))?,
+        }
+        if let Some(settled) = settled
+            && let Some(output) = settled()?
+        {
+            info!(
+                "qm {} took effect despite the error, not retrying",
+                operation
+            );
+            return Ok(output);
+        }
+    }
+}
+
+// The raw config of a VM, None if it doesn't exist
+fn existing_config(vm_id: u32) -> Result<Option<String>> {
+    match qm_config(vm_id) {
+        Ok(raw) => Ok(Some(raw)),
+        Err(AppError::QMError(e)) if e.stderr.contains("does not exist") => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Volumes imported but not attached yet, as "unusedN:storage:volume"
+fn unused_disks(raw_config: &str) -> Vec<String> {
+    raw_config
+        .lines()
+        .filter(|line| line.starts_with("unused"))
+        .map(|line| line.replacen(": ", ":", 1))
+        .collect()
+}
+
+// Formats a startup order the way Proxmox expects it, e.g. "order=1,up=30,down=60"
+pub fn format_startup(startup: &StartupOrder) -> String {
+    [
+        startup.order.map(|v| format!("order={}", v)),
+        startup.up_secs.map(|v| format!("up={}", v)),
+        startup.down_secs.map(|v| format!("down={}", v)),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>()
+    .join(",")
+}
+
+pub fn qm_create(
+    config: &VMConfig,
+    nix_hash: &str,
+    commit_hash: &str,
+    owner_tag: &str,
+) -> Result<String> {
+    let mut args = vec![
+        "create".to_string(),
+        config.vm_id.to_string(),
+        "--name".to_string(),
+        config.name.clone(),
+        "--memory".to_string(),
+        config.memory_mb.to_string(),
+        "--cores".to_string(),
+        config.cores.to_string(),
+        "--net0".to_string(),
+        format!("virtio,bridge={}", config.network_bridge),
+        "--scsihw".to_string(),
+        config.scsi_hw.clone(),
+        "--tags".to_string(),
+        std::iter::once(format!(
+            "proxnix;{};nix-{};commit-{}",
+            owner_tag, nix_hash, commit_hash
+        ))
+        .chain(config.vm_id_allocated.then(|| alloc_tag(&config.name)))
+        .chain(config.tags.iter().cloned())
+        .collect::<Vec<_>>()
+        .join(";"),
+        "--onboot".to_string(),
+        u8::from(config.onboot).to_string(),
+    ];
+    if let Some(startup) = &config.startup {
+        args.push("--startup".to_string());
+        args.push(format_startup(startup));
+    }
+    // An attempt that timed out may still have created the VM, retrying would then fail
+    // with "already exists"
+    let created = || {
+        Ok(existing_config(config.vm_id)?
+            .and_then(|raw| parse_qm_config(&raw).ok())
+            .filter(|parsed| parsed.name == config.name)
+            .map(|_| String::new()))
+    };
+    run_qm_with_policy(
+        "create",
+        Some(config.vm_id),
+        &args,
+        config::get().timeouts.qm(),
+        &RetryPolicy::default(),
+        Some(&created),
+    )
+}
+
+// Asks the guest to power off via ACPI (or the agent if enabled) and waits up to timeout_secs.
+// Not retried, a failure here means the caller should escalate to qm_stop.
+// Returns false if the VM was not running.
+pub fn qm_shutdown(vm_id: u32, timeout_secs: u64) -> Result<bool> {
+    let result = run_qm_with_policy(
+        "shutdown",
+        Some(vm_id),
+        &[
+            "shutdown".to_string(),
+            vm_id.to_string(),
+            "--timeout".to_string(),
+            timeout_secs.to_string(),
+        ],
+        Duration::from_secs(timeout_secs) + config::get().timeouts.qm(),
+        &RetryPolicy::once(),
+        None,
+    );
+    match result {
+        Ok(_) => Ok(true),
+        Err(AppError::QMError(e)) if e.stderr.contains("not running") => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// Runs a command inside the guest through the qemu agent, returns its exit code
+pub fn qm_guest_exec(vm_id: u32, command: &[String], timeout_secs: u64) -> Result<i64> {
+    let mut args = vec![
+        "guest".to_string(),
+        "exec".to_string(),
+        vm_id.to_string(),
+        "--timeout".to_string(),
+        timeout_secs.to_string(),
+        "--".to_string(),
+    ];
+    args.extend(command.iter().cloned());
+    // The command may have run even if qm reports an error, so it is not repeated
+    let output = run_qm_with_policy(
+        "guest exec",
+        Some(vm_id),
+        &args,
+        Duration::from_secs(timeout_secs) + config::get().timeouts.qm(),
+        &RetryPolicy::once(),
+        None,
+    )?;
+    parse_guest_exec_output(&output)
+}
+
+// Output is JSON like: {"exitcode": 0, "exited": 1, "out-data": "..."}
+fn parse_guest_exec_output(output: &str) -> Result<i64> {
+    let parsed: serde_json::Value = serde_json::from_str(output)?;
+    if parsed.get("exited").and_then(|v| v.as_i64()) != Some(1) {
         return Err(AppError::CmdError(format!(
-            "qm stop has failed with exit code: {:?}: {}",
-            qm_stop.status.code(),
-            stderr
+            "guest command did not exit in time: {}",
+            output
         )));
     }
-    let stdout_bytes = qm_stop.stdout;
-    let output_string = String::from_utf8(stdout_bytes)?;
+    parsed
+        .get("exitcode")
+        .and_then(|v| v.as_i64())
+        .ok_or_else(|| AppError::CmdError(format!("no exitcode in guest exec output: {}", output)))
+}
 
-    Ok(output_string)
+pub fn qm_stop(vm_id: &u32) -> Result<String> {
+    match run_qm(
+        "stop",
+        Some(*vm_id),
+        &["stop".to_string(), vm_id.to_string()],
+    ) {
+        Err(AppError::QMError(e)) if e.stderr.contains("not running") => Ok(String::new()),
+        other => other,
+    }
 }
 
 // Parses output like: "Successfully imported disk as 'unused0:local-lvm:vm-100-disk-1'"
@@ -72,8 +370,8 @@ fn parse_importdisk_output(output: &str) -> Result<String> {
             // Output is like: "unused0:local-lvm:vm-100-disk-0"
             // Strip the "unusedN:" prefix to get just "local-lvm:vm-100-disk-0"
             let full_ref = &line[start + 1..end];
-            let disk_ref = full_ref.splitn(2, ':').nth(1)?.to_string();
-            Some(disk_ref)
+            let (_, disk_ref) = full_ref.split_once(':')?;
+            Some(disk_ref.to_string())
         })
         .ok_or_else(|| {
             AppError::CmdError(format!(
@@ -86,22 +384,32 @@ fn parse_importdisk_output(output: &str) -> Result<String> {
 }
 
 pub fn qm_importdisk(vm_id: u32, qcow_path: &str, storage: &str) -> Result<String> {
-    let qm_importdisk = Command::new("qm")
-        .arg("importdisk")
-        .arg(vm_id.to_string())
-        .arg(qcow_path.to_string())
-        .arg(storage.to_string())
-        .arg("--format=raw")
-        .output()?;
-    if !qm_importdisk.status.success() {
-        let stderr = String::from_utf8_lossy(&qm_importdisk.stderr);
-        return Err(AppError::CmdError(format!(
-            "qm importdisk has failed with exit code: {:?}: {}",
-            qm_importdisk.status.code(),
-            stderr
-        )));
-    }
-    let output_string = String::from_utf8(qm_importdisk.stdout)?;
+    // A failed attempt can leave the volume imported already, a retry would import a second
+    // one and orphan the first. A new unused volume on the VM means the import went through.
+    let before = unused_disks(&qm_config(vm_id)?);
+    let imported = || {
+        let Some(raw) = existing_config(vm_id)? else {
+            return Ok(None);
+        };
+        Ok(unused_disks(&raw)
+            .into_iter()
+            .find(|disk| !before.contains(disk))
+            .map(|disk| format!("Successfully imported disk as '{}'", disk)))
+    };
+    let output_string = run_qm_with_policy(
+        "importdisk",
+        Some(vm_id),
+        &[
+            "importdisk".to_string(),
+            vm_id.to_string(),
+            qcow_path.to_string(),
+            storage.to_string(),
+            "--format=raw".to_string(),
+        ],
+        config::get().timeouts.qm_importdisk(),
+        &RetryPolicy::default(),
+        Some(&imported),
+    )?;
     let disk_id = parse_importdisk_output(&output_string)?;
     // Some Proxmox versions omit the storage name in the output (e.g. "vm-823-disk-0")
     // while others include it (e.g. "local-lvm:vm-823-disk-0"). Normalise to always
@@ -116,187 +424,250 @@ pub fn qm_importdisk(vm_id: u32, qcow_path: &str, storage: &str) -> Result<Strin
 }
 
 pub fn qm_set_disk(vm_id: u32, disk_ref: &str, disk_slot: &str) -> Result<String> {
-    let qm_set_disk = Command::new("qm")
-        .arg("set")
-        .arg(vm_id.to_string())
-        .arg(format!("--{}", disk_slot))
-        .arg(disk_ref)
-        .arg("--boot")
-        .arg(format!("order={}", disk_slot))
-        .output()?;
-    if !qm_set_disk.status.success() {
-        let stderr = String::from_utf8_lossy(&qm_set_disk.stderr);
-        return Err(AppError::CmdError(format!(
-            "qm set disk has failed with the exit code: {:?}: {}",
-            qm_set_disk.status.code(),
-            stderr
-        )));
-    }
-    let stdout_bytes = qm_set_disk.stdout;
-    let output_string = String::from_utf8(stdout_bytes)?;
+    run_qm(
+        "set disk",
+        Some(vm_id),
+        &[
+            "set".to_string(),
+            vm_id.to_string(),
+            format!("--{}", disk_slot),
+            disk_ref.to_string(),
+            "--boot".to_string(),
+            format!("order={}", disk_slot),
+        ],
+    )
+}
 
-    Ok(output_string)
+pub fn qm_set_tags(vm_id: u32, tags: &[String]) -> Result<String> {
+    run_qm(
+        "set tags",
+        Some(vm_id),
+        &[
+            "set".to_string(),
+            vm_id.to_string(),
+            "--tags".to_string(),
+            tags.join(";"),
+        ],
+    )
 }
 //TODO MAYBE add something other than socket as the serial console, bit of a nitpick
 pub fn qm_set_agent(vm_id: u32) -> Result<String> {
-    let qm_set_agent = Command::new("qm")
-        .arg("set")
-        .arg(vm_id.to_string())
-        .arg("--agent")
-        .arg("1")
-        .arg("--serial0")
-        .arg("socket")
-        .output()?;
-    if !qm_set_agent.status.success() {
-        let stderr = String::from_utf8_lossy(&qm_set_agent.stderr);
-        return Err(AppError::CmdError(format!(
-            "qm set agent has failed with the exit code: {:?}: {}",
-            qm_set_agent.status.code(),
-            stderr
-        )));
-    }
-    let stdout_bytes = qm_set_agent.stdout;
-    let output_string = String::from_utf8(stdout_bytes)?;
-
-    Ok(output_string)
-}
-
-pub fn qm_template(vm_id: u32) -> Result<String> {
-    let qm_template = Command::new("qm")
-        .arg("template")
-        .arg(vm_id.to_string())
-        .output()?;
-    if !qm_template.status.success() {
-        let stderr = String::from_utf8_lossy(&qm_template.stderr);
-        return Err(AppError::CmdError(format!(
-            "qm template has failed with the exit code: {:?}: {}",
-            qm_template.status.code(),
-            stderr
-        )));
-    }
-    let stdout_bytes = qm_template.stdout;
-    let output_string = String::from_utf8(stdout_bytes)?;
-
-    Ok(output_string)
-}
-
-pub fn qm_clone(source_vm_id: u32, dest_vm_id: u32, name: &str) -> Result<String> {
-    let qm_clone = Command::new("qm")
-        .arg("clone")
-        .arg(source_vm_id.to_string())
-        .arg(dest_vm_id.to_string())
-        .arg("--name")
-        .arg(name.to_string())
-        .arg("--full")
-        .arg("0")
-        .output()?;
-    if !qm_clone.status.success() {
-        let stderr = String::from_utf8_lossy(&qm_clone.stderr);
-        return Err(AppError::CmdError(format!(
-            "qm clone has failed with the exit code: {:?}: {}",
-            qm_clone.status.code(),
-            stderr
-        )));
-    }
-    let stdout_bytes = qm_clone.stdout;
-    let output_string = String::from_utf8(stdout_bytes)?;
-
-    Ok(output_string)
+    run_qm(
+        "set agent",
+        Some(vm_id),
+        &[
+            "set".to_string(),
+            vm_id.to_string(),
+            "--agent".to_string(),
+            "1".to_string(),
+            "--serial0".to_string(),
+            "socket".to_string(),
+        ],
+    )
 }
 
 pub fn qm_start(vm_id: u32) -> Result<bool> {
-    let output = Command::new("qm")
-        .arg("start")
-        .arg(vm_id.to_string())
-        .output()?;
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        if stderr.contains("already running") {
-            return Ok(false);
-        }
-        return Err(AppError::CmdError(format!(
-            "qm start {} failed (exit: {:?}): {}",
-            vm_id,
-            output.status.code(),
-            stderr
-        )));
+    match run_qm(
+        "start",
+        Some(vm_id),
+        &["start".to_string(), vm_id.to_string()],
+    ) {
+        Ok(_) => Ok(true),
+        Err(AppError::QMError(e)) if e.stderr.contains("already running") => Ok(false),
+        Err(e) => Err(e),
     }
-    Ok(true)
 }
 
 pub fn qm_destroy(vm_id: u32) -> Result<String> {
-    let qm_destroy = Command::new("qm")
-        .arg("destroy")
-        .arg(vm_id.to_string())
-        .output()?;
-    if !qm_destroy.status.success() {
-        let stderr = String::from_utf8_lossy(&qm_destroy.stderr);
-        return Err(AppError::CmdError(format!(
-            "qm destroy has failed with the exit code: {:?}: {}",
-            qm_destroy.status.code(),
-            stderr
-        )));
-    }
-    let stdout_bytes = qm_destroy.stdout;
-    let output_string = String::from_utf8(stdout_bytes)?;
-
-    Ok(output_string)
+    // Retried only while the VM still exists, a timed out attempt may have removed it
+    let gone = || Ok(existing_config(vm_id)?.is_none().then(String::new));
+    run_qm_with_policy(
+        "destroy",
+        Some(vm_id),
+        &["destroy".to_string(), vm_id.to_string()],
+        config::get().timeouts.qm(),
+        &RetryPolicy::default(),
+        Some(&gone),
+    )
 }
 
 pub fn qm_resize(vm_id: u32, disk_slot: &str, size_gb: u32) -> Result<String> {
-    let output = Command::new("qm")
-        .arg("disk")
-        .arg("resize")
-        .arg(vm_id.to_string())
-        .arg(disk_slot)
-        .arg(format!("{}G", size_gb))
-        .output()?;
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(AppError::CmdError(format!(
-            "qm disk resize failed (exit: {:?}): {}",
-            output.status.code(),
-            stderr
-        )));
-    }
-    Ok(String::from_utf8(output.stdout)?)
+    run_qm(
+        "disk resize",
+        Some(vm_id),
+        &[
+            "disk".to_string(),
+            "resize".to_string(),
+            vm_id.to_string(),
+            disk_slot.to_string(),
+            format!("{}G", size_gb),
+        ],
+    )
 }
 
 pub fn qm_set_resources(vm_id: u32, update: &VMUpdate) -> Result<String> {
-    let mut qm_set_resources = Command::new("qm");
-    qm_set_resources.arg("set");
-    qm_set_resources.arg(vm_id.to_string());
+    let mut args = vec!["set".to_string(), vm_id.to_string()];
 
     for field in &update.changed_fields {
         match field {
+            FieldChange::Name => {
+                args.push("--name".to_string());
+                args.push(update.config.name.clone());
+            }
             FieldChange::Memory => {
-                qm_set_resources
-                    .arg("--memory")
-                    .arg(update.config.memory_mb.to_string());
+                args.push("--memory".to_string());
+                args.push(update.config.memory_mb.to_string());
             }
             FieldChange::Cores => {
-                qm_set_resources
-                    .arg("--cores")
-                    .arg(update.config.cores.to_string());
+                args.push("--cores".to_string());
+                args.push(update.config.cores.to_string());
             }
             FieldChange::Sockets => {
-                qm_set_resources
-                    .arg("--sockets")
-                    .arg(update.config.sockets.to_string());
+                args.push("--sockets".to_string());
+                args.push(update.config.sockets.to_string());
+            }
+            FieldChange::OnBoot => {
+                args.push("--onboot".to_string());
+                args.push(u8::from(update.config.onboot).to_string());
             }
+            FieldChange::Network => {
+                // Keep the model and MAC, only swap the bridge
+                let net0 = match update.deployed.net0.as_deref() {
+                    Some(current) => current
+                        .split(',')
+                        .map(|part| {
+                            if part.trim().starts_with("bridge=") {
+                                format!("bridge={}", update.config.network_bridge)
+                            } else {
+                                part.to_string()
+                            }
+                        })
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    None => format!("virtio,bridge={}", update.config.network_bridge),
+                };
+                args.push("--net0".to_string());
+                args.push(net0);
+            }
+            FieldChange::Tags => {
+                let tags: Vec<String> = update
+                    .deployed
+                    .tags
+                    .iter()
+                    .filter(|tag| is_system_tag(tag))
+                    .chain(update.config.tags.iter())
+                    .cloned()
+                    .collect();
+                args.push("--tags".to_string());
+                args.push(tags.join(";"));
+            }
+            FieldChange::Startup => match &update.config.startup {
+                Some(startup) => {
+                    args.push("--startup".to_string());
+                    args.push(format_startup(startup));
+                }
+                None => {
+                    args.push("--delete".to_string());
+                    args.push("startup".to_string());
+                }
+            },
             _ => {}
         }
     }
-    let output = qm_set_resources.output()?;
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(AppError::CmdError(format!(
-            "qm set resources has failed with the exit code: {:?}: {}",
-            output.status.code(),
-            stderr
-        )));
+    // Power state changes alone don't touch the config
+    if args.len() == 2 {
+        return Ok(String::new());
     }
-    let output_string = String::from_utf8(output.stdout)?;
 
-    Ok(output_string)
+    run_qm("set resources", Some(vm_id), &args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_qm_error() {
+        assert_eq!(
+            classify_qm_error("VM is locked (backup)"),
+            QMErrorKind::Locked
+        );
+        assert_eq!(
+            classify_qm_error("VM 100 is locked (migrate)"),
+            QMErrorKind::Locked
+        );
+        assert_eq!(
+            classify_qm_error(
+                "can't lock file '/var/lock/qemu-server/lock-100.conf' - got timeout"
+            ),
+            QMErrorKind::Locked
+        );
+        assert_eq!(
+            classify_qm_error("cfs-lock 'storage-local-lvm' error: got lock request timeout"),
+            QMErrorKind::StorageBusy
+        );
+        assert_eq!(
+            classify_qm_error("VM 100 qmp command 'query-status' failed - got timeout"),
+            QMErrorKind::Timeout
+        );
+        // Mentioning busy or timeout isn't enough
+        assert_eq!(
+            classify_qm_error("storage 'local-lvm' is busy"),
+            QMErrorKind::Fatal
+        );
+        assert_eq!(
+            classify_qm_error("command 'lvcreate' failed: got timeout"),
+            QMErrorKind::Fatal
+        );
+        assert_eq!(
+            classify_qm_error("400 Parameter verification failed.\ntimeout: invalid format"),
+            QMErrorKind::Fatal
+        );
+    }
+
+    #[test]
+    fn test_unused_disks() {
+        let raw =
+            "name: web\nscsi0: local-lvm:vm-100-disk-0,size=20G\nunused0: local-lvm:vm-100-disk-1";
+        assert_eq!(unused_disks(raw), vec!["unused0:local-lvm:vm-100-disk-1"]);
+        assert_eq!(
+            parse_importdisk_output(&format!(
+                "Successfully imported disk as '{}'",
+                unused_disks(raw)[0]
+            ))
+            .unwrap(),
+            "local-lvm:vm-100-disk-1"
+        );
+    }
+
+    #[test]
+    fn test_parse_guest_exec_output() {
+        assert_eq!(
+            parse_guest_exec_output(r#"{"exitcode": 0, "exited": 1, "out-data": "drained\n"}"#)
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            parse_guest_exec_output(r#"{"exitcode": 1, "exited": 1, "err-data": "error"}"#)
+                .unwrap(),
+            1
+        );
+        assert!(parse_guest_exec_output(r#"{"exited": 0, "pid": 1234}"#).is_err());
+    }
+
+    #[test]
+    fn test_retry_delay_bounds() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(30),
+        };
+        for attempt in 0..10 {
+            let capped = Duration::from_secs(2)
+                .saturating_mul(2u32.saturating_pow(attempt))
+                .min(Duration::from_secs(30));
+            let delay = policy.delay(attempt);
+            assert!(delay >= capped / 2 && delay <= capped);
+        }
+    }
 }
@@ -0,0 +1,384 @@
+use crate::config::{self, RepoConfig};
+use crate::credentials::repo_key;
+use crate::deployments;
+use crate::state::is_legacy;
+use crate::types::{AppError, DeployedVM, DesiredState, Result};
+use std::collections::HashSet;
+
+pub const OWNER_TAG_PREFIX: &str = "owner-";
+
+// Each entry in git.repos is one environment with its own pipeline, deployment history and
+// VMs. The same URL can appear several times, e.g. main for prod and staging for staging.
+pub fn environment(repo: &RepoConfig) -> String {
+    match &repo.name {
+        Some(name) => name.clone(),
+        None => repo_key(&repo.url),
+    }
+}
+
+// A repo that isn't configured is deployed as an environment of its own, named after its URL
+pub fn unconfigured(repo_url: &str) -> RepoConfig {
+    RepoConfig {
+        url: repo_url.to_string(),
+        ..Default::default()
+    }
+}
+
+// Only while git.repos is empty. Once repos are listed any other URL is refused, /whlisten
+// takes pushes from anyone.
+fn accept_unconfigured(repos: &[RepoConfig], repo_url: &str) -> Option<RepoConfig> {
+    repos.is_empty().then(|| unconfigured(repo_url))
+}
+
+pub fn unconfigured_if_allowed(repo_url: &str) -> Option<RepoConfig> {
+    accept_unconfigured(&config::get().git.repos, repo_url)
+}
+
+pub fn find(environment_name: &str) -> Option<RepoConfig> {
+    config::get()
+        .git
+        .repos
+        .iter()
+        .find(|repo| environment(repo) == environment_name)
+        .cloned()
+}
+
+// Proxmox tags only allow [a-z0-9_+.-]
+pub fn tag_safe(value: &str) -> String {
+    value
+        .to_lowercase()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "_+.-".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+// Written on every VM an environment creates. Only VMs with it are ever changed or destroyed
+// by that environment, so controllers and environments sharing a cluster leave each other alone.
+pub fn owner_tag(environment: &str) -> String {
+    format!(
+        "{}{}-{}",
+        OWNER_TAG_PREFIX,
+        tag_safe(&config::get().controller_id),
+        tag_safe(environment)
+    )
+}
+
+// Filters are branch names ("main"), full refs ("refs/tags/v1") or either ending in * for a
+// prefix match ("release/*"). No filters means every ref.
+pub fn ref_matches(filters: &[String], git_ref: &str) -> bool {
+    if filters.is_empty() {
+        return true;
+    }
+    let branch = git_ref.strip_prefix("refs/heads/");
+    filters.iter().any(|filter| {
+        let matches = |name: &str| match filter.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == filter,
+        };
+        matches(git_ref) || branch.is_some_and(matches)
+    })
+}
+
+// Environments a push to repo_url on git_ref deploys to, None if the repo isn't accepted at
+// all. Pushes without a ref (some forges leave it out of tag or manual events) only go to
+// environments without filters.
+pub fn for_push(repo_url: &str, git_ref: Option<&str>) -> Option<Vec<RepoConfig>> {
+    push_targets(&config::get().git.repos, repo_url, git_ref)
+}
+
+fn push_targets(
+    repos: &[RepoConfig],
+    repo_url: &str,
+    git_ref: Option<&str>,
+) -> Option<Vec<RepoConfig>> {
+    let key = repo_key(repo_url);
+    let configured: Vec<&RepoConfig> = repos
+        .iter()
+        .filter(|repo| repo_key(&repo.url) == key)
+        .collect();
+    if configured.is_empty() {
+        return accept_unconfigured(repos, repo_url).map(|repo| vec![repo]);
+    }
+    Some(
+        configured
+            .into_iter()
+            .filter(|repo| match git_ref {
+                Some(git_ref) => ref_matches(&repo.refs, git_ref),
+                None => repo.refs.is_empty(),
+            })
+            .cloned()
+            .collect(),
+    )
+}
+
+// Environment names and the owner tags made from them have to be unique, and VM ID ranges
+// must not overlap
+pub fn validate(repos: &[RepoConfig]) -> std::result::Result<(), String> {
+    let mut names = HashSet::new();
+    let mut tags = HashSet::new();
+    for repo in repos {
+        let name = environment(repo);
+        if !names.insert(name.clone()) {
+            return Err(format!(
+                "Environment {} is configured twice, give the repos distinct names",
+                name
+            ));
+        }
+        if !tags.insert(tag_safe(&name)) {
+            return Err(format!(
+                "Environment {} has the same owner tag as another one, give the repos distinct names",
+                name
+            ));
+        }
+        if let Some((start, end)) = repo.vm_id_range
+            && start > end
+        {
+            return Err(format!(
+                "vm_id_range of {} starts after it ends: {}-{}",
+                name, start, end
+            ));
+        }
+    }
+    for (i, a) in repos.iter().enumerate() {
+        for b in &repos[i + 1..] {
+            if let (Some((a_start, a_end)), Some((b_start, b_end))) = (a.vm_id_range, b.vm_id_range)
+                && a_start <= b_end
+                && b_start <= a_end
+            {
+                return Err(format!(
+                    "vm_id_range of {} overlaps with {}",
+                    environment(a),
+                    environment(b)
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Which VMs an environment may create, change and destroy: those in its own VM ID range (all
+// if it has none), minus anything in another environment's range or last deployment. Of the
+// existing VMs it manages only those carrying its owner tag, plus legacy VMs it claims.
+#[derive(Debug, Clone, Default)]
+pub struct Scope {
+    pub environment: String,
+    pub owner_tag: String,
+    pub range: Option<(u32, u32)>,
+    pub foreign: Vec<(u32, u32)>,
+    pub foreign_ids: HashSet<u32>,
+    // IDs in this environment's desired state or last deployment
+    pub legacy_claims: HashSet<u32>,
+}
+
+impl Scope {
+    pub fn owns(&self, vm_id: u32) -> bool {
+        self.range
+            .is_none_or(|(start, end)| (start..=end).contains(&vm_id))
+            && !self
+                .foreign
+                .iter()
+                .any(|(start, end)| (*start..=*end).contains(&vm_id))
+            && !self.foreign_ids.contains(&vm_id)
+    }
+
+    pub fn manages(&self, vm: &DeployedVM) -> bool {
+        self.owns(vm.vm_id)
+            && (vm.tags.contains(&self.owner_tag)
+                || (is_legacy(vm) && self.legacy_claims.contains(&vm.vm_id)))
+    }
+
+    pub fn claim(&mut self, desired: &DesiredState) {
+        self.legacy_claims
+            .extend(desired.vms.values().map(|vm| vm.vm_id));
+    }
+
+    // Refuses the whole deployment if any VM falls outside the scope, before anything is touched
+    pub fn check_desired(&self, desired: &DesiredState) -> Result<()> {
+        let mut outside: Vec<String> = desired
+            .vms
+            .values()
+            .filter(|vm| !self.owns(vm.vm_id))
+            .map(|vm| format!("{} ({})", vm.name, vm.vm_id))
+            .collect();
+        if outside.is_empty() {
+            return Ok(());
+        }
+        outside.sort();
+        Err(AppError::OwnershipError(format!(
+            "{} may not manage {}: outside its vm_id_range or owned by another environment",
+            self.environment,
+            outside.join(", ")
+        )))
+    }
+}
+
+pub fn scope(repo: &RepoConfig) -> Result<Scope> {
+    let name = environment(repo);
+    let mut scope = Scope {
+        environment: name.clone(),
+        owner_tag: owner_tag(&name),
+        range: repo.vm_id_range,
+        ..Default::default()
+    };
+    scope.foreign = config::get()
+        .git
+        .repos
+        .iter()
+        .filter(|other| environment(other) != name)
+        .filter_map(|other| other.vm_id_range)
+        .collect();
+    let dir = &config::get().deployments_dir;
+    for other in deployments::environments(dir)? {
+        let Some(snapshot) = deployments::load_current(&deployments::environment_dir(dir, &other))?
+        else {
+            continue;
+        };
+        if other == name {
+            scope.claim(&snapshot.desired);
+        } else {
+            scope
+                .foreign_ids
+                .extend(snapshot.desired.vms.values().map(|vm| vm.vm_id));
+        }
+    }
+    Ok(scope)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::fixtures::{deployed_vm, vm_config};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_ref_matches() {
+        assert!(ref_matches(&[], "refs/heads/anything"));
+        let filters = vec!["main".to_string(), "release/*".to_string()];
+        assert!(ref_matches(&filters, "refs/heads/main"));
+        assert!(ref_matches(&filters, "refs/heads/release/1.2"));
+        assert!(!ref_matches(&filters, "refs/heads/feature"));
+        assert!(!ref_matches(&filters, "refs/tags/main-old"));
+        assert!(ref_matches(&["refs/tags/*".to_string()], "refs/tags/v1"));
+    }
+
+    #[test]
+    fn test_push_targets() {
+        let prod = RepoConfig {
+            url: "git@github.com:org/infra.git".to_string(),
+            name: Some("prod".to_string()),
+            refs: vec!["main".to_string()],
+            ..Default::default()
+        };
+        let names = |targets: Option<Vec<RepoConfig>>| {
+            targets.map(|repos| repos.iter().map(environment).collect::<Vec<_>>())
+        };
+        let url = "https://github.com/org/infra";
+        let configured = [prod];
+        assert_eq!(
+            names(push_targets(&configured, url, Some("refs/heads/main"))),
+            Some(vec!["prod".to_string()])
+        );
+        assert_eq!(
+            names(push_targets(&configured, url, Some("refs/heads/dev"))),
+            Some(Vec::new())
+        );
+        // Unlisted repos are refused once any repo is listed
+        let other = "https://github.com/someone/else";
+        assert_eq!(names(push_targets(&configured, other, None)), None);
+        assert_eq!(
+            names(push_targets(&[], other, None)),
+            Some(vec!["github.com_someone_else".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_validate_and_scope() {
+        let repo = |name: &str, range: Option<(u32, u32)>| RepoConfig {
+            url: "git@example.com:org/infra.git".to_string(),
+            name: Some(name.to_string()),
+            vm_id_range: range,
+            ..Default::default()
+        };
+        assert!(
+            validate(&[
+                repo("prod", Some((100, 199))),
+                repo("staging", Some((200, 299)))
+            ])
+            .is_ok()
+        );
+        assert!(
+            validate(&[
+                repo("prod", Some((100, 199))),
+                repo("staging", Some((150, 299)))
+            ])
+            .is_err()
+        );
+        assert!(validate(&[repo("prod", None), repo("prod", None)]).is_err());
+        assert!(validate(&[repo("Prod", None), repo("prod", None)]).is_err());
+
+        let scope = Scope {
+            environment: "staging".to_string(),
+            range: Some((200, 299)),
+            foreign: vec![(100, 199)],
+            foreign_ids: HashSet::from([250]),
+            ..Default::default()
+        };
+        assert!(scope.owns(200));
+        assert!(!scope.owns(150));
+        assert!(!scope.owns(250));
+        assert!(!scope.owns(300));
+
+        // Without a range of its own everything not claimed elsewhere is in scope
+        let open = Scope {
+            environment: "legacy".to_string(),
+            foreign: vec![(100, 199)],
+            ..Default::default()
+        };
+        assert!(open.owns(5000));
+        assert!(!open.owns(100));
+    }
+
+    #[test]
+    fn test_owner_tag_is_tag_safe() {
+        assert_eq!(owner_tag("Prod EU"), "owner-proxnix-prod_eu");
+        assert_eq!(
+            owner_tag("github.com_org_infra"),
+            "owner-proxnix-github.com_org_infra"
+        );
+    }
+
+    #[test]
+    fn test_scope_manages_owned_and_claimed_legacy() {
+        let mut scope = Scope {
+            environment: "prod".to_string(),
+            owner_tag: "owner-proxnix-prod".to_string(),
+            ..Default::default()
+        };
+        let mut owned = deployed_vm("web", 100);
+        owned.tags.push("owner-proxnix-prod".to_string());
+        let mut other = deployed_vm("db", 101);
+        other.tags.push("owner-proxnix-staging".to_string());
+        let legacy = deployed_vm("cache", 102);
+        assert!(scope.manages(&owned));
+        assert!(!scope.manages(&other));
+        assert!(!scope.manages(&legacy));
+
+        // A legacy VM is taken over once the environment's config claims its ID, never one
+        // that already has another owner
+        let desired = DesiredState {
+            vms: HashMap::from([
+                ("cache".to_string(), vm_config("cache", 102)),
+                ("db".to_string(), vm_config("db", 101)),
+            ]),
+        };
+        scope.claim(&desired);
+        assert!(scope.manages(&legacy));
+        assert!(!scope.manages(&other));
+    }
+}
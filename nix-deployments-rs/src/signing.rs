@@ -0,0 +1,271 @@
+use crate::config::{self, SigningConfig};
+use crate::exec;
+use crate::types::{AppError, Result};
+use git2::{Oid, Repository};
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tracing::info;
+
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SignatureKind {
+    Gpg,
+    Ssh,
+}
+
+pub fn signature_kind(signature: &str) -> Option<SignatureKind> {
+    let signature = signature.trim_start();
+    if signature.starts_with("-----BEGIN PGP SIGNATURE-----") {
+        Some(SignatureKind::Gpg)
+    } else if signature.starts_with("-----BEGIN SSH SIGNATURE-----") {
+        Some(SignatureKind::Ssh)
+    } else {
+        None
+    }
+}
+
+fn normalise_fingerprint(fingerprint: &str) -> String {
+    fingerprint.replace(' ', "").to_ascii_uppercase()
+}
+
+// Signing key and primary key fingerprints from gpg --status-fd output, only for a good signature
+pub fn parse_gpg_status(status: &str) -> Option<(String, String)> {
+    if !status.lines().any(|l| l.starts_with("[GNUPG:] GOODSIG ")) {
+        return None;
+    }
+    let fields: Vec<&str> = status
+        .lines()
+        .find(|l| l.starts_with("[GNUPG:] VALIDSIG "))?
+        .split_whitespace()
+        .collect();
+    let signing = fields.get(2)?.to_string();
+    let primary = fields.get(11).unwrap_or(&fields[2]).to_string();
+    Some((signing, primary))
+}
+
+fn untrusted(commit_hash: &str, reason: impl std::fmt::Display) -> AppError {
+    AppError::UntrustedCommit(format!("{}: {}", commit_hash, reason))
+}
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// The signature as a file for gpg and ssh-keygen, which want it as a path. It lives in a
+// fresh directory only root can enter, created with create_new so nothing planted in /tmp
+// (a symlink, a file of the same name) is followed or reused. Removed on drop.
+struct TempSignature(PathBuf);
+
+impl TempSignature {
+    fn create(signature: &str) -> Result<Self> {
+        let dir = std::env::temp_dir().join(format!(
+            "proxnix-sig-{}-{}",
+            std::process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+        let temp = TempSignature(dir.join("commit.sig"));
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&temp.0)?
+            .write_all(signature.as_bytes())?;
+        Ok(temp)
+    }
+}
+
+impl Drop for TempSignature {
+    fn drop(&mut self) {
+        if let Some(dir) = self.0.parent() {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+}
+
+fn verify_gpg(
+    policy: &SigningConfig,
+    commit_hash: &str,
+    signature: &TempSignature,
+    data: Vec<u8>,
+) -> Result<String> {
+    let mut cmd = Command::new("gpg");
+    cmd.arg("--homedir")
+        .arg(&policy.gpg_home)
+        .arg("--status-fd")
+        .arg("1")
+        .arg("--verify")
+        .arg(&signature.0)
+        .arg("-");
+    let output = exec::run_with_input(cmd, data, VERIFY_TIMEOUT)?;
+    let status = String::from_utf8_lossy(&output.stdout);
+    let Some((signing, primary)) = parse_gpg_status(&status).filter(|_| output.status.success())
+    else {
+        return Err(untrusted(
+            commit_hash,
+            format!(
+                "GPG signature did not verify against {}: {}",
+                policy.gpg_home,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    };
+    // Every key that ever got into the keyring would be trusted without an allowlist
+    if policy.allowed_gpg_fingerprints.is_empty() {
+        return Err(untrusted(
+            commit_hash,
+            format!(
+                "signed by GPG key {}, but signing.allowed_gpg_fingerprints is empty",
+                primary
+            ),
+        ));
+    }
+    let allowed: Vec<String> = policy
+        .allowed_gpg_fingerprints
+        .iter()
+        .map(|f| normalise_fingerprint(f))
+        .collect();
+    if !allowed.contains(&signing) && !allowed.contains(&primary) {
+        return Err(untrusted(
+            commit_hash,
+            format!(
+                "signed by GPG key {} which is not in the allowlist",
+                primary
+            ),
+        ));
+    }
+    Ok(format!("GPG key {}", primary))
+}
+
+fn verify_ssh(
+    policy: &SigningConfig,
+    commit_hash: &str,
+    signature: &TempSignature,
+    data: Vec<u8>,
+) -> Result<String> {
+    let mut find = Command::new("ssh-keygen");
+    find.arg("-Y")
+        .arg("find-principals")
+        .arg("-f")
+        .arg(&policy.ssh_allowed_signers)
+        .arg("-s")
+        .arg(&signature.0);
+    let found = exec::run(find, VERIFY_TIMEOUT)?;
+    let principals = String::from_utf8_lossy(&found.stdout);
+    let Some(principal) = principals.lines().next().filter(|_| found.status.success()) else {
+        return Err(untrusted(
+            commit_hash,
+            format!("SSH signing key is not in {}", policy.ssh_allowed_signers),
+        ));
+    };
+
+    let mut verify = Command::new("ssh-keygen");
+    verify
+        .arg("-Y")
+        .arg("verify")
+        .arg("-f")
+        .arg(&policy.ssh_allowed_signers)
+        .arg("-I")
+        .arg(principal)
+        .arg("-n")
+        .arg("git")
+        .arg("-s")
+        .arg(&signature.0);
+    let output = exec::run_with_input(verify, data, VERIFY_TIMEOUT)?;
+    if !output.status.success() {
+        return Err(untrusted(
+            commit_hash,
+            format!(
+                "SSH signature by {} did not verify: {}",
+                principal,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+    Ok(format!("SSH principal {}", principal))
+}
+
+// Checks the commit carries a signature from an allowed key, returns who signed it.
+// Runs against the mirror before the commit is checked out, see trusted().
+pub fn verify_commit(repo: &Repository, commit_hash: &str) -> Result<String> {
+    let policy = &config::get().signing;
+    let oid = Oid::from_str(commit_hash)?;
+    let (signature, data) = repo
+        .extract_signature(&oid, None)
+        .map_err(|_| untrusted(commit_hash, "commit is not signed"))?;
+    let signature = String::from_utf8_lossy(&signature).to_string();
+    let kind = signature_kind(&signature)
+        .ok_or_else(|| untrusted(commit_hash, "unrecognised signature format"))?;
+
+    let temp = TempSignature::create(&signature)?;
+    let signer = match kind {
+        SignatureKind::Gpg => verify_gpg(policy, commit_hash, &temp, data.to_vec())?,
+        SignatureKind::Ssh => verify_ssh(policy, commit_hash, &temp, data.to_vec())?,
+    };
+    info!("Commit {} is signed by {}", commit_hash, signer);
+    Ok(signer)
+}
+
+// Verify callback for git_ensure_commit, a no-op unless signing.required is set
+pub fn trusted(commit_hash: &str) -> impl Fn(&Repository) -> Result<()> + '_ {
+    move |repo| {
+        if config::get().signing.required {
+            let signer = verify_commit(repo, commit_hash)?;
+            exec::log_line(&format!("Commit {} signed by {}", commit_hash, signer));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_temp_signature_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let temp = TempSignature::create("-----BEGIN SSH SIGNATURE-----").unwrap();
+        let dir = temp.0.parent().unwrap().to_path_buf();
+        let mode =
+            |path: &std::path::Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&dir), 0o700);
+        assert_eq!(mode(&temp.0), 0o600);
+        assert_eq!(
+            std::fs::read_to_string(&temp.0).unwrap(),
+            "-----BEGIN SSH SIGNATURE-----"
+        );
+        drop(temp);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_signature_kind() {
+        assert_eq!(
+            signature_kind("-----BEGIN PGP SIGNATURE-----\n\niQ..."),
+            Some(SignatureKind::Gpg)
+        );
+        assert_eq!(
+            signature_kind("-----BEGIN SSH SIGNATURE-----\nU1NI..."),
+            Some(SignatureKind::Ssh)
+        );
+        assert_eq!(signature_kind("garbage"), None);
+    }
+
+    #[test]
+    fn test_parse_gpg_status() {
+        let good = "[GNUPG:] NEWSIG\n\
+            [GNUPG:] GOODSIG 1234567890ABCDEF Alice <alice@example.com>\n\
+            [GNUPG:] VALIDSIG SUBKEYFPR 2024-01-01 1704067200 0 4 0 22 10 00 PRIMARYFPR\n";
+        assert_eq!(
+            parse_gpg_status(good),
+            Some(("SUBKEYFPR".to_string(), "PRIMARYFPR".to_string()))
+        );
+        let expired = "[GNUPG:] EXPKEYSIG 1234567890ABCDEF Alice\n\
+            [GNUPG:] VALIDSIG SUBKEYFPR 2024-01-01 1704067200 0 4 0 22 10 00 PRIMARYFPR\n";
+        assert_eq!(parse_gpg_status(expired), None);
+        assert_eq!(normalise_fingerprint("abcd 1234"), "ABCD1234");
+    }
+}
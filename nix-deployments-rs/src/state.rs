@@ -1,45 +1,24 @@
+use crate::alloc::ALLOC_TAG_PREFIX;
+use crate::qm::{qm_set_tags, run_qm};
+use crate::repos::{OWNER_TAG_PREFIX, Scope};
 use crate::types::{
-    AppError, DeployedState, DeployedVM, DesiredState, FieldChange, QMConfig, QMList, Result,
-    StateDiff, UpdateAction, VMConfig, VMUpdate,
+    AppError, DeployedState, DeployedVM, DesiredState, FieldChange, PowerState, QMConfig, QMList,
+    Result, StartupOrder, StateDiff, UpdateAction, VMConfig, VMUpdate,
 };
-use std::collections::HashMap;
-use std::process::Command;
+use std::collections::{HashMap, HashSet};
+use tracing::{info, warn};
 
 pub fn parse_vm_config(json: &str) -> Result<DesiredState> {
-    let state: DesiredState = serde_json::from_str(&json)?;
+    let state: DesiredState = serde_json::from_str(json)?;
     Ok(state)
 }
 
 pub fn qm_list() -> Result<String> {
-    let qm_list = Command::new("qm").arg("list").output()?;
-    if !qm_list.status.success() {
-        return Err(AppError::CmdError(format!(
-            "qm list has failed with exit code: {:?}",
-            qm_list.status.code()
-        )));
-    }
-    let stdout_bytes = qm_list.stdout;
-    let output_string = String::from_utf8(stdout_bytes)?;
-
-    Ok(output_string)
+    run_qm("list", None, &["list".to_string()])
 }
 
 pub fn qm_config(vm_id: u32) -> Result<String> {
-    let qm_config = Command::new("qm")
-        .arg("config")
-        .arg(vm_id.to_string())
-        .output()?;
-    if !qm_config.status.success() {
-        return Err(AppError::CmdError(format!(
-            "qm config has failed with exit code: {:?}",
-            qm_config.status.code()
-        )));
-    }
-
-    let stdout_bytes = qm_config.stdout;
-    let output_string = String::from_utf8(stdout_bytes)?;
-
-    Ok(output_string)
+    run_qm("config", None, &["config".to_string(), vm_id.to_string()])
 }
 
 pub fn parse_qm_config(output_string: &str) -> Result<QMConfig> {
@@ -57,6 +36,7 @@ pub fn parse_qm_config(output_string: &str) -> Result<QMConfig> {
                 "bootdisk" => accumulator.bootdisk = value.parse().unwrap(),
                 "cipassword" => accumulator.cipassword = Some(value.to_string()),
                 "ciuser" => accumulator.ciuser = Some(value.to_string()),
+                "lock" => accumulator.lock = Some(value.to_string()),
                 "cores" => accumulator.cores = value.parse().unwrap(),
                 "cpu" => accumulator.cpu = value.parse().unwrap(),
                 "cpuunits" => accumulator.cpuunits = value.parse().unwrap(),
@@ -68,6 +48,7 @@ pub fn parse_qm_config(output_string: &str) -> Result<QMConfig> {
                 "protection" => accumulator.protection = value.parse().unwrap(),
                 "sockets" => accumulator.sockets = value.parse().unwrap(),
                 "sshkeys" => accumulator.sshkeys = Some(value.to_string()),
+                "startup" => accumulator.startup = Some(value.to_string()),
                 "tags" => accumulator.tags = Some(value.to_string()),
                 "vga" => accumulator.vga = value.parse().unwrap(),
                 "vmgenid" => accumulator.vmgenid = value.parse().unwrap(),
@@ -101,7 +82,7 @@ pub fn parse_qm_config(output_string: &str) -> Result<QMConfig> {
 }
 
 pub fn parse_qm_list(output_string: &str) -> Result<Vec<QMList>> {
-    let lines = output_string
+    output_string
         .lines()
         .skip(1)
         .map(|line| -> Result<QMList> {
@@ -125,9 +106,53 @@ pub fn parse_qm_list(output_string: &str) -> Result<Vec<QMList>> {
                 pid: col(5)?.parse()?,
             })
         })
-        .collect();
+        .collect()
+}
+
+// Tags as written on the VM, in order
+pub fn config_tags(parsed: &QMConfig) -> Vec<String> {
+    parsed
+        .tags
+        .as_deref()
+        .map(|t| {
+            t.split(';')
+                .map(|tag| tag.trim().to_string())
+                .filter(|tag| !tag.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Fills in what qm list doesn't show from the VM's qm config
+pub fn with_config(vm: DeployedVM, parsed: &QMConfig) -> DeployedVM {
+    let tags = config_tags(parsed);
+    let nix_hash = tags
+        .iter()
+        .find_map(|tag| tag.strip_prefix("nix-"))
+        .map(|hash| hash.to_string());
+    DeployedVM {
+        nix_hash,
+        cores: parsed.cores as u16,
+        sockets: parsed.sockets,
+        onboot: parsed.onboot == 1,
+        startup: parsed.startup.as_deref().map(parse_startup),
+        net0: parsed.networks.get("net0").cloned(),
+        tags,
+        storage: boot_disk(parsed)
+            .and_then(|disk| disk.split_once(':').map(|(storage, _)| storage.to_string())),
+        ..vm
+    }
+}
 
-    lines
+// boot is "order=scsi0;net0" on current Proxmox, older VMs only have bootdisk
+fn boot_disk(parsed: &QMConfig) -> Option<&String> {
+    let slot = parsed
+        .boot
+        .strip_prefix("order=")
+        .and_then(|order| order.split(';').next())
+        .filter(|slot| !slot.is_empty())
+        .unwrap_or(&parsed.bootdisk);
+    parsed.disks.get(slot)
 }
 
 pub fn enrich_cpu_info(deployed: DeployedState) -> Result<DeployedState> {
@@ -135,123 +160,240 @@ pub fn enrich_cpu_info(deployed: DeployedState) -> Result<DeployedState> {
     for (_name, vm) in deployed.vms {
         let config = qm_config(vm.vm_id)?;
         let parsed = parse_qm_config(&config)?;
-        let is_proxnix = parsed
-            .tags
-            .as_deref()
-            .map(|t| t.split(';').any(|tag| tag.trim() == "proxnix"))
-            .unwrap_or(false);
-        if !is_proxnix {
+        let vm = with_config(vm, &parsed);
+        if !vm.tags.iter().any(|tag| tag == "proxnix") {
             continue;
         }
-        let nix_hash = parsed.tags.as_deref().and_then(|t| {
-            t.split(';')
-                .find(|tag| tag.trim().starts_with("nix-"))
-                .map(|tag| tag.trim().trim_start_matches("nix-").to_string())
-        });
-        deployedvms.insert(
-            vm.vm_name.clone(),
-            DeployedVM {
-                vm_id: vm.vm_id,
-                vm_name: vm.vm_name,
-                nix_hash,
-                template_id: vm.template_id,
-                mem_mb: vm.mem_mb,
-                bootdisk_gb: vm.bootdisk_gb,
-                status: vm.status,
-                pid: vm.pid,
-                cores: parsed.cores as u16,
-                sockets: parsed.sockets,
-            },
-        );
+        deployedvms.insert(vm.vm_id, vm);
     }
     Ok(DeployedState { vms: deployedvms })
 }
 
+// Tags proxnix writes itself, everything else on a managed VM comes from VMConfig.tags
+pub fn is_system_tag(tag: &str) -> bool {
+    tag == "proxnix"
+        || tag.starts_with("nix-")
+        || tag.starts_with("commit-")
+        || tag.starts_with(OWNER_TAG_PREFIX)
+        || tag.starts_with(ALLOC_TAG_PREFIX)
+}
+
+// VMs created before ownership tags existed carry "proxnix" but no owner
+pub fn is_legacy(vm: &DeployedVM) -> bool {
+    !vm.tags.iter().any(|tag| tag.starts_with(OWNER_TAG_PREFIX))
+}
+
+// Gives legacy VMs the environment claims their owner tag, so from then on they are managed
+// like any VM it created. VMs nobody claims are left alone and reported.
+pub fn adopt_legacy(scope: &Scope) -> Result<Vec<String>> {
+    let mut adopted = Vec::new();
+    for vm in load_state()?.vms.into_values().filter(is_legacy) {
+        // Another environment's VMs are its own business
+        if !scope.owns(vm.vm_id) {
+            continue;
+        }
+        if !scope.manages(&vm) {
+            warn!(
+                "{} ({}) is tagged proxnix but has no owner, {} does not claim it",
+                vm.vm_name, vm.vm_id, scope.environment
+            );
+            continue;
+        }
+        info!(
+            "Adopting {} ({}) into {}",
+            vm.vm_name, vm.vm_id, scope.environment
+        );
+        let tags: Vec<String> = vm
+            .tags
+            .iter()
+            .cloned()
+            .chain(std::iter::once(scope.owner_tag.clone()))
+            .collect();
+        qm_set_tags(vm.vm_id, &tags)?;
+        adopted.push(vm.vm_name);
+    }
+    Ok(adopted)
+}
+
+pub fn user_tags(tags: &[String]) -> Vec<String> {
+    let mut user: Vec<String> = tags
+        .iter()
+        .filter(|tag| !is_system_tag(tag))
+        .cloned()
+        .collect();
+    user.sort();
+    user.dedup();
+    user
+}
+
+// Pulls the bridge out of a net0 value like "virtio=BC:24:11:00:00:01,bridge=vmbr0,firewall=1"
+pub fn net_bridge(net: &str) -> Option<&str> {
+    net.split(',')
+        .find_map(|part| part.trim().strip_prefix("bridge="))
+}
+
+// Parses Proxmox's startup option, e.g. "order=1,up=30,down=60"
+pub fn parse_startup(value: &str) -> StartupOrder {
+    value
+        .split(',')
+        .filter_map(|part| part.split_once('='))
+        .fold(StartupOrder::default(), |mut startup, (key, value)| {
+            let value = value.trim().parse().ok();
+            match key.trim() {
+                "order" => startup.order = value,
+                "up" => startup.up_secs = value,
+                "down" => startup.down_secs = value,
+                _ => {}
+            }
+            startup
+        })
+}
+
+pub fn from_qm_list(qmlist: QMList) -> DeployedVM {
+    DeployedVM {
+        vm_id: qmlist.vm_id,
+        vm_name: qmlist.name,
+        nix_hash: None,
+        template_id: None,
+        mem_mb: qmlist.mem_mb,
+        bootdisk_gb: qmlist.bootdisk_gb,
+        status: qmlist.status,
+        pid: qmlist.pid,
+        cores: 0,   //placeholder
+        sockets: 0, //placeholder
+        onboot: false,
+        startup: None,
+        net0: None,
+        tags: Vec::new(),
+        storage: None,
+    }
+}
+
+// Keyed by VM ID, names are neither unique nor stable
 pub fn list_to_deployed_vm(qmlists: Vec<QMList>) -> DeployedState {
     let lists = qmlists
         .into_iter()
-        .map(|qmlist| -> (String, DeployedVM) {
-            (
-                qmlist.name.clone(),
-                DeployedVM {
-                    vm_id: qmlist.vm_id,
-                    vm_name: qmlist.name,
-                    nix_hash: None,
-                    template_id: None,
-                    mem_mb: qmlist.mem_mb,
-                    bootdisk_gb: qmlist.bootdisk_gb,
-                    status: qmlist.status,
-                    pid: qmlist.pid,
-                    cores: 0,   //placeholder
-                    sockets: 0, //placeholder
-                },
-            )
-        })
+        .map(|qmlist| (qmlist.vm_id, from_qm_list(qmlist)))
         .collect();
 
     DeployedState { vms: lists }
 }
 
+fn field_changes(
+    vmconfig: &VMConfig,
+    deployed_vm: &DeployedVM,
+    image_hashes: &HashMap<String, String>,
+) -> Vec<FieldChange> {
+    let mut changes = Vec::new();
+    if vmconfig.name != deployed_vm.vm_name {
+        changes.push(FieldChange::Name);
+    }
+    if vmconfig.memory_mb != deployed_vm.mem_mb {
+        changes.push(FieldChange::Memory);
+    }
+    if vmconfig.disk_gb > deployed_vm.bootdisk_gb.round() as u32 {
+        changes.push(FieldChange::Disk);
+    }
+    if vmconfig.cores != deployed_vm.cores {
+        changes.push(FieldChange::Cores);
+    }
+    if vmconfig.sockets != deployed_vm.sockets {
+        changes.push(FieldChange::Sockets);
+    }
+    let is_running = deployed_vm.status == "running";
+    if (vmconfig.power_state == PowerState::Running) != is_running {
+        changes.push(FieldChange::PowerState);
+    }
+    if vmconfig.onboot != deployed_vm.onboot {
+        changes.push(FieldChange::OnBoot);
+    }
+    if vmconfig.startup != deployed_vm.startup {
+        changes.push(FieldChange::Startup);
+    }
+    if deployed_vm
+        .net0
+        .as_deref()
+        .and_then(net_bridge)
+        .is_some_and(|bridge| bridge != vmconfig.network_bridge)
+    {
+        changes.push(FieldChange::Network);
+    }
+    if user_tags(&deployed_vm.tags) != user_tags(&vmconfig.tags) {
+        changes.push(FieldChange::Tags);
+    }
+    let desired_nix_hash = image_hashes.get(&vmconfig.image_type).map(|s| s.as_str());
+    if desired_nix_hash
+        .zip(deployed_vm.nix_hash.as_deref())
+        .map(|(desired, deployed)| desired != deployed)
+        .unwrap_or(true)
+    {
+        changes.push(FieldChange::Image);
+    }
+    changes
+}
 
-pub fn diff_state(deployed: &DeployedState, desired: &DesiredState, image_hashes: &HashMap<String, String>) -> StateDiff {
+// Desired VMs are matched to deployed ones by VM ID, so a changed name is a rename in place.
+// A VM whose ID changed is matched by name instead, as long as no config still uses its old
+// ID, and has to be rebuilt under the new one.
+pub fn diff_state(
+    deployed: &DeployedState,
+    desired: &DesiredState,
+    image_hashes: &HashMap<String, String>,
+) -> StateDiff {
     let mut to_create: Vec<VMConfig> = Vec::new();
     let mut to_update: Vec<VMUpdate> = Vec::new();
-    let mut to_delete: Vec<DeployedVM> = Vec::new();
+    let mut matched: HashSet<u32> = HashSet::new();
+    let desired_ids: HashSet<u32> = desired.vms.values().map(|vm| vm.vm_id).collect();
 
     for (name, vmconfig) in &desired.vms {
-        let mut changes = Vec::new();
-
-        if deployed.vms.contains_key(name) {
-            let deployed_vm = deployed.vms.get(name).unwrap();
-            if vmconfig.memory_mb != deployed_vm.mem_mb {
-                changes.push(FieldChange::Memory);
-            }
-            if vmconfig.disk_gb > deployed_vm.bootdisk_gb.round() as u32 {
-                changes.push(FieldChange::Disk);
-            }
-            if vmconfig.cores != deployed_vm.cores {
-                changes.push(FieldChange::Cores);
-            }
-            if vmconfig.sockets != deployed_vm.sockets {
-                changes.push(FieldChange::Sockets);
+        let (deployed_vm, mut changes) = match deployed.vms.get(&vmconfig.vm_id) {
+            Some(deployed_vm) => (deployed_vm, Vec::new()),
+            None => {
+                let mut by_name = deployed
+                    .vms
+                    .values()
+                    .filter(|vm| vm.vm_name == vmconfig.name && !desired_ids.contains(&vm.vm_id));
+                match (by_name.next(), by_name.next()) {
+                    (Some(deployed_vm), None) => (deployed_vm, vec![FieldChange::VmId]),
+                    // Several old VMs with that name, none of them is obviously this one
+                    _ => {
+                        to_create.push(vmconfig.clone());
+                        continue;
+                    }
+                }
             }
-            let desired_nix_hash = image_hashes.get(&vmconfig.image_type).map(|s| s.as_str());
-            if desired_nix_hash
-                .zip(deployed_vm.nix_hash.as_deref())
-                .map(|(desired, deployed)| desired != deployed)
-                .unwrap_or(true)
+        };
+        matched.insert(deployed_vm.vm_id);
+        changes.extend(field_changes(vmconfig, deployed_vm, image_hashes));
+        if !changes.is_empty() {
+            let action = if vmconfig.protected {
+                UpdateAction::Protected
+            } else if changes.contains(&FieldChange::Disk)
+                || changes.contains(&FieldChange::Image)
+                || changes.contains(&FieldChange::VmId)
             {
-                changes.push(FieldChange::Image);
-            }
-            if !changes.is_empty() {
-                let action = if vmconfig.protected {
-                    UpdateAction::Protected
-                } else if changes.contains(&FieldChange::Disk)
-                    || changes.contains(&FieldChange::Image)
-                {
-                    UpdateAction::Rebuild
-                } else {
-                    UpdateAction::InPlace
-                };
-
-                to_update.push(VMUpdate {
-                    name: name.clone(),
-                    config: vmconfig.clone(),
-                    changed_fields: changes,
-                    required_action: action,
-                });
-            }
-        } else {
-            to_create.push(vmconfig.clone())
-        }
-    }
+                UpdateAction::Rebuild
+            } else {
+                UpdateAction::InPlace
+            };
 
-    for (name, deployed_vm) in &deployed.vms {
-        if !desired.vms.contains_key(name) {
-            to_delete.push(deployed_vm.clone())
+            to_update.push(VMUpdate {
+                name: name.clone(),
+                config: vmconfig.clone(),
+                deployed: deployed_vm.clone(),
+                changed_fields: changes,
+                required_action: action,
+            });
         }
     }
 
+    let to_delete = deployed
+        .vms
+        .values()
+        .filter(|vm| !matched.contains(&vm.vm_id))
+        .cloned()
+        .collect();
+
     StateDiff {
         to_create,
         to_update,
@@ -259,6 +401,33 @@ pub fn diff_state(deployed: &DeployedState, desired: &DesiredState, image_hashes
     }
 }
 
+// Desired VM IDs already taken by a VM the environment doesn't manage, which qm create would
+// fail on halfway through a deployment. Checked before anything is touched.
+pub fn check_collisions(
+    desired: &DesiredState,
+    existing: &HashMap<u32, String>,
+    managed: &DeployedState,
+    scope: &Scope,
+) -> Result<()> {
+    let mut taken: Vec<String> = desired
+        .vms
+        .values()
+        .filter(|vm| !managed.vms.contains_key(&vm.vm_id))
+        .filter_map(|vm| {
+            let other = existing.get(&vm.vm_id)?;
+            Some(format!("{} ({}, taken by {})", vm.name, vm.vm_id, other))
+        })
+        .collect();
+    if taken.is_empty() {
+        return Ok(());
+    }
+    taken.sort();
+    Err(AppError::OwnershipError(format!(
+        "VM IDs are used by VMs {} does not manage: {}. Import those VMs or pick other IDs",
+        scope.environment,
+        taken.join(", ")
+    )))
+}
 
 pub fn get_vm_statuses() -> Result<HashMap<u32, String>> {
     let raw = qm_list()?;
@@ -275,10 +444,22 @@ pub fn load_state() -> Result<DeployedState> {
     Ok(enriched)
 }
 
-
-pub fn full_diff(desired: &DesiredState, image_hashes: &HashMap<String, String>) -> Result<StateDiff> {
-    let deployed = load_state()?;
-    let diff = diff_state(&deployed, &desired, image_hashes);
+pub fn full_diff(
+    desired: &DesiredState,
+    image_hashes: &HashMap<String, String>,
+    scope: &Scope,
+) -> Result<StateDiff> {
+    let listed = parse_qm_list(&qm_list()?)?;
+    let existing: HashMap<u32, String> = listed
+        .iter()
+        .map(|vm| (vm.vm_id, vm.name.clone()))
+        .collect();
+    let mut deployed = enrich_cpu_info(list_to_deployed_vm(listed))?;
+    // VMs of other environments and controllers are invisible here, so they are never updated
+    // or destroyed
+    deployed.vms.retain(|_, vm| scope.manages(vm));
+    check_collisions(desired, &existing, &deployed, scope)?;
+    let diff = diff_state(&deployed, desired, image_hashes);
 
     Ok(diff)
 }
@@ -286,6 +467,128 @@ pub fn full_diff(desired: &DesiredState, image_hashes: &HashMap<String, String>)
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::types::fixtures::{deployed_vm, vm_config};
+
+    fn single(config: VMConfig, deployed: DeployedVM) -> StateDiff {
+        let desired = DesiredState {
+            vms: HashMap::from([(config.name.clone(), config)]),
+        };
+        let deployed = DeployedState {
+            vms: HashMap::from([(deployed.vm_id, deployed)]),
+        };
+        let hashes = HashMap::from([("base".to_string(), "abc".to_string())]);
+        diff_state(&deployed, &desired, &hashes)
+    }
+
+    #[test]
+    fn test_diff_unchanged_vm() {
+        let diff = single(vm_config("web", 100), deployed_vm("web", 100));
+        assert!(
+            diff.to_create.is_empty() && diff.to_update.is_empty() && diff.to_delete.is_empty()
+        );
+    }
+
+    #[test]
+    fn test_diff_power_and_autostart_in_place() {
+        let mut config = vm_config("web", 100);
+        config.power_state = PowerState::Stopped;
+        config.onboot = true;
+        config.startup = Some(parse_startup("order=2,up=30"));
+        let diff = single(config, deployed_vm("web", 100));
+        let update = &diff.to_update[0];
+        assert_eq!(
+            update.changed_fields,
+            vec![
+                FieldChange::PowerState,
+                FieldChange::OnBoot,
+                FieldChange::Startup
+            ]
+        );
+        assert!(matches!(update.required_action, UpdateAction::InPlace));
+    }
+
+    #[test]
+    fn test_diff_hand_edited_network_and_tags() {
+        let mut deployed = deployed_vm("web", 100);
+        deployed.net0 = Some("virtio=BC:24:11:00:00:01,bridge=vmbr1".to_string());
+        deployed.tags.push("handmade".to_string());
+        deployed.tags.push("owner-proxnix-prod".to_string());
+        assert_eq!(user_tags(&deployed.tags), vec!["handmade".to_string()]);
+        let diff = single(vm_config("web", 100), deployed);
+        assert_eq!(
+            diff.to_update[0].changed_fields,
+            vec![FieldChange::Network, FieldChange::Tags]
+        );
+    }
+
+    #[test]
+    fn test_diff_rename_in_place() {
+        let diff = single(vm_config("web-01", 100), deployed_vm("web", 100));
+        assert!(diff.to_create.is_empty() && diff.to_delete.is_empty());
+        assert_eq!(diff.to_update[0].changed_fields, vec![FieldChange::Name]);
+        assert!(matches!(
+            diff.to_update[0].required_action,
+            UpdateAction::InPlace
+        ));
+    }
+
+    #[test]
+    fn test_diff_vm_id_change_rebuilds() {
+        let diff = single(vm_config("web", 105), deployed_vm("web", 100));
+        assert!(diff.to_create.is_empty() && diff.to_delete.is_empty());
+        let update = &diff.to_update[0];
+        assert_eq!(update.changed_fields, vec![FieldChange::VmId]);
+        assert_eq!(update.deployed.vm_id, 100);
+        assert!(matches!(update.required_action, UpdateAction::Rebuild));
+
+        // The old ID is still configured for another VM, so this one is new
+        let desired = DesiredState {
+            vms: HashMap::from([
+                ("web".to_string(), vm_config("web", 105)),
+                ("db".to_string(), vm_config("db", 100)),
+            ]),
+        };
+        let deployed = DeployedState {
+            vms: HashMap::from([(100, deployed_vm("web", 100))]),
+        };
+        let hashes = HashMap::from([("base".to_string(), "abc".to_string())]);
+        let diff = diff_state(&deployed, &desired, &hashes);
+        assert_eq!(diff.to_create.len(), 1);
+        assert_eq!(diff.to_create[0].name, "web");
+        assert_eq!(diff.to_update[0].changed_fields, vec![FieldChange::Name]);
+    }
+
+    #[test]
+    fn test_collisions_with_unmanaged_vms() {
+        let scope = Scope {
+            environment: "prod".to_string(),
+            ..Default::default()
+        };
+        let desired = DesiredState {
+            vms: HashMap::from([
+                ("web".to_string(), vm_config("web", 100)),
+                ("db".to_string(), vm_config("db", 101)),
+                ("cache".to_string(), vm_config("cache", 102)),
+            ]),
+        };
+        let managed = DeployedState {
+            vms: HashMap::from([(100, deployed_vm("web", 100))]),
+        };
+        let existing = HashMap::from([(100, "web".to_string())]);
+        assert!(check_collisions(&desired, &existing, &managed, &scope).is_ok());
+        let existing = HashMap::from([(100, "web".to_string()), (101, "handmade".to_string())]);
+        let err = check_collisions(&desired, &existing, &managed, &scope).unwrap_err();
+        assert!(err.to_string().contains("db (101, taken by handmade)"));
+    }
+
+    #[test]
+    fn test_parse_startup() {
+        let startup = parse_startup("order=1,up=30,down=60");
+        assert_eq!(startup.order, Some(1));
+        assert_eq!(startup.up_secs, Some(30));
+        assert_eq!(startup.down_secs, Some(60));
+        assert_eq!(crate::qm::format_startup(&startup), "order=1,up=30,down=60");
+    }
 
     #[test]
 
@@ -316,7 +619,12 @@ mod tests {
       9006 nixos-template       stopped    4096               3.91 0
       9010 clean-ubuntu         stopped    1024               2.20 0";
 
-        let result = parse_qm_list(sample);
-        println!("{:#?}", result)
+        let result = parse_qm_list(sample).unwrap();
+        assert_eq!(result.len(), 24);
+        // Both masters survive, they are told apart by ID
+        let deployed = list_to_deployed_vm(result);
+        assert_eq!(deployed.vms.len(), 24);
+        assert_eq!(deployed.vms[&100].vm_name, "master");
+        assert_eq!(deployed.vms[&102].vm_name, "master");
     }
 }
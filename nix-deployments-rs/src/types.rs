@@ -4,12 +4,8 @@ use std::{collections::HashMap, string::FromUtf8Error};
 pub enum AppError {
     #[error("Git has failed, error: {0}")]
     GitError(String),
-    #[error("Nix failed to build, output: {0}")]
-    NixError(String),
-    #[error("Proxmox API error: {0}")]
-    ProxmoxError(String),
     #[error("QM error: {0}")]
-    QMError(String),
+    QMError(#[from] QMCommandError),
     #[error("File IO error {0}")]
     FileIOError(#[from] std::io::Error),
     #[error("Serialisation error at some point {0}")]
@@ -18,6 +14,10 @@ pub enum AppError {
     UTF8Error(#[from] FromUtf8Error),
     #[error("Command error: {0}")]
     CmdError(String),
+    #[error("Command timed out: {0}")]
+    CmdTimeout(String),
+    #[error("Cancelled: {0}")]
+    Cancelled(String),
     #[error("Parsing int error: {0}")]
     ParseIntError(#[from] std::num::ParseIntError),
     #[error("Parsing float error: {0}")]
@@ -26,14 +26,58 @@ pub enum AppError {
     Git2Error(#[from] git2::Error),
     #[error("Parsing module error: {0}")]
     ParsingModuleError(String),
+    #[error("Untrusted commit {0}")]
+    UntrustedCommit(String),
+    #[error("Ownership error: {0}")]
+    OwnershipError(String),
+    #[error("Config error: {0}")]
+    ConfigError(String),
+    #[error("Import error: {0}")]
+    ImportError(String),
+    #[error("Invalid config:\n{0}")]
+    ValidationError(String),
+    #[error("Not enough capacity:\n{0}")]
+    CapacityError(String),
+    #[error("Policy violations:\n{0}")]
+    PolicyError(String),
 }
 
 pub type Result<T> = std::result::Result<T, AppError>;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QMErrorKind {
+    Locked,
+    StorageBusy,
+    Timeout,
+    Fatal,
+}
+
+impl QMErrorKind {
+    pub fn is_retryable(&self) -> bool {
+        !matches!(self, QMErrorKind::Fatal)
+    }
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("qm {operation} has failed with exit code: {exit_code:?} ({kind:?}): {stderr}")]
+pub struct QMCommandError {
+    pub operation: String,
+    pub vm_id: Option<u32>,
+    pub exit_code: Option<i32>,
+    pub stderr: String,
+    pub kind: QMErrorKind,
+}
+
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 pub struct VMConfig {
     pub name: String,
+    // Left out in proxnix.nix to have one allocated from the repo's vm_id_range, see alloc.rs.
+    // 0 until then.
+    #[serde(default)]
     pub vm_id: u32,
+    // Set by the allocator, not meant for proxnix.nix
+    #[serde(default)]
+    pub vm_id_allocated: bool,
     pub image_type: String,
     pub cores: u16,
     pub sockets: u8,
@@ -48,6 +92,17 @@ pub struct VMConfig {
     pub scsi_hw: String,
     #[serde(default = "default_disk_slot")]
     pub disk_slot: String,
+    #[serde(default)]
+    pub shutdown: ShutdownPolicy,
+    #[serde(default)]
+    pub power_state: PowerState,
+    #[serde(default)]
+    pub onboot: bool,
+    #[serde(default)]
+    pub startup: Option<StartupOrder>,
+    // Extra Proxmox tags, on top of the ones proxnix manages itself
+    #[serde(default)]
+    pub tags: Vec<String>,
 }
 
 // Defaults for VMConfig
@@ -63,6 +118,56 @@ fn default_disk_slot() -> String {
     "scsi0".to_string()
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerState {
+    #[default]
+    Running,
+    Stopped,
+}
+
+// Maps to Proxmox's startup option: boot order among onboot VMs and the delays after start/before stop
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct StartupOrder {
+    #[serde(default)]
+    pub order: Option<u32>,
+    #[serde(default)]
+    pub up_secs: Option<u32>,
+    #[serde(default)]
+    pub down_secs: Option<u32>,
+}
+
+// How a VM is brought down before it is destroyed or rebuilt. The guest gets an ACPI/agent
+// shutdown and timeout_secs to finish, then it is hard stopped.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct ShutdownPolicy {
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub timeout_secs: u64,
+    // Run in the guest via the agent first, e.g. ["kubectl", "drain", "node", "--ignore-daemonsets"]
+    #[serde(default)]
+    pub pre_shutdown_command: Option<Vec<String>>,
+    #[serde(default = "default_pre_shutdown_timeout_secs")]
+    pub pre_shutdown_timeout_secs: u64,
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    120
+}
+
+fn default_pre_shutdown_timeout_secs() -> u64 {
+    300
+}
+
+impl Default for ShutdownPolicy {
+    fn default() -> Self {
+        Self {
+            timeout_secs: default_shutdown_timeout_secs(),
+            pre_shutdown_command: None,
+            pre_shutdown_timeout_secs: default_pre_shutdown_timeout_secs(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 pub enum CloudInit {
     None,
@@ -91,6 +196,13 @@ pub struct DeployedVM {
     pub pid: u32,
     pub cores: u16,
     pub sockets: u8,
+    pub onboot: bool,
+    pub startup: Option<StartupOrder>,
+    pub net0: Option<String>,
+    pub tags: Vec<String>,
+    // Storage of the boot disk
+    #[serde(default)]
+    pub storage: Option<String>,
 }
 
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
@@ -106,6 +218,7 @@ pub struct QMConfig {
     pub cpuunits: u16,
     pub disks: HashMap<String, String>,
     pub ipconfigs: HashMap<String, String>,
+    pub lock: Option<String>,
     pub memory: u32,
     pub meta: String,
     pub name: String,
@@ -116,6 +229,7 @@ pub struct QMConfig {
     pub serial: HashMap<String, String>,
     pub sockets: u8,
     pub sshkeys: Option<String>,
+    pub startup: Option<String>,
     pub tags: Option<String>,
     pub vga: String,
     pub vmgenid: String,
@@ -136,6 +250,7 @@ impl Default for QMConfig {
             cpuunits: Default::default(),
             disks: Default::default(),
             ipconfigs: Default::default(),
+            lock: Default::default(),
             memory: Default::default(),
             meta: Default::default(),
             name: Default::default(),
@@ -145,6 +260,7 @@ impl Default for QMConfig {
             protection: Default::default(),
             serial: Default::default(),
             sshkeys: Default::default(),
+            startup: Default::default(),
             tags: Default::default(),
             vga: Default::default(),
             vmgenid: Default::default(),
@@ -159,7 +275,8 @@ pub struct DesiredState {
 
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 pub struct DeployedState {
-    pub vms: HashMap<String, DeployedVM>,
+    // Keyed by VM ID
+    pub vms: HashMap<u32, DeployedVM>,
 }
 
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
@@ -173,6 +290,7 @@ pub struct StateDiff {
 pub struct VMUpdate {
     pub name: String,
     pub config: VMConfig,
+    pub deployed: DeployedVM,
     pub changed_fields: Vec<FieldChange>,
     pub required_action: UpdateAction,
 }
@@ -186,23 +304,215 @@ pub enum UpdateAction {
 
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize, PartialEq)]
 pub enum FieldChange {
+    Name,
+    // The config moved the VM to another ID, see state::diff_state
+    VmId,
     Memory,
     Cores,
     Sockets,
     Disk,
     Image,
+    PowerState,
+    OnBoot,
+    Startup,
+    Network,
+    Tags,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum ShutdownPath {
+    NotRunning,
+    Graceful,
+    Forced,
 }
 
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
-pub enum RebuildStrategy {
-    Rebuild,
+pub struct ShutdownRecord {
+    pub path: ShutdownPath,
+    // None when no pre-shutdown command is configured
+    pub pre_shutdown_succeeded: Option<bool>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub enum ReportAction {
+    Created,
+    Destroyed,
+    Rebuilt,
+    UpdatedInPlace,
+    Protected,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct VMReport {
+    pub name: String,
+    pub vm_id: u32,
+    pub action: ReportAction,
+    pub shutdown: Option<ShutdownRecord>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum ImageStatus {
+    Built,
+    Substituted,
+    // Either no VM needs the image this run or it was already in the store
+    Skipped,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct ImageReport {
+    pub image_type: String,
+    pub nix_hash: String,
+    pub status: ImageStatus,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum PipelineTrigger {
+    #[default]
+    Push,
+    Rollback {
+        from: Option<String>,
+    },
+    Poll {
+        git_ref: String,
+    },
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+pub struct RunReport {
+    pub commit_hash: String,
+    #[serde(default)]
+    pub trigger: PipelineTrigger,
+    #[serde(default)]
+    pub images: Vec<ImageReport>,
+    pub vms: Vec<VMReport>,
+    #[serde(default)]
+    pub gc: Option<GcReport>,
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+pub struct GcReport {
+    pub removed_checkouts: Vec<String>,
+    pub checkout_bytes_freed: u64,
+    // None when nix store gc is disabled
+    pub store_paths_deleted: Option<u64>,
+    pub store_bytes_freed: Option<u64>,
+}
+
+// Evaluated, not built, so the pipeline knows an image's hash before deciding to build it
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImagePaths {
+    pub drv_path: String,
+    pub out_path: String,
+}
+
+// What a successful pipeline deployed, enough to diff against live state later without rebuilding
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct DeploymentSnapshot {
+    pub repo_url: String,
+    #[serde(default)]
+    pub environment: String,
+    pub commit_hash: String,
+    pub deployed_at: u64,
+    pub desired: DesiredState,
+    pub image_hashes: HashMap<String, String>,
+}
+
+// Result of taking over a VM proxnix did not create, see import.rs
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct ImportReport {
+    pub environment: String,
+    pub vm_id: u32,
+    pub name: String,
+    // Commit the VM's config was read from
+    pub commit_hash: String,
+    // What the next deployment of that commit would change on the VM once it is managed
+    pub changed_fields: Vec<FieldChange>,
+    pub required_action: Option<UpdateAction>,
+    pub tags: Vec<String>,
+    pub applied: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct PipelineOutcome {
+    pub snapshot: DeploymentSnapshot,
+    pub report: RunReport,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum DriftClass {
     InPlace,
+    Rebuild,
     Protected,
+    Missing,
+    Unexpected,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct DriftEntry {
+    pub name: String,
+    pub vm_id: u32,
+    pub class: DriftClass,
+    pub fields: Vec<FieldChange>,
+    pub corrected: bool,
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+pub struct DriftReport {
+    pub commit_hash: String,
+    pub checked_at: u64,
+    pub entries: Vec<DriftEntry>,
 }
 
 #[derive(Debug)]
 pub struct ParsedWebhook {
     pub repository: String,
     pub hash: String,
+    pub git_ref: Option<String>,
 }
 
+// A VM as proxnix.nix would define it and the same VM as deployed, for the tests of any
+// module. Tests change the fields they care about.
+#[cfg(test)]
+pub mod fixtures {
+    use super::{DeployedVM, VMConfig};
+
+    pub fn vm_json(name: &str, vm_id: u32) -> serde_json::Value {
+        serde_json::json!({
+            "name": name,
+            "vm_id": vm_id,
+            "image_type": "base",
+            "cores": 2,
+            "sockets": 1,
+            "memory_mb": 2048,
+            "storage_location": "local-lvm",
+            "disk_gb": 10,
+            "cloud_init": "None",
+            "protected": false
+        })
+    }
+
+    pub fn vm_config(name: &str, vm_id: u32) -> VMConfig {
+        serde_json::from_value(vm_json(name, vm_id)).unwrap()
+    }
+
+    pub fn deployed_vm(name: &str, vm_id: u32) -> DeployedVM {
+        DeployedVM {
+            vm_id,
+            vm_name: name.to_string(),
+            nix_hash: Some("abc".to_string()),
+            template_id: None,
+            mem_mb: 2048,
+            bootdisk_gb: 10.0,
+            status: "running".to_string(),
+            pid: 1,
+            cores: 2,
+            sockets: 1,
+            onboot: false,
+            startup: None,
+            net0: Some("virtio=BC:24:11:00:00:01,bridge=vmbr0".to_string()),
+            tags: vec!["proxnix".to_string(), "nix-abc".to_string()],
+            storage: Some("local-lvm".to_string()),
+        }
+    }
+}
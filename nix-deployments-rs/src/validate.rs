@@ -0,0 +1,523 @@
+use crate::config;
+use crate::exec;
+use crate::types::{AppError, DesiredState, Result, VMConfig};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::process::Command;
+
+// Everything VMConfig accepts from proxnix.nix, so typos are reported instead of ignored.
+// Kept in sync with VMConfig by test_vm_fields_match_vm_config.
+const VM_FIELDS: &[&str] = &[
+    "name",
+    "vm_id",
+    "image_type",
+    "cores",
+    "sockets",
+    "memory_mb",
+    "storage_location",
+    "disk_gb",
+    "cloud_init",
+    "protected",
+    "network_bridge",
+    "scsi_hw",
+    "disk_slot",
+    "shutdown",
+    "power_state",
+    "onboot",
+    "startup",
+    "tags",
+];
+// Fields without a default
+const REQUIRED_FIELDS: &[&str] = &[
+    "name",
+    "image_type",
+    "cores",
+    "sockets",
+    "memory_mb",
+    "storage_location",
+    "disk_gb",
+    "cloud_init",
+    "protected",
+];
+const SHUTDOWN_FIELDS: &[&str] = &[
+    "timeout_secs",
+    "pre_shutdown_command",
+    "pre_shutdown_timeout_secs",
+];
+const STARTUP_FIELDS: &[&str] = &["order", "up_secs", "down_secs"];
+// Proxmox reserves IDs below 100
+const MIN_VM_ID: u32 = 100;
+// Only present on a Proxmox node
+const PVE_DIR: &str = "/etc/pve";
+const NETWORK_INTERFACES: &str = "/etc/network/interfaces";
+const NETWORK_INTERFACES_D: &str = "/etc/network/interfaces.d";
+const SDN_VNETS: &str = "/etc/pve/sdn/vnets.cfg";
+
+// One thing wrong with the config, path is where in the proxnix output it is, e.g. vms.web.cores
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub path: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+pub fn problem(path: impl Into<String>, message: impl Into<String>) -> Problem {
+    Problem {
+        path: path.into(),
+        message: message.into(),
+    }
+}
+
+// What the config is checked against. None skips that check, e.g. when validating away
+// from the Proxmox host.
+#[derive(Debug, Default)]
+pub struct Host {
+    pub image_types: Option<Vec<String>>,
+    pub storages: Option<HashSet<String>>,
+    pub bridges: Option<HashSet<String>>,
+}
+
+impl Host {
+    // Storages and bridges are only looked up on a Proxmox node, anywhere else they'd be
+    // the wrong ones
+    pub fn discover(image_types: Option<Vec<String>>) -> Self {
+        let proxmox = Path::new(PVE_DIR).is_dir();
+        Host {
+            image_types,
+            storages: proxmox.then(|| storages().ok()).flatten(),
+            bridges: proxmox.then(|| bridges().ok()).flatten(),
+        }
+    }
+}
+
+pub fn parse_pvesm_status(output: &str) -> HashSet<String> {
+    output
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().next())
+        .map(|name| name.to_string())
+        .collect()
+}
+
+pub fn pvesm_status() -> Result<String> {
+    let mut cmd = Command::new("pvesm");
+    cmd.arg("status");
+    let output = exec::run(cmd, config::get().timeouts.qm())?;
+    if !output.status.success() {
+        return Err(AppError::CmdError(format!(
+            "pvesm status failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+fn storages() -> Result<HashSet<String>> {
+    Ok(parse_pvesm_status(&pvesm_status()?))
+}
+
+// Linux and OVS bridges from an ifupdown config, the format Proxmox keeps its network in
+pub fn parse_interfaces(raw: &str) -> HashSet<String> {
+    let mut bridges = HashSet::new();
+    let mut iface = None;
+    for line in raw.lines().map(str::trim) {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("iface") => iface = words.next(),
+            Some("bridge-ports" | "bridge_ports") => bridges.extend(iface),
+            Some("ovs_type") if words.next() == Some("OVSBridge") => bridges.extend(iface),
+            _ => {}
+        }
+    }
+    bridges.into_iter().map(|name| name.to_string()).collect()
+}
+
+// SDN vnets, which VMs attach to like bridges
+pub fn parse_sdn_vnets(raw: &str) -> HashSet<String> {
+    raw.lines()
+        .filter_map(|line| line.strip_prefix("vnet:"))
+        .map(|name| name.trim().to_string())
+        .collect()
+}
+
+fn bridges() -> Result<HashSet<String>> {
+    let mut bridges = parse_interfaces(&std::fs::read_to_string(NETWORK_INTERFACES)?);
+    if let Ok(entries) = std::fs::read_dir(NETWORK_INTERFACES_D) {
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            if let Ok(raw) = std::fs::read_to_string(entry.path()) {
+                bridges.extend(parse_interfaces(&raw));
+            }
+        }
+    }
+    if let Ok(raw) = std::fs::read_to_string(SDN_VNETS) {
+        bridges.extend(parse_sdn_vnets(&raw));
+    }
+    Ok(bridges)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous + usize::from(ca != *cb);
+            previous = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(previous + 1);
+        }
+    }
+    row[b.len()]
+}
+
+fn unknown_fields(path: &str, fields: &Map<String, Value>, known: &[&str]) -> Vec<Problem> {
+    fields
+        .keys()
+        .filter(|field| !known.contains(&field.as_str()))
+        .map(|field| {
+            let suggestion = known
+                .iter()
+                .map(|k| (edit_distance(field, k), k))
+                .filter(|(distance, _)| *distance <= 2)
+                .min();
+            let message = match suggestion {
+                Some((_, k)) => format!("unknown field, did you mean {}?", k),
+                None => "unknown field".to_string(),
+            };
+            problem(format!("{}.{}", path, field), message)
+        })
+        .collect()
+}
+
+fn sorted(names: &HashSet<String>) -> String {
+    let mut names: Vec<&str> = names.iter().map(|s| s.as_str()).collect();
+    names.sort();
+    names.join(", ")
+}
+
+fn check_vm(key: &str, path: &str, vm: &VMConfig, host: &Host) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    if vm.name != key {
+        problems.push(problem(
+            format!("{}.name", path),
+            format!(
+                "is {} but the attribute is {}, they must match",
+                vm.name, key
+            ),
+        ));
+    }
+    if vm.vm_id != 0 && vm.vm_id < MIN_VM_ID {
+        problems.push(problem(
+            format!("{}.vm_id", path),
+            format!("must be at least {}", MIN_VM_ID),
+        ));
+    }
+    for (field, value) in [
+        ("cores", u32::from(vm.cores)),
+        ("sockets", u32::from(vm.sockets)),
+        ("memory_mb", vm.memory_mb),
+        ("disk_gb", vm.disk_gb),
+    ] {
+        if value == 0 {
+            problems.push(problem(format!("{}.{}", path, field), "must not be 0"));
+        }
+    }
+    if let Some(image_types) = &host.image_types
+        && !image_types.contains(&vm.image_type)
+    {
+        problems.push(problem(
+            format!("{}.image_type", path),
+            format!(
+                "no nixosConfiguration named {} (found: {})",
+                vm.image_type,
+                image_types.join(", ")
+            ),
+        ));
+    }
+    if let Some(storages) = &host.storages
+        && !storages.contains(&vm.storage_location)
+    {
+        problems.push(problem(
+            format!("{}.storage_location", path),
+            format!(
+                "no storage named {} (found: {})",
+                vm.storage_location,
+                sorted(storages)
+            ),
+        ));
+    }
+    if let Some(bridges) = &host.bridges
+        && !bridges.contains(&vm.network_bridge)
+    {
+        problems.push(problem(
+            format!("{}.network_bridge", path),
+            format!(
+                "no bridge named {} (found: {})",
+                vm.network_bridge,
+                sorted(bridges)
+            ),
+        ));
+    }
+    if vm
+        .shutdown
+        .pre_shutdown_command
+        .as_ref()
+        .is_some_and(|command| command.is_empty())
+    {
+        problems.push(problem(
+            format!("{}.shutdown.pre_shutdown_command", path),
+            "must not be empty, leave it out instead",
+        ));
+    }
+    problems
+}
+
+// Checks the JSON of the proxnix flake output and collects every problem rather than stopping
+// at the first, so one failed run shows everything to fix
+pub fn validate(raw: &str, host: &Host) -> std::result::Result<DesiredState, Vec<Problem>> {
+    let value: Value = serde_json::from_str(raw)
+        .map_err(|e| vec![problem("", format!("not valid JSON: {}", e))])?;
+    let Some(root) = value.as_object() else {
+        return Err(vec![problem("", "must be an attribute set with vms in it")]);
+    };
+    let mut problems = unknown_fields("", root, &["vms"]);
+    for p in &mut problems {
+        p.path = p.path.trim_start_matches('.').to_string();
+    }
+    let Some(vms) = root.get("vms").and_then(|vms| vms.as_object()) else {
+        problems.push(problem("vms", "missing or not an attribute set"));
+        return Err(problems);
+    };
+
+    let mut desired = DesiredState {
+        vms: HashMap::new(),
+    };
+    let mut ids: BTreeMap<u32, Vec<&str>> = BTreeMap::new();
+    for (key, vm) in vms {
+        let path = format!("vms.{}", key);
+        let Some(fields) = vm.as_object() else {
+            problems.push(problem(path, "must be an attribute set"));
+            continue;
+        };
+        problems.extend(unknown_fields(&path, fields, VM_FIELDS));
+        for (nested, known) in [("shutdown", SHUTDOWN_FIELDS), ("startup", STARTUP_FIELDS)] {
+            if let Some(nested_fields) = fields.get(nested).and_then(|v| v.as_object()) {
+                problems.extend(unknown_fields(
+                    &format!("{}.{}", path, nested),
+                    nested_fields,
+                    known,
+                ));
+            }
+        }
+        let missing: Vec<&&str> = REQUIRED_FIELDS
+            .iter()
+            .filter(|field| !fields.contains_key(**field))
+            .collect();
+        for field in &missing {
+            problems.push(problem(format!("{}.{}", path, field), "missing"));
+        }
+        match serde_json::from_value::<VMConfig>(vm.clone()) {
+            Ok(config) => {
+                problems.extend(check_vm(key, &path, &config, host));
+                if config.vm_id != 0 {
+                    ids.entry(config.vm_id).or_default().push(key);
+                }
+                desired.vms.insert(key.clone(), config);
+            }
+            // Already reported above
+            Err(_) if !missing.is_empty() => {}
+            Err(e) => problems.push(problem(path, e.to_string())),
+        }
+    }
+    for (vm_id, keys) in ids.into_iter().filter(|(_, keys)| keys.len() > 1) {
+        for key in &keys {
+            let others: Vec<&str> = keys.iter().filter(|k| *k != key).copied().collect();
+            problems.push(problem(
+                format!("vms.{}.vm_id", key),
+                format!("{} is also used by {}", vm_id, others.join(", ")),
+            ));
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(desired)
+    } else {
+        Err(problems)
+    }
+}
+
+// validate() for the pipeline, where any problem fails the run
+pub fn validated(raw: &str, host: &Host) -> Result<DesiredState> {
+    validate(raw, host).map_err(|problems| {
+        AppError::ValidationError(
+            problems
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>()
+                .join("\n"),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::CloudInit;
+    use crate::types::fixtures::vm_json;
+
+    fn vm(name: &str) -> Value {
+        vm_json(name, 100)
+    }
+
+    fn host() -> Host {
+        Host {
+            image_types: Some(vec!["base".to_string()]),
+            storages: Some(HashSet::from(["local-lvm".to_string()])),
+            bridges: Some(HashSet::from(["vmbr0".to_string()])),
+        }
+    }
+
+    fn paths(raw: &Value, host: &Host) -> Vec<String> {
+        let mut paths: Vec<String> = validate(&raw.to_string(), host)
+            .unwrap_err()
+            .into_iter()
+            .map(|p| p.path)
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn test_valid_config() {
+        let raw = serde_json::json!({ "vms": { "web": vm("web") } });
+        let desired = validate(&raw.to_string(), &host()).unwrap();
+        assert_eq!(desired.vms["web"].vm_id, 100);
+        // Nothing known about the host, nothing to check against
+        assert!(validate(&raw.to_string(), &Host::default()).is_ok());
+    }
+
+    #[test]
+    fn test_reports_every_problem() {
+        let mut web = vm("web");
+        web["memroy_mb"] = serde_json::json!(4096);
+        web["cores"] = serde_json::json!(0);
+        web["storage_location"] = serde_json::json!("nvme");
+        web["network_bridge"] = serde_json::json!("vmbr9");
+        web["shutdown"] = serde_json::json!({ "timeout": 10 });
+        let mut db = vm("database");
+        db["image_type"] = serde_json::json!("postgres");
+        let mut cache = vm("cache");
+        cache.as_object_mut().unwrap().remove("disk_gb");
+        let raw = serde_json::json!({ "vm": {}, "vms": { "web": web, "db": db, "cache": cache } });
+
+        assert_eq!(
+            paths(&raw, &host()),
+            vec![
+                "vm",
+                "vms.cache.disk_gb",
+                "vms.db.image_type",
+                "vms.db.name",
+                "vms.db.vm_id",
+                "vms.web.cores",
+                "vms.web.memroy_mb",
+                "vms.web.network_bridge",
+                "vms.web.shutdown.timeout",
+                "vms.web.storage_location",
+                "vms.web.vm_id",
+            ]
+        );
+        let problems = validate(&raw.to_string(), &host()).unwrap_err();
+        let typo = problems
+            .iter()
+            .find(|p| p.path == "vms.web.memroy_mb")
+            .unwrap();
+        assert_eq!(typo.message, "unknown field, did you mean memory_mb?");
+        let duplicate = problems.iter().find(|p| p.path == "vms.db.vm_id").unwrap();
+        assert_eq!(duplicate.message, "100 is also used by web");
+    }
+
+    #[test]
+    fn test_type_errors_name_the_vm() {
+        let mut web = vm("web");
+        web["cores"] = serde_json::json!("two");
+        let raw = serde_json::json!({ "vms": { "web": web } });
+        let problems = validate(&raw.to_string(), &host()).unwrap_err();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].path, "vms.web");
+        assert!(problems[0].message.contains("invalid type"));
+        assert!(validate("not json", &host()).is_err());
+    }
+
+    #[test]
+    fn test_vm_fields_match_vm_config() {
+        let config: VMConfig = serde_json::from_value(vm("web")).unwrap();
+        assert!(matches!(config.cloud_init, CloudInit::None));
+        let serialised = serde_json::to_value(&config).unwrap();
+        let mut fields: Vec<&str> = serialised
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(|k| k.as_str())
+            .filter(|k| *k != "vm_id_allocated")
+            .collect();
+        fields.sort();
+        let mut known = VM_FIELDS.to_vec();
+        known.sort();
+        assert_eq!(fields, known);
+    }
+
+    #[test]
+    fn test_parse_pvesm_status() {
+        let output = "Name             Type     Status           Total            Used       Available        %
+local             dir     active        98497780        12345678        81062628   12.53%
+local-lvm     lvmthin     active       832888832       123456789       709432043   14.82%";
+        assert_eq!(
+            parse_pvesm_status(output),
+            HashSet::from(["local".to_string(), "local-lvm".to_string()])
+        );
+        assert_eq!(edit_distance("memroy_mb", "memory_mb"), 2);
+    }
+
+    #[test]
+    fn test_parse_bridges() {
+        let interfaces = "auto lo
+iface lo inet loopback
+
+iface eno1 inet manual
+
+auto vmbr0
+iface vmbr0 inet static
+\taddress 192.168.1.10/24
+\tbridge-ports eno1
+\tbridge-stp off
+
+auto vmbr1
+iface vmbr1 inet manual
+\tovs_type OVSBridge
+\tovs_ports eno2
+
+auto eno2
+iface eno2 inet manual
+\tovs_type OVSPort
+\tovs_bridge vmbr1
+";
+        assert_eq!(
+            parse_interfaces(interfaces),
+            HashSet::from(["vmbr0".to_string(), "vmbr1".to_string()])
+        );
+        assert_eq!(
+            parse_sdn_vnets("vnet: tenant1\n\tzone lab\n\tvlanaware 1\n"),
+            HashSet::from(["tenant1".to_string()])
+        );
+    }
+}